@@ -68,9 +68,83 @@ console.log("Sending funds");
 
 const utxos = 4;
 
-const receiverAddresses = Array.from({ length: utxos }).map(_ =>
-  "25390c97cda75b7db1b24aa1e34910234b58ca0f1d66f847438d5d97d40f7760|0300d491742496c85185533d20d9eb4cabfe94e2f53670abea6ec145d0b7c728e28b49eac08af8691451dc7d1380dff0b0cc20559b112098610b")
-  ;
+const BECH32_CHARSET = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32M_CONST = 0x2bc830a3;
+
+const polymod = (values: number[]) => {
+  const generator = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+  let chk = 1;
+  for (const value of values) {
+    const top = chk >>> 25;
+    chk = ((chk & 0x1ffffff) << 5) ^ value;
+    for (let i = 0; i < 5; i++) {
+      if ((top >>> i) & 1) {
+        chk ^= generator[i];
+      }
+    }
+  }
+  return chk >>> 0;
+};
+
+// the wallet only takes the `coin_pk|enc_pk` form, which is the payload of the
+// Bech32m address printed by the batcher.
+const toLegacyAddress = (address: string) => {
+  if (address.includes("|")) {
+    return address;
+  }
+
+  const lower = address.toLowerCase();
+  const separator = lower.lastIndexOf("1");
+  const hrp = lower.slice(0, separator);
+
+  if (!hrp.startsWith("mn_shield-addr")) {
+    throw new Error(`Not a shielded address: ${address}`);
+  }
+
+  const words = [...lower.slice(separator + 1)].map((c) => {
+    const word = BECH32_CHARSET.indexOf(c);
+    if (word === -1) {
+      throw new Error(`Invalid Bech32m character ${c}`);
+    }
+    return word;
+  });
+
+  const hrpWords = [
+    ...[...hrp].map((c) => c.charCodeAt(0) >> 5),
+    0,
+    ...[...hrp].map((c) => c.charCodeAt(0) & 31),
+  ];
+
+  if (words.length < 6 || polymod([...hrpWords, ...words]) !== BECH32M_CONST) {
+    throw new Error(`Invalid Bech32m checksum: ${address}`);
+  }
+
+  const bytes: number[] = [];
+  let acc = 0;
+  let bits = 0;
+  for (const word of words.slice(0, -6)) {
+    acc = ((acc << 5) | word) & 0x1fff;
+    bits += 5;
+    if (bits >= 8) {
+      bits -= 8;
+      bytes.push((acc >> bits) & 0xff);
+    }
+  }
+
+  const hex = Buffer.from(bytes).toString("hex");
+
+  return `${hex.slice(0, 64)}|${hex.slice(64)}`;
+};
+
+// the address of the batcher, as printed by `midnight-batcher address`, in
+// either format.
+const batcherAddress = toLegacyAddress(
+  process.argv[2] ??
+    process.env.BATCHER_ADDRESS ??
+    "25390c97cda75b7db1b24aa1e34910234b58ca0f1d66f847438d5d97d40f7760|0300d491742496c85185533d20d9eb4cabfe94e2f53670abea6ec145d0b7c728e28b49eac08af8691451dc7d1380dff0b0cc20559b112098610b"
+);
+
+const receiverAddresses = Array.from({ length: utxos }).map(_ => batcherAddress);
 
 let i = 0;
 
@@ -0,0 +1,282 @@
+//! PRC-1 achievements, defined in a config file as rules over the matches
+//! played. They are checked when the indexer sees a match finish.
+
+use crate::{contract_state::MatchResult, db::Db};
+use anyhow::Context as _;
+use serde::Deserialize;
+use std::{collections::HashSet, path::Path};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AchievementsConfig {
+    pub caip2: String,
+    pub id: String,
+    pub name: String,
+    pub achievements: Vec<AchievementDefinition>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AchievementDefinition {
+    pub name: String,
+    pub display_name: String,
+    pub description: String,
+    #[serde(default = "is_active_default")]
+    pub is_active: bool,
+    pub rule: Rule,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Rule {
+    MatchesPlayed {
+        count: u64,
+    },
+    Wins {
+        count: u64,
+    },
+    /// consecutive wins, draws and losses break the streak
+    WinStreak {
+        count: u64,
+    },
+    DistinctOpponents {
+        count: u64,
+    },
+}
+
+pub struct PlayerMatch {
+    pub opponent: String,
+    pub result: MatchResult,
+}
+
+fn is_active_default() -> bool {
+    true
+}
+
+impl Default for AchievementsConfig {
+    fn default() -> Self {
+        Self {
+            caip2: "polkadot:00000000000000000000000000000000".to_string(),
+            id: "paima-kachina-kolosseum".to_string(),
+            name: "Kachina Kolosseum".to_string(),
+            achievements: vec![AchievementDefinition {
+                name: "played_first_match".to_string(),
+                display_name: "Welcome to the Arena".to_string(),
+                description: "Play your first match (win or lose)".to_string(),
+                is_active: true,
+                rule: Rule::MatchesPlayed { count: 1 },
+            }],
+        }
+    }
+}
+
+impl Rule {
+    pub fn total(&self) -> u64 {
+        match self {
+            Rule::MatchesPlayed { count }
+            | Rule::Wins { count }
+            | Rule::WinStreak { count }
+            | Rule::DistinctOpponents { count } => *count,
+        }
+    }
+
+    /// How far the player is, capped at the total. `matches` has to be sorted
+    /// by block.
+    pub fn progress(&self, matches: &[PlayerMatch]) -> u64 {
+        let progress = match self {
+            Rule::MatchesPlayed { .. } => matches.len() as u64,
+            Rule::Wins { .. } => matches
+                .iter()
+                .filter(|m| m.result == MatchResult::Win)
+                .count() as u64,
+            Rule::WinStreak { .. } => {
+                let mut best = 0;
+                let mut current = 0;
+
+                for m in matches {
+                    if m.result == MatchResult::Win {
+                        current += 1;
+                        best = best.max(current);
+                    } else {
+                        current = 0;
+                    }
+                }
+
+                best
+            }
+            Rule::DistinctOpponents { .. } => matches
+                .iter()
+                .map(|m| m.opponent.as_str())
+                .collect::<HashSet<_>>()
+                .len() as u64,
+        };
+
+        progress.min(self.total())
+    }
+}
+
+impl AchievementsConfig {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+
+        let raw = std::fs::read_to_string(path).context(format!(
+            "Failed to read achievements file {}",
+            path.display()
+        ))?;
+
+        toml::from_str(&raw).context("Invalid achievements file")
+    }
+
+    /// Records the achievements the player completes with a match finished at
+    /// `block_number`.
+    pub async fn update_player(
+        &self,
+        db: &Db,
+        player: &str,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        let matches = player_matches(db, player).await?;
+        let completed = db.get_completed_achievements(player).await?;
+
+        for achievement in self.achievements.iter().filter(|a| a.is_active) {
+            if completed.iter().any(|(name, _)| name == &achievement.name) {
+                continue;
+            }
+
+            if achievement.rule.progress(&matches) >= achievement.rule.total() {
+                db.complete_achievement(player, &achievement.name, block_number)
+                    .await?;
+
+                tracing::info!(player, achievement = %achievement.name, "achievement completed");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The finished matches of the player, oldest first.
+pub async fn player_matches(db: &Db, player: &str) -> anyhow::Result<Vec<PlayerMatch>> {
+    db.get_player_matches(player)
+        .await?
+        .into_iter()
+        .map(|(opponent, result)| {
+            Ok(PlayerMatch {
+                opponent,
+                result: MatchResult::parse(&result)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midnight_zswap::serialize::NetworkId;
+
+    fn matches(results: &[(&str, MatchResult)]) -> Vec<PlayerMatch> {
+        results
+            .iter()
+            .map(|(opponent, result)| PlayerMatch {
+                opponent: opponent.to_string(),
+                result: *result,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rules_count_the_matches() {
+        use MatchResult::*;
+
+        let played = matches(&[
+            ("a", Win),
+            ("b", Win),
+            ("a", Loss),
+            ("c", Win),
+            ("a", Draw),
+            ("b", Win),
+            ("c", Win),
+            ("a", Win),
+        ]);
+
+        let progress = |rule: Rule| rule.progress(&played);
+
+        assert_eq!(progress(Rule::MatchesPlayed { count: 100 }), 8);
+        assert_eq!(progress(Rule::Wins { count: 100 }), 6);
+        assert_eq!(progress(Rule::WinStreak { count: 100 }), 3);
+        assert_eq!(progress(Rule::DistinctOpponents { count: 100 }), 3);
+
+        // capped at the total
+        assert_eq!(progress(Rule::Wins { count: 2 }), 2);
+        assert_eq!(Rule::WinStreak { count: 5 }.progress(&[]), 0);
+    }
+
+    #[test]
+    fn rules_are_read_by_kind() {
+        let config: AchievementsConfig = toml::from_str(
+            r#"
+            caip2 = "polkadot:00000000000000000000000000000000"
+            id = "game"
+            name = "Game"
+
+            [[achievements]]
+            name = "streak"
+            display_name = "On Fire"
+            description = "Win three matches in a row"
+            rule = { kind = "win_streak", count = 3 }
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.achievements[0].is_active);
+        assert!(matches!(
+            config.achievements[0].rule,
+            Rule::WinStreak { count: 3 }
+        ));
+    }
+
+    #[tokio::test]
+    async fn completed_achievements_are_recorded_once() {
+        let db = Db::open_db(
+            "file:batcher-achievements?mode=memory&cache=shared",
+            NetworkId::Undeployed,
+        )
+        .await
+        .unwrap();
+
+        let config = AchievementsConfig {
+            achievements: vec![AchievementDefinition {
+                name: "two_wins".to_string(),
+                display_name: "Two Wins".to_string(),
+                description: "Win two matches".to_string(),
+                is_active: true,
+                rule: Rule::Wins { count: 2 },
+            }],
+            ..Default::default()
+        };
+
+        db.record_match("a", "p1", "p2", "win", 10).await.unwrap();
+        config.update_player(&db, "p1", 10).await.unwrap();
+        assert!(db
+            .get_completed_achievements("p1")
+            .await
+            .unwrap()
+            .is_empty());
+
+        db.record_match("b", "p1", "p2", "win", 11).await.unwrap();
+        config.update_player(&db, "p1", 11).await.unwrap();
+
+        db.record_match("c", "p1", "p2", "win", 12).await.unwrap();
+        config.update_player(&db, "p1", 12).await.unwrap();
+
+        assert_eq!(
+            db.get_completed_achievements("p1").await.unwrap(),
+            vec![("two_wins".to_string(), 11)]
+        );
+        assert!(db
+            .get_completed_achievements("p2")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
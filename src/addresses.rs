@@ -0,0 +1,227 @@
+//! Address encodings served to the clients. Contract addresses are stored as
+//! the hex of their 32 bytes, without the network id byte that prefixes them
+//! when serialized, so that any encoding sent by a client can be compared with
+//! the stored ones.
+
+use anyhow::Context as _;
+use bech32::{primitives::decode::CheckedHrpstring, Bech32m, Checksum, Hrp};
+use midnight_zswap::{
+    local::State,
+    serialize::{NetworkId, Serializable},
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromFormField, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFormat {
+    /// hex of the address bytes, without the network id
+    #[default]
+    Hex,
+    /// hex of the serialized address, network id included
+    Prefixed,
+    /// bech32m, like the Midnight tooling
+    Bech32m,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractAddress {
+    network_id: NetworkId,
+    bytes: [u8; 32],
+}
+
+impl ContractAddress {
+    /// Parses an address serialized for the network, that is the network id
+    /// byte followed by the address bytes.
+    pub fn from_serialized(raw: &[u8], network_id: NetworkId) -> anyhow::Result<Self> {
+        let [network_tag, bytes @ ..] = raw else {
+            anyhow::bail!("Empty contract address");
+        };
+
+        anyhow::ensure!(
+            *network_tag == network_id as u8,
+            "Contract address of network {}, expected {:?}",
+            network_tag,
+            network_id
+        );
+
+        Self::from_bytes(bytes, network_id)
+    }
+
+    /// Parses the stored form of the address.
+    pub fn from_stored(stored: &str, network_id: NetworkId) -> anyhow::Result<Self> {
+        let raw = hex::decode(stored).context("Contract address is not hex encoded")?;
+
+        Self::from_bytes(&raw, network_id)
+    }
+
+    /// Parses an address sent by a client, in any of the [`AddressFormat`]s.
+    pub fn parse(address: &str, network_id: NetworkId) -> anyhow::Result<Self> {
+        if let Ok(checked) = CheckedHrpstring::new::<LongBech32m>(address) {
+            let expected = hrp_for("contract", network_id);
+
+            anyhow::ensure!(
+                checked.hrp().to_lowercase() == expected,
+                "Expected a {} address",
+                expected
+            );
+
+            return Self::from_bytes(&checked.byte_iter().collect::<Vec<_>>(), network_id);
+        }
+
+        let raw = hex::decode(address).context("Contract address is neither hex nor bech32m")?;
+
+        match raw.len() {
+            32 => Self::from_bytes(&raw, network_id),
+            _ => Self::from_serialized(&raw, network_id),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8], network_id: NetworkId) -> anyhow::Result<Self> {
+        Ok(Self {
+            network_id,
+            bytes: bytes.try_into().context(format!(
+                "Unexpected contract address length {}",
+                bytes.len()
+            ))?,
+        })
+    }
+
+    /// The form stored in the database.
+    pub fn to_stored(&self) -> String {
+        hex::encode(self.bytes)
+    }
+
+    pub fn encode(&self, format: AddressFormat) -> anyhow::Result<String> {
+        match format {
+            AddressFormat::Hex => Ok(hex::encode(self.bytes)),
+            AddressFormat::Prefixed => Ok(format!(
+                "{:02x}{}",
+                self.network_id as u8,
+                hex::encode(self.bytes)
+            )),
+            AddressFormat::Bech32m => bech32m("contract", self.network_id, &self.bytes),
+        }
+    }
+}
+
+/// The shielded address of the wallet: the coin public key followed by the
+/// serialized encryption public key, like the legacy `coin_pk|enc_pk` form.
+pub fn shielded_address(state: &State, network_id: NetworkId) -> anyhow::Result<String> {
+    let mut data = state.coin_public_key().0 .0.to_vec();
+
+    <_ as Serializable>::serialize(&state.enc_public_key(), &mut data)
+        .context("Failed to serialize the encryption public key")?;
+
+    bech32m("shield-addr", network_id, &data)
+}
+
+/// Encodes `data` with the `mn_<kind>` human readable part, suffixed with the
+/// network for the non-mainnet networks.
+pub fn bech32m(kind: &str, network_id: NetworkId, data: &[u8]) -> anyhow::Result<String> {
+    let hrp = hrp_for(kind, network_id);
+
+    Ok(bech32::encode::<LongBech32m>(
+        Hrp::parse(&hrp).context("Invalid human readable part")?,
+        data,
+    )?)
+}
+
+/// Bech32m without a length limit below the one of the checksum itself, since
+/// the shielded addresses are longer than the 90 characters of BIP-173.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum LongBech32m {}
+
+impl Checksum for LongBech32m {
+    type MidstateRepr = <Bech32m as Checksum>::MidstateRepr;
+    const CODE_LENGTH: usize = 1023;
+    const CHECKSUM_LENGTH: usize = Bech32m::CHECKSUM_LENGTH;
+    const GENERATOR_SH: [Self::MidstateRepr; 5] = Bech32m::GENERATOR_SH;
+    const TARGET_RESIDUE: Self::MidstateRepr = Bech32m::TARGET_RESIDUE;
+}
+
+fn hrp_for(kind: &str, network_id: NetworkId) -> String {
+    match network_suffix(network_id) {
+        Some(network) => format!("mn_{}_{}", kind, network),
+        None => format!("mn_{}", kind),
+    }
+}
+
+fn network_suffix(network_id: NetworkId) -> Option<&'static str> {
+    match network_id {
+        NetworkId::Undeployed => Some("undeployed"),
+        NetworkId::DevNet => Some("dev"),
+        NetworkId::TestNet => Some("test"),
+        NetworkId::MainNet => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_parsed_in_every_format() {
+        let mut serialized = vec![NetworkId::TestNet as u8];
+        serialized.extend([0xab; 32]);
+
+        let address = ContractAddress::from_serialized(&serialized, NetworkId::TestNet).unwrap();
+
+        assert_eq!(address.to_stored(), "ab".repeat(32));
+
+        for format in [
+            AddressFormat::Hex,
+            AddressFormat::Prefixed,
+            AddressFormat::Bech32m,
+        ] {
+            let parsed =
+                ContractAddress::parse(&address.encode(format).unwrap(), NetworkId::TestNet)
+                    .unwrap();
+
+            assert_eq!(parsed, address);
+        }
+
+        assert_eq!(
+            address.encode(AddressFormat::Prefixed).unwrap(),
+            hex::encode(serialized)
+        );
+    }
+
+    #[test]
+    fn addresses_of_other_networks_are_rejected() {
+        let address = ContractAddress::from_stored(&"ab".repeat(32), NetworkId::MainNet).unwrap();
+
+        assert!(ContractAddress::parse(
+            &address.encode(AddressFormat::Bech32m).unwrap(),
+            NetworkId::TestNet
+        )
+        .is_err());
+        assert!(ContractAddress::parse(&"ab".repeat(31), NetworkId::TestNet).is_err());
+
+        let prefixed = address.encode(AddressFormat::Prefixed).unwrap();
+        assert!(ContractAddress::parse(&prefixed, NetworkId::TestNet).is_err());
+        assert!(ContractAddress::from_serialized(
+            &hex::decode(&prefixed).unwrap(),
+            NetworkId::TestNet
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn shielded_addresses_round_trip() {
+        let state = crate::wallet_from_seed([7; 32]);
+
+        let address = shielded_address(&state, NetworkId::TestNet).unwrap();
+        assert!(address.len() > 90);
+
+        let decoded = CheckedHrpstring::new::<LongBech32m>(&address).unwrap();
+        assert_eq!(decoded.hrp().as_str(), "mn_shield-addr_test");
+
+        let data = decoded.byte_iter().collect::<Vec<_>>();
+        assert_eq!(&data[..32], &state.coin_public_key().0 .0[..]);
+
+        let mut enc_public_key = vec![];
+        <_ as Serializable>::serialize(&state.enc_public_key(), &mut enc_public_key).unwrap();
+        assert_eq!(&data[32..], &enc_public_key[..]);
+    }
+}
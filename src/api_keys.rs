@@ -0,0 +1,79 @@
+use crate::db::Db;
+use clap::{arg, ArgMatches, Command};
+use rand::{rngs::OsRng, Rng as _};
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Only the hash of the key is stored, so a leaked db file can't be used to
+/// impersonate partners.
+pub fn hash_key(key: &str) -> String {
+    sha256::digest(key)
+}
+
+pub fn generate_key() -> String {
+    hex::encode(OsRng.gen::<[u8; 32]>())
+}
+
+pub fn command() -> Command {
+    Command::new("api-key")
+        .about("Manage the API keys allowed to submit transactions")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("add")
+                .about("Create a new key and print it")
+                .arg(arg!(<NAME> "a unique name for the key owner"))
+                .arg(
+                    arg!(--budget <DUST> "total amount of fees the key can spend")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(arg!(--"requests-per-minute" <COUNT>).value_parser(clap::value_parser!(u32))),
+        )
+        .subcommand(Command::new("list").about("List the existing keys and their usage"))
+        .subcommand(
+            Command::new("revoke")
+                .about("Delete a key")
+                .arg(arg!(<NAME>)),
+        )
+}
+
+pub async fn run(db: &Db, matches: &ArgMatches) -> anyhow::Result<()> {
+    match matches.subcommand() {
+        Some(("add", matches)) => {
+            let name = matches.get_one::<String>("NAME").expect("required");
+            let budget = matches.get_one::<u64>("budget").copied();
+            let requests_per_minute = matches.get_one::<u32>("requests-per-minute").copied();
+
+            let key = generate_key();
+
+            db.insert_api_key(name, &hash_key(&key), budget, requests_per_minute)
+                .await?;
+
+            println!("{}", key);
+        }
+        Some(("list", _)) => {
+            for key in db.get_api_keys().await? {
+                println!(
+                    "{}\tspent: {}\tbudget: {}\trequests per minute: {}",
+                    key.name,
+                    key.spent,
+                    key.budget
+                        .map(|budget| budget.to_string())
+                        .unwrap_or_else(|| "unlimited".to_string()),
+                    key.requests_per_minute
+                        .map(|rpm| rpm.to_string())
+                        .unwrap_or_else(|| "unlimited".to_string()),
+                );
+            }
+        }
+        Some(("revoke", matches)) => {
+            let name = matches.get_one::<String>("NAME").expect("required");
+
+            if !db.revoke_api_key(name).await? {
+                anyhow::bail!("api key {} not found", name);
+            }
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+
+    Ok(())
+}
@@ -1,23 +1,32 @@
 use crate::{
-    db::Db,
+    coin_selection::CoinSelectionConfig,
+    config::FeePolicy,
+    consolidation::ConsolidationConfig,
+    db::{Db, PlayerFeeReservation},
     endpoints::Error,
     midnight::{self},
+    player_limits::PlayerBudget,
     preproofing::{prove_tx_in_rayon_pool, PreProvingServiceChannelTx},
-    utils::OnDrop,
+    utils::{unix_now, OnDrop},
     whitelisting::{self, check_call, check_deploy},
 };
 use anyhow::Context as _;
 use midnight_ledger::structure::{Transaction, DUMMY_PARAMETERS};
-use midnight_transient_crypto::proofs::{IrSource, ParamsProver, Proof, ProverKey, VerifierKey};
+use midnight_transient_crypto::proofs::{
+    IrSource, ParamsProver, Proof, ProofPreimage, ProverKey, VerifierKey,
+};
 use midnight_zswap::{
-    coin_structure::{self, coin::NATIVE_TOKEN},
+    coin_structure::{
+        self,
+        coin::{QualifiedInfo, TokenType, NATIVE_TOKEN},
+    },
     local::State,
     serialize::{deserialize, serialize, NetworkId},
-    Offer, Output,
+    Input, Offer, Output,
 };
 use rand::{rngs::OsRng, Rng as _};
+use rocket::http::Header;
 use std::{
-    cmp::Reverse,
     fs::File,
     io::{BufReader, Cursor},
     sync::Arc,
@@ -141,6 +150,14 @@ impl ProvingParams {
     }
 }
 
+/// Budgets the fees of a transaction are charged to.
+#[derive(Default)]
+pub struct FeeBudgets {
+    /// hash of the api key used in the request
+    pub api_key: Option<String>,
+    pub player: Option<PlayerBudget>,
+}
+
 #[allow(clippy::too_many_arguments)]
 pub async fn balance_and_submit_tx(
     prover_params: Arc<ProvingParams>,
@@ -151,6 +168,9 @@ pub async fn balance_and_submit_tx(
     inputs_service: PreProvingServiceChannelTx,
     whitelisting: &Option<whitelisting::Constraints>,
     db: &Db,
+    budgets: FeeBudgets,
+    fee_policy: &FeePolicy,
+    coin_selection: &CoinSelectionConfig,
 ) -> Result<(String, Vec<String>), Error> {
     // TODO: we should fetch this from the ledger state, but this works right now anyway.
     let parameters = DUMMY_PARAMETERS;
@@ -180,81 +200,290 @@ pub async fn balance_and_submit_tx(
         }
     }
 
-    let mut state_guard = base_state.lock().await;
-
-    // TODO: this probably only works for a single input and a single output.
-    //
-    // figure out how to generalize
-    let zswap_cost_estimation = 40000;
     let cost = unbalanced_tx
         .cost(&parameters)
         .map_err(|e| Error::InternalError(e.to_string()))?;
 
-    let fees = cost + zswap_cost_estimation;
+    let contributions = Contributions::new(&Imbalances::of(&unbalanced_tx), fee_policy)?;
+
+    let mut state_guard = base_state.lock().await;
+
+    // spent on a copy, so that nothing is left pending if some coins are
+    // missing or the budgets are exhausted.
+    let mut state = state_guard.clone();
+
+    let mut guaranteed = OfferBalancing::default();
+    for (token_type, amount) in &contributions.guaranteed_tokens {
+        guaranteed.add(&mut state, *token_type, *amount, coin_selection)?;
+    }
+
+    let mut fallible = OfferBalancing::default();
+    for (token_type, amount) in &contributions.fallible {
+        fallible.add(&mut state, *token_type, *amount, coin_selection)?;
+    }
+
+    // the inputs and change outputs of both offers are paid for by the native
+    // fees, so they are picked first. The native inputs, dust included, and
+    // their change output are paid for too.
+    let fees = |native_inputs| {
+        cost + zswap_fees(fee_policy, native_inputs, 1)
+            + guaranteed.zswap_fees(fee_policy)
+            + fallible.zswap_fees(fee_policy)
+    };
+
+    let (inputs, curr_balance) = spend_coins(
+        &mut state,
+        NATIVE_TOKEN,
+        |native_inputs| contributions.native_to_cover(fees(native_inputs)),
+        coin_selection,
+    )?;
+
+    let native = contributions.native(fees(inputs.len()));
 
-    let mut to_spend = vec![];
-    let mut curr_balance = 0;
+    // the budgets are reserved before the coins are taken from the wallet, and
+    // given back if the transaction doesn't make it.
+    let amount = u64::try_from(contributions.native_total(native))
+        .map_err(|_| Error::BadRequest("Transaction fees are too big".to_string()))?;
+
+    let mut on_drop_release_api_key_budget = if let Some(key_hash) = budgets.api_key {
+        if !db.reserve_api_key_budget(&key_hash, amount).await? {
+            return Err(Error::Forbidden("Api key budget exhausted".to_string()));
+        }
+
+        let db = db.clone();
+        Some(OnDrop::new(move || {
+            tokio::task::spawn(async move {
+                if let Err(error) = db.release_api_key_budget(&key_hash, amount).await {
+                    tracing::error!(?error, "failed to release api key budget");
+                }
+            });
+        }))
+    } else {
+        None
+    };
+
+    let mut on_drop_release_player_fee = if let Some(player_budget) = budgets.player {
+        match db
+            .reserve_player_fee(
+                &player_budget.player,
+                amount,
+                player_budget.daily_fee_budget,
+                unix_now(),
+            )
+            .await?
+        {
+            PlayerFeeReservation::Reserved(id) => {
+                let db = db.clone();
+                Some(OnDrop::new(move || {
+                    tokio::task::spawn(async move {
+                        if let Err(error) = db.release_player_fee(id).await {
+                            tracing::error!(?error, "failed to release player fee");
+                        }
+                    });
+                }))
+            }
+            PlayerFeeReservation::Exceeded { retry_after } => {
+                return Err(Error::TooManyRequests(
+                    format!(
+                        "Daily fee budget exceeded, retry in {} seconds",
+                        retry_after
+                    ),
+                    Header::new("Retry-After", retry_after.to_string()),
+                ));
+            }
+        }
+    } else {
+        None
+    };
+
+    *state_guard = state;
+
+    let coin_public_key = state_guard.coin_public_key();
+    let enc_public_key = state_guard.enc_public_key();
+
+    std::mem::drop(state_guard);
+
+    let mut on_drop_remove_inputs_from_pending = remove_from_pending_on_drop(
+        base_state,
+        inputs
+            .iter()
+            .chain(&guaranteed.inputs)
+            .chain(&fallible.inputs)
+            .cloned()
+            .collect(),
+    );
+
+    let inputs_tx = pre_proven_inputs(&inputs_service, &inputs).await?;
+
+    let tx_ids = prove_and_submit(
+        inputs_tx,
+        curr_balance,
+        native,
+        Arc::clone(&prover_params),
+        PublicKeys {
+            coin_public_key,
+            enc_public_key,
+        },
+        Some(unbalanced_tx),
+        guaranteed,
+        fallible,
+        network_id,
+        api,
+    )
+    .await?;
+
+    on_drop_remove_inputs_from_pending.cancel();
+
+    if let Some(on_drop) = on_drop_release_api_key_budget.as_mut() {
+        on_drop.cancel();
+    }
+
+    if let Some(on_drop) = on_drop_release_player_fee.as_mut() {
+        on_drop.cancel();
+    }
+
+    Ok(tx_ids)
+}
+
+/// Merges up to `max_inputs` of the smallest unspent native coins worth at most
+/// `dust_threshold` into a single coin, paying the fees from them. Returns the
+/// transaction hash and the number of coins merged, or `None` when fewer than
+/// `min_coins` of them would be merged or they don't cover the fees.
+#[allow(clippy::too_many_arguments)]
+pub async fn consolidate_dust(
+    prover_params: Arc<ProvingParams>,
+    api: &OnlineClient<SubstrateConfig>,
+    base_state: Arc<Mutex<State>>,
+    network_id: NetworkId,
+    inputs_service: PreProvingServiceChannelTx,
+    fee_policy: &FeePolicy,
+    dust_threshold: u64,
+    consolidation: &ConsolidationConfig,
+) -> Result<Option<(String, usize)>, Error> {
+    let mut state_guard = base_state.lock().await;
 
-    let mut sorted_coins = state_guard
+    let candidates = state_guard
         .coins
         .iter()
-        // we only need to pay fees, so we don't care about utxos for other assets
         .filter(|(_, coin)| coin.type_ == NATIVE_TOKEN)
         .filter(|(null, _)| !state_guard.pending_spends.contains_key(null))
+        .map(|(_, coin)| coin)
         .collect::<Vec<_>>();
 
-    // always pick the biggest unused utxo first, to spend evenly from the pool.
-    sorted_coins.sort_by_key(|(_, coin)| Reverse(coin.value));
+    let values = candidates.iter().map(|coin| coin.value).collect::<Vec<_>>();
 
-    for coin in sorted_coins {
-        curr_balance += coin.1.value;
-        to_spend.push(coin);
+    let dust = dust_to_merge(&values, dust_threshold, consolidation)
+        .into_iter()
+        .map(|i| candidates[i].clone())
+        .collect::<Vec<_>>();
 
-        if curr_balance >= fees {
-            break;
-        }
+    if dust.is_empty() {
+        return Ok(None);
     }
 
-    if curr_balance < fees {
-        tracing::error!(
-            curr_balance,
-            fees,
-            "not enough funds to balance transaction"
-        );
-        return Err(Error::NotAvailable("No funds available".to_string()));
+    // the dust inputs and the merged coin.
+    let fees = zswap_fees(fee_policy, dust.len(), 1);
+    let curr_balance: u128 = dust.iter().map(|coin| coin.value).sum();
+
+    if curr_balance <= fees {
+        return Ok(None);
     }
 
+    let coins = dust.len();
+
+    let mut state = state_guard.clone();
     let mut inputs = vec![];
-    for coin in to_spend {
-        let (new_state, input) = state_guard
-            .spend(&mut OsRng, &coin.1)
+    for coin in dust {
+        let (new_state, input) = state
+            .spend(&mut OsRng, &coin)
             .map_err(|e| Error::InternalError(e.to_string()))?;
 
-        *state_guard = new_state;
+        state = new_state;
         inputs.push(input);
     }
 
+    *state_guard = state;
+
     let coin_public_key = state_guard.coin_public_key();
     let enc_public_key = state_guard.enc_public_key();
 
     std::mem::drop(state_guard);
 
-    let mut on_drop_remove_inputs_from_pending = {
-        let inputs = inputs.clone();
-        OnDrop::new(move || {
-            tokio::task::spawn(async move {
-                let offer = Offer {
-                    inputs,
-                    outputs: vec![],
-                    transient: vec![],
-                    deltas: vec![],
-                };
-                let mut state = base_state.lock().await;
-                *state = state.apply_failed(&offer);
-            });
-        })
-    };
+    let mut on_drop_remove_inputs_from_pending =
+        remove_from_pending_on_drop(base_state, inputs.clone());
 
+    let inputs_tx = pre_proven_inputs(&inputs_service, &inputs).await?;
+
+    let (tx_hash, _) = prove_and_submit(
+        inputs_tx,
+        curr_balance,
+        fees,
+        prover_params,
+        PublicKeys {
+            coin_public_key,
+            enc_public_key,
+        },
+        None,
+        OfferBalancing::default(),
+        OfferBalancing::default(),
+        network_id,
+        api,
+    )
+    .await?;
+
+    on_drop_remove_inputs_from_pending.cancel();
+
+    Ok(Some((tx_hash, coins)))
+}
+
+/// Indexes of up to `max_inputs` of the smallest `values` worth at most
+/// `dust_threshold`, or none when fewer than `min_coins` of them, and at least
+/// two, would be merged.
+fn dust_to_merge(
+    values: &[u128],
+    dust_threshold: u64,
+    consolidation: &ConsolidationConfig,
+) -> Vec<usize> {
+    let mut dust = (0..values.len())
+        .filter(|i| values[*i] <= u128::from(dust_threshold))
+        .collect::<Vec<_>>();
+
+    dust.sort_by_key(|i| values[*i]);
+    dust.truncate(consolidation.max_inputs);
+
+    if dust.len() < consolidation.min_coins.max(2) {
+        return vec![];
+    }
+
+    dust
+}
+
+/// Gives the coins of `inputs` back to the wallet when dropped, unless
+/// cancelled once the transaction spending them is submitted.
+fn remove_from_pending_on_drop(
+    base_state: Arc<Mutex<State>>,
+    inputs: Vec<Input<ProofPreimage>>,
+) -> OnDrop<impl FnOnce()> {
+    OnDrop::new(move || {
+        tokio::task::spawn(async move {
+            let offer = Offer {
+                inputs,
+                outputs: vec![],
+                transient: vec![],
+                deltas: vec![],
+            };
+            let mut state = base_state.lock().await;
+            *state = state.apply_failed(&offer);
+        });
+    })
+}
+
+/// The proofs of `inputs` from the pre-proving service, merged in a single
+/// transaction.
+async fn pre_proven_inputs(
+    inputs_service: &PreProvingServiceChannelTx,
+    inputs: &[Input<ProofPreimage>],
+) -> Result<Transaction<Proof>, Error> {
     let (inputs_tx, inputs_rx) = tokio::sync::oneshot::channel();
     inputs_service
         .send((
@@ -266,31 +495,307 @@ pub async fn balance_and_submit_tx(
 
     let proven_inputs = inputs_rx.await.unwrap();
 
-    let inputs_tx = proven_inputs
+    proven_inputs
         .into_iter()
         .map(Ok)
         .reduce(|tx1, tx2| tx1?.merge(&tx2?))
         .ok_or_else(|| Error::InternalError("pre-computed proofs are empty".to_string()))?
-        .map_err(|e| Error::InternalError(e.to_string()))?;
+        .map_err(|e| Error::InternalError(e.to_string()))
+}
 
-    let tx_ids = prove_and_submit(
-        inputs_tx.clone(),
-        curr_balance,
-        fees,
-        Arc::clone(&prover_params),
-        PublicKeys {
-            coin_public_key,
-            enc_public_key,
-        },
-        unbalanced_tx,
-        network_id,
-        api,
-    )
-    .await?;
+/// Picks unspent coins of `token_type` covering the target for their number
+/// with the configured strategy, returning them with their total value.
+fn select_coins(
+    state: &State,
+    token_type: TokenType,
+    target: impl Fn(usize) -> u128,
+    selection: &CoinSelectionConfig,
+) -> Option<(Vec<QualifiedInfo>, u128)> {
+    let candidates = state
+        .coins
+        .iter()
+        .filter(|(_, coin)| coin.type_ == token_type)
+        .filter(|(null, _)| !state.pending_spends.contains_key(null))
+        .map(|(_, coin)| coin)
+        .collect::<Vec<_>>();
 
-    on_drop_remove_inputs_from_pending.cancel();
+    let values = candidates.iter().map(|coin| coin.value).collect::<Vec<_>>();
 
-    Ok(tx_ids)
+    let selected = selection.select_covering(&values, target, &mut OsRng)?;
+
+    let to_spend = selected
+        .into_iter()
+        .map(|i| candidates[i].clone())
+        .collect::<Vec<_>>();
+    let curr_balance = to_spend.iter().map(|coin| coin.value).sum();
+
+    Some((to_spend, curr_balance))
+}
+
+/// Spends coins of `token_type` worth at least `amount`, given the number of
+/// coins spent, returning the inputs and their total value.
+fn spend_coins(
+    state: &mut State,
+    token_type: TokenType,
+    amount: impl Fn(usize) -> u128,
+    selection: &CoinSelectionConfig,
+) -> Result<(Vec<Input<ProofPreimage>>, u128), Error> {
+    let min_amount = amount(1);
+
+    let Some((coins, balance)) = select_coins(state, token_type, amount, selection) else {
+        tracing::error!(
+            token_type = hex::encode(token_type.0 .0),
+            amount = min_amount,
+            "not enough funds to balance transaction"
+        );
+        return Err(Error::NotAvailable("No funds available".to_string()));
+    };
+
+    let mut inputs = vec![];
+    for coin in coins {
+        let (new_state, input) = state
+            .spend(&mut OsRng, &coin)
+            .map_err(|e| Error::InternalError(e.to_string()))?;
+
+        *state = new_state;
+        inputs.push(input);
+    }
+
+    Ok((inputs, balance))
+}
+
+/// Value of the inputs minus the value of the outputs of the offers of a
+/// transaction, per token type.
+struct Imbalances {
+    guaranteed: Vec<(TokenType, i128)>,
+    fallible: Vec<(TokenType, i128)>,
+}
+
+impl Imbalances {
+    fn of(tx: &Transaction<Proof>) -> Self {
+        let Transaction::Standard(stx) = tx else {
+            return Self {
+                guaranteed: vec![],
+                fallible: vec![],
+            };
+        };
+
+        Self {
+            guaranteed: offer_imbalances(&stx.guaranteed_coins),
+            fallible: stx
+                .fallible_coins
+                .as_ref()
+                .map(offer_imbalances)
+                .unwrap_or_default(),
+        }
+    }
+
+    fn guaranteed_native(&self) -> i128 {
+        self.guaranteed
+            .iter()
+            .find(|(token_type, _)| *token_type == NATIVE_TOKEN)
+            .map_or(0, |(_, delta)| *delta)
+    }
+}
+
+fn offer_imbalances(offer: &Offer<Proof>) -> Vec<(TokenType, i128)> {
+    let mut imbalances: Vec<(TokenType, i128)> = vec![];
+
+    for (token_type, delta) in &offer.deltas {
+        match imbalances.iter_mut().find(|(t, _)| t == token_type) {
+            Some((_, total)) => *total += delta,
+            None => imbalances.push((*token_type, *delta)),
+        }
+    }
+
+    imbalances
+}
+
+/// What the batcher puts in each offer of the transaction. The fees are
+/// only paid from the guaranteed offer, since the fallible one may fail.
+struct Contributions {
+    /// native tokens the guaranteed offer is short of, or has in excess when
+    /// positive
+    guaranteed_native: i128,
+    /// custom tokens the guaranteed offer is short of
+    guaranteed_tokens: Vec<(TokenType, u128)>,
+    /// tokens the fallible offer is short of, native included
+    fallible: Vec<(TokenType, u128)>,
+}
+
+impl Contributions {
+    fn new(imbalances: &Imbalances, fee_policy: &FeePolicy) -> Result<Self, Error> {
+        let guaranteed = deficits(&imbalances.guaranteed);
+        let fallible = deficits(&imbalances.fallible);
+
+        if let Some((token_type, amount)) = fallible.first() {
+            if !fee_policy.balance_fallible {
+                return Err(Error::BadRequest(format!(
+                    "The fallible offer is short of {} of token {}, which can't be balanced",
+                    amount,
+                    hex::encode(token_type.0 .0)
+                )));
+            }
+        }
+
+        let native_deficit: u128 = guaranteed
+            .iter()
+            .chain(&fallible)
+            .filter(|(token_type, _)| *token_type == NATIVE_TOKEN)
+            .map(|(_, amount)| amount)
+            .sum();
+
+        if native_deficit > u128::from(fee_policy.max_native_deficit) {
+            return Err(Error::Forbidden(format!(
+                "Transaction is short of {} native tokens besides the fees, more than the allowed {}",
+                native_deficit, fee_policy.max_native_deficit
+            )));
+        }
+
+        for (token_type, _) in guaranteed.iter().chain(&fallible) {
+            if *token_type == NATIVE_TOKEN {
+                continue;
+            }
+
+            let token_hex = hex::encode(token_type.0 .0);
+
+            let policy = fee_policy
+                .tokens
+                .iter()
+                .find(|policy| policy.token_type.eq_ignore_ascii_case(&token_hex))
+                .ok_or_else(|| {
+                    Error::BadRequest(format!("Token {} can't be balanced", token_hex))
+                })?;
+
+            let amount: u128 = guaranteed
+                .iter()
+                .chain(&fallible)
+                .filter(|(t, _)| t == token_type)
+                .map(|(_, amount)| amount)
+                .sum();
+
+            if amount > u128::from(policy.max_per_transaction) {
+                return Err(Error::Forbidden(format!(
+                    "Transaction needs {} of token {}, more than the allowed {}",
+                    amount, token_hex, policy.max_per_transaction
+                )));
+            }
+        }
+
+        Ok(Self {
+            guaranteed_native: imbalances.guaranteed_native(),
+            guaranteed_tokens: guaranteed
+                .into_iter()
+                .filter(|(token_type, _)| *token_type != NATIVE_TOKEN)
+                .collect(),
+            fallible,
+        })
+    }
+
+    /// Native tokens of the guaranteed offer: the fees, plus what the offer is
+    /// short of, or minus what it has in excess.
+    fn native(&self, fees: u128) -> u128 {
+        if self.guaranteed_native < 0 {
+            fees + self.guaranteed_native.unsigned_abs()
+        } else {
+            fees.saturating_sub(self.guaranteed_native as u128)
+        }
+    }
+
+    /// Native tokens the pre-proven inputs have to cover. At least one input is
+    /// spent, even when the transaction overpays its fees, as the inputs
+    /// transaction can't be empty.
+    fn native_to_cover(&self, fees: u128) -> u128 {
+        self.native(fees).max(1)
+    }
+
+    /// All the native tokens given out, `native` in the guaranteed offer and
+    /// the deficit of the fallible one, which are charged to the budgets.
+    fn native_total(&self, native: u128) -> u128 {
+        native
+            + self
+                .fallible
+                .iter()
+                .filter(|(token_type, _)| *token_type == NATIVE_TOKEN)
+                .map(|(_, amount)| amount)
+                .sum::<u128>()
+    }
+}
+
+/// Estimated fees of `inputs` and `outputs` added by the batcher. The
+/// `zswap_cost_estimation` covers a single input and its change output, so
+/// half of it is charged for each of them.
+fn zswap_fees(fee_policy: &FeePolicy, inputs: usize, outputs: usize) -> u128 {
+    u128::from(fee_policy.zswap_cost_estimation) * (inputs + outputs) as u128 / 2
+}
+
+/// The tokens an offer is short of.
+fn deficits(imbalances: &[(TokenType, i128)]) -> Vec<(TokenType, u128)> {
+    imbalances
+        .iter()
+        .filter(|(_, delta)| *delta < 0)
+        .map(|(token_type, delta)| (*token_type, delta.unsigned_abs()))
+        .collect()
+}
+
+/// Coins the batcher adds to one of the offers of the transaction, besides the
+/// pre-proven native inputs of the guaranteed offer. They are proven with the
+/// outputs.
+#[derive(Default)]
+struct OfferBalancing {
+    inputs: Vec<Input<ProofPreimage>>,
+    /// token type, change sent back to the batcher and amount given out
+    changes: Vec<(TokenType, u128, u128)>,
+}
+
+impl OfferBalancing {
+    fn add(
+        &mut self,
+        state: &mut State,
+        token_type: TokenType,
+        amount: u128,
+        selection: &CoinSelectionConfig,
+    ) -> Result<(), Error> {
+        let (inputs, balance) = spend_coins(state, token_type, |_| amount, selection)?;
+
+        self.inputs.extend(inputs);
+        self.changes.push((token_type, balance - amount, amount));
+
+        Ok(())
+    }
+
+    /// Estimated fees of the inputs and change outputs added so far.
+    fn zswap_fees(&self, fee_policy: &FeePolicy) -> u128 {
+        let outputs = self
+            .changes
+            .iter()
+            .filter(|(_, change, _)| *change > 0)
+            .count();
+
+        zswap_fees(fee_policy, self.inputs.len(), outputs)
+    }
+
+    /// The offer with the inputs, the change outputs and the amounts given out
+    /// as deltas.
+    fn into_offer(self, public_keys: &PublicKeys) -> Result<Offer<ProofPreimage>, Error> {
+        let mut outputs = vec![];
+        let mut deltas = vec![];
+
+        for (token_type, change, amount) in self.changes {
+            if change > 0 {
+                outputs.push(change_output(public_keys, token_type, change)?);
+            }
+
+            deltas.push((token_type, amount as i128));
+        }
+
+        Ok(Offer {
+            inputs: self.inputs,
+            outputs,
+            transient: vec![],
+            deltas,
+        })
+    }
 }
 
 struct PublicKeys {
@@ -298,37 +803,55 @@ struct PublicKeys {
     enc_public_key: midnight_transient_crypto::encryption::PublicKey,
 }
 
+fn change_output(
+    public_keys: &PublicKeys,
+    token_type: TokenType,
+    value: u128,
+) -> Result<Output<ProofPreimage>, Error> {
+    Output::new(
+        &mut OsRng,
+        &coin_structure::coin::Info {
+            nonce: OsRng.gen(),
+            type_: token_type,
+            value,
+        },
+        &public_keys.coin_public_key,
+        Some(public_keys.enc_public_key),
+    )
+    .map_err(|e| Error::InternalError(e.to_string()))
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn prove_and_submit(
     inputs_tx: Transaction<Proof>,
     curr_balance: u128,
-    fees: u128,
+    native_amount: u128,
     prover_params: Arc<ProvingParams>,
     public_keys: PublicKeys,
-    unbalanced_tx: Transaction<Proof>,
+    unbalanced_tx: Option<Transaction<Proof>>,
+    guaranteed: OfferBalancing,
+    fallible: OfferBalancing,
     network_id: NetworkId,
     api: &OnlineClient<SubstrateConfig>,
 ) -> Result<(String, Vec<String>), Error> {
-    let value = curr_balance - fees;
-
-    let outputs_offer_tx = Offer {
-        inputs: vec![],
-        outputs: vec![Output::new(
-            &mut OsRng,
-            &coin_structure::coin::Info {
-                nonce: OsRng.gen(),
-                type_: NATIVE_TOKEN,
-                value,
-            },
-            &public_keys.coin_public_key,
-            Some(public_keys.enc_public_key),
-        )
-        .map_err(|e| Error::InternalError(e.to_string()))?],
-        transient: vec![],
-        deltas: vec![(NATIVE_TOKEN, fees as i128)],
+    let value = curr_balance - native_amount;
+
+    let mut guaranteed_offer = guaranteed.into_offer(&public_keys)?;
+
+    guaranteed_offer
+        .outputs
+        .push(change_output(&public_keys, NATIVE_TOKEN, value)?);
+    guaranteed_offer
+        .deltas
+        .push((NATIVE_TOKEN, native_amount as i128));
+
+    let fallible_offer = if fallible.inputs.is_empty() {
+        None
+    } else {
+        Some(fallible.into_offer(&public_keys)?)
     };
 
-    let outputs_tx = Transaction::new(outputs_offer_tx, None, None);
+    let outputs_tx = Transaction::new(guaranteed_offer, fallible_offer, None);
 
     let instant = std::time::Instant::now();
 
@@ -339,12 +862,16 @@ async fn prove_and_submit(
         instant.elapsed().as_millis()
     );
 
-    let final_tx = inputs_tx
+    let mut final_tx = inputs_tx
         .merge(&outputs_tx)
-        .map_err(|e| Error::InternalError(e.to_string()))?
-        .merge(&unbalanced_tx)
         .map_err(|e| Error::InternalError(e.to_string()))?;
 
+    if let Some(unbalanced_tx) = unbalanced_tx {
+        final_tx = final_tx
+            .merge(&unbalanced_tx)
+            .map_err(|e| Error::InternalError(e.to_string()))?;
+    }
+
     let mut serialized_final_tx = vec![];
 
     serialize(
@@ -410,3 +937,107 @@ async fn prove_and_submit(
 
     Ok((tx_hash, identifiers))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contributions(guaranteed_native: i128) -> Contributions {
+        Contributions::new(
+            &Imbalances {
+                guaranteed: vec![(NATIVE_TOKEN, guaranteed_native)],
+                fallible: vec![],
+            },
+            &FeePolicy {
+                max_native_deficit: 1000,
+                ..FeePolicy::default()
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn native_contribution_includes_the_token_fees() {
+        let fee_policy = FeePolicy::default();
+
+        // a custom token balanced with two inputs and a change output
+        let token_fees = zswap_fees(&fee_policy, 2, 1);
+        assert_eq!(
+            token_fees,
+            u128::from(fee_policy.zswap_cost_estimation) * 3 / 2
+        );
+
+        let fees = 1000 + token_fees;
+
+        assert_eq!(contributions(0).native(fees), fees);
+        assert_eq!(contributions(-100).native(fees), fees + 100);
+        assert_eq!(contributions(100).native(fees), fees - 100);
+        assert_eq!(contributions(i128::from(u32::MAX)).native(fees), 0);
+    }
+
+    #[test]
+    fn overpaid_fees_still_spend_a_native_input() {
+        let fees = 1000;
+        let overpaying = contributions(2000);
+
+        assert_eq!(overpaying.native(fees), 0);
+        assert_eq!(overpaying.native_to_cover(fees), 1);
+
+        let selected = CoinSelectionConfig::default()
+            .select_covering(
+                &[500, 100],
+                |_| overpaying.native_to_cover(fees),
+                &mut OsRng,
+            )
+            .unwrap();
+
+        assert_eq!(selected.len(), 1);
+    }
+
+    #[test]
+    fn dust_is_merged_smallest_first() {
+        let consolidation = ConsolidationConfig {
+            min_coins: 2,
+            max_inputs: 3,
+            ..ConsolidationConfig::default()
+        };
+
+        let values = [50, 1000, 10, 100, 30, 101, 20];
+
+        // the coins above the threshold are left out, and the biggest of the
+        // dust ones past `max_inputs`
+        assert_eq!(dust_to_merge(&values, 100, &consolidation), vec![2, 6, 4]);
+        assert!(dust_to_merge(&values, 10, &consolidation).is_empty());
+    }
+
+    #[test]
+    fn dust_is_only_merged_from_min_coins() {
+        let values = [10, 20, 30, 1000];
+        let consolidation = |min_coins| ConsolidationConfig {
+            min_coins,
+            ..ConsolidationConfig::default()
+        };
+
+        assert_eq!(
+            dust_to_merge(&values, 100, &consolidation(3)),
+            vec![0, 1, 2]
+        );
+        assert!(dust_to_merge(&values, 100, &consolidation(4)).is_empty());
+        // a single coin is never merged
+        assert!(dust_to_merge(&values, 10, &consolidation(0)).is_empty());
+    }
+
+    #[test]
+    fn offer_fees_count_the_change_outputs() {
+        let fee_policy = FeePolicy::default();
+
+        // given out without change, as in a fallible offer short of exactly
+        // the value of a coin
+        let offer = OfferBalancing {
+            inputs: vec![],
+            changes: vec![(NATIVE_TOKEN, 5, 10), (NATIVE_TOKEN, 0, 10)],
+        };
+
+        assert_eq!(offer.zswap_fees(&fee_policy), zswap_fees(&fee_policy, 0, 1));
+    }
+}
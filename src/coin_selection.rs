@@ -0,0 +1,488 @@
+//! Strategies to pick the coins spent to balance a transaction. They work on
+//! plain coin values, so that they can be simulated without a wallet.
+
+use rand::{seq::SliceRandom as _, Rng};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+
+/// Most subsets tried by the branch and bound search before falling back to
+/// largest-first.
+const BRANCH_AND_BOUND_TRIES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// biggest coins first, spends evenly from the pool
+    #[default]
+    LargestFirst,
+    /// the smallest coin covering the target, after taking the biggest ones
+    /// while none does
+    SmallestSufficient,
+    /// a subset worth the target up to `exact_match_tolerance`, so that there
+    /// is no change, or largest-first if there is none
+    BranchAndBound,
+    /// random coins, then more random ones getting the change closer to the
+    /// target, so that the coins spent don't tell much about the batcher
+    RandomImprove,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoinSelectionConfig {
+    pub strategy: Strategy,
+    /// change accepted by the branch and bound search
+    pub exact_match_tolerance: u64,
+    /// coins worth at most this much are added to the selections, to merge
+    /// them into the change. 0 disables it
+    pub dust_threshold: u64,
+    /// most dust coins added to a single selection
+    pub max_dust_inputs: usize,
+}
+
+impl Default for CoinSelectionConfig {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::default(),
+            exact_match_tolerance: 0,
+            dust_threshold: 0,
+            max_dust_inputs: 2,
+        }
+    }
+}
+
+impl CoinSelectionConfig {
+    /// Indices of the coins to spend to cover `target`, `None` if all of them
+    /// don't.
+    pub fn select(&self, values: &[u128], target: u128, rng: &mut impl Rng) -> Option<Vec<usize>> {
+        if values.iter().sum::<u128>() < target {
+            return None;
+        }
+
+        let mut selected = match self.strategy {
+            Strategy::LargestFirst => largest_first(values, target),
+            Strategy::SmallestSufficient => smallest_sufficient(values, target),
+            Strategy::BranchAndBound => {
+                branch_and_bound(values, target, u128::from(self.exact_match_tolerance))
+                    .unwrap_or_else(|| largest_first(values, target))
+            }
+            Strategy::RandomImprove => random_improve(values, target, rng),
+        };
+
+        if self.dust_threshold > 0 {
+            let mut dust = (0..values.len())
+                .filter(|i| values[*i] <= u128::from(self.dust_threshold) && !selected.contains(i))
+                .collect::<Vec<_>>();
+
+            dust.sort_by_key(|i| values[*i]);
+
+            selected.extend(dust.into_iter().take(self.max_dust_inputs));
+        }
+
+        Some(selected)
+    }
+
+    /// Like [`Self::select`], for a target that grows with the number of coins
+    /// spent, like the fees paying for their inputs. Selects again with the
+    /// target of the coins picked, dust included, until they cover it.
+    pub fn select_covering(
+        &self,
+        values: &[u128],
+        target: impl Fn(usize) -> u128,
+        rng: &mut impl Rng,
+    ) -> Option<Vec<usize>> {
+        let mut inputs = 1;
+
+        loop {
+            let selected = self.select(values, target(inputs), rng)?;
+
+            // the target only grows with the inputs, so fewer of them are
+            // covered too.
+            if selected.len() <= inputs {
+                return Some(selected);
+            }
+
+            inputs = selected.len();
+        }
+    }
+
+    /// Order in which the coins are pre-proven, the ones more likely to be
+    /// picked first.
+    pub fn proving_order<T>(&self, coins: &mut [(T, u128)], rng: &mut impl Rng) {
+        match self.strategy {
+            Strategy::LargestFirst | Strategy::BranchAndBound => {
+                coins.sort_by_key(|(_, value)| Reverse(*value))
+            }
+            Strategy::SmallestSufficient => coins.sort_by_key(|(_, value)| *value),
+            Strategy::RandomImprove => coins.shuffle(rng),
+        }
+    }
+}
+
+fn largest_first(values: &[u128], target: u128) -> Vec<usize> {
+    let mut sorted = (0..values.len()).collect::<Vec<_>>();
+    sorted.sort_by_key(|i| Reverse(values[*i]));
+
+    let mut selected = vec![];
+    let mut total = 0;
+
+    for i in sorted {
+        if total >= target && !selected.is_empty() {
+            break;
+        }
+
+        total += values[i];
+        selected.push(i);
+    }
+
+    selected
+}
+
+fn smallest_sufficient(values: &[u128], target: u128) -> Vec<usize> {
+    let mut remaining = (0..values.len()).collect::<Vec<_>>();
+    remaining.sort_by_key(|i| values[*i]);
+
+    let mut selected = vec![];
+    let mut total = 0;
+
+    while let Some(&largest) = remaining.last() {
+        let missing = target.saturating_sub(total);
+
+        if let Some(position) = remaining.iter().position(|i| values[*i] >= missing) {
+            selected.push(remaining[position]);
+            break;
+        }
+
+        total += values[largest];
+        selected.push(largest);
+        remaining.pop();
+
+        if total >= target {
+            break;
+        }
+    }
+
+    selected
+}
+
+/// Depth first search over the coins sorted by value, including or excluding
+/// each one, and pruning the branches over the target plus the tolerance or
+/// that can't reach the target anymore.
+fn branch_and_bound(values: &[u128], target: u128, tolerance: u128) -> Option<Vec<usize>> {
+    let mut sorted = (0..values.len()).collect::<Vec<_>>();
+    sorted.sort_by_key(|i| Reverse(values[*i]));
+
+    // value of the coins after each position, to prune the branches that
+    // can't reach the target.
+    let mut remaining = vec![0; sorted.len() + 1];
+    for position in (0..sorted.len()).rev() {
+        remaining[position] = remaining[position + 1] + values[sorted[position]];
+    }
+
+    let mut best: Option<(u128, Vec<usize>)> = None;
+    let mut tries = 0;
+
+    // (position, total, selected)
+    let mut stack = vec![(0, 0, vec![])];
+
+    while let Some((position, total, selected)) = stack.pop() {
+        tries += 1;
+
+        if tries > BRANCH_AND_BOUND_TRIES {
+            break;
+        }
+
+        if total >= target {
+            let waste = total - target;
+
+            let better = match &best {
+                Some((best, _)) => waste < *best,
+                None => true,
+            };
+
+            if waste <= tolerance && better {
+                best = Some((waste, selected));
+
+                if waste == 0 {
+                    break;
+                }
+            }
+
+            continue;
+        }
+
+        if position == sorted.len() || total + remaining[position] < target {
+            continue;
+        }
+
+        stack.push((position + 1, total, selected.clone()));
+
+        let mut with = selected;
+        with.push(sorted[position]);
+        stack.push((position + 1, total + values[sorted[position]], with));
+    }
+
+    best.map(|(_, selected)| selected)
+}
+
+/// Random selection until the target is covered, then random coins are added
+/// while they get the total closer to twice the target without going over
+/// three times the target. This leaves change outputs of about the size of the
+/// requests, instead of many tiny ones.
+fn random_improve(values: &[u128], target: u128, rng: &mut impl Rng) -> Vec<usize> {
+    let mut shuffled = (0..values.len()).collect::<Vec<_>>();
+    shuffled.shuffle(rng);
+
+    let mut selected = vec![];
+    let mut total = 0;
+    let mut rest = vec![];
+
+    for i in shuffled {
+        if total >= target && !selected.is_empty() {
+            rest.push(i);
+        } else {
+            total += values[i];
+            selected.push(i);
+        }
+    }
+
+    let ideal = target.saturating_mul(2);
+    let limit = target.saturating_mul(3);
+
+    for i in rest {
+        let improved = total + values[i];
+
+        if improved <= limit && improved.abs_diff(ideal) < total.abs_diff(ideal) {
+            total = improved;
+            selected.push(i);
+        }
+    }
+
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng as _;
+    use rand_chacha::ChaCha20Rng;
+
+    #[derive(Debug, Default)]
+    struct SimulationReport {
+        requests: usize,
+        failed: usize,
+        inputs: usize,
+        max_inputs: usize,
+        coins_left: usize,
+        /// coins left worth less than the biggest fee
+        dust_left: usize,
+    }
+
+    /// Runs `requests` random fee payments over a pool of `initial_coins` coins of
+    /// `coin_value`, with a new coin received every `top_up_every` requests, to
+    /// compare how the strategies fragment the pool.
+    fn simulate(
+        config: &CoinSelectionConfig,
+        rng: &mut impl Rng,
+        requests: usize,
+        initial_coins: usize,
+        coin_value: u128,
+        fees: (u128, u128),
+        top_up_every: usize,
+    ) -> SimulationReport {
+        let mut coins = vec![coin_value; initial_coins];
+        let mut report = SimulationReport {
+            requests,
+            ..Default::default()
+        };
+
+        for request in 0..requests {
+            if top_up_every > 0 && request > 0 && request % top_up_every == 0 {
+                coins.push(coin_value);
+            }
+
+            let fee = rng.gen_range(fees.0..=fees.1);
+
+            let Some(mut selected) = config.select(&coins, fee, rng) else {
+                report.failed += 1;
+                continue;
+            };
+
+            report.inputs += selected.len();
+            report.max_inputs = report.max_inputs.max(selected.len());
+
+            let total = selected.iter().map(|i| coins[*i]).sum::<u128>();
+
+            // remove from the back, so that the indices stay valid.
+            selected.sort_unstable_by_key(|i| Reverse(*i));
+            for i in selected {
+                coins.swap_remove(i);
+            }
+
+            if total > fee {
+                coins.push(total - fee);
+            }
+        }
+
+        report.coins_left = coins.len();
+        report.dust_left = coins.iter().filter(|value| **value < fees.1).count();
+
+        report
+    }
+
+    #[test]
+    fn dust_inputs_are_covered() {
+        let config = CoinSelectionConfig {
+            dust_threshold: 1,
+            max_dust_inputs: 2,
+            ..Default::default()
+        };
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+        let values = [100, 50, 1, 1];
+        let target = |inputs: usize| 80 + 10 * inputs as u128;
+
+        let selected = config.select_covering(&values, target, &mut rng).unwrap();
+
+        assert_eq!(selected.len(), 4);
+        assert!(selected.iter().map(|i| values[*i]).sum::<u128>() >= target(selected.len()));
+
+        // not enough for the fees of the dust
+        let values = [100, 1, 1];
+        assert!(config.select_covering(&values, target, &mut rng).is_none());
+    }
+
+    /// 400 fee payments from 20 coins, with a new coin every 10 payments.
+    fn simulate_strategy(strategy: Strategy, dust_threshold: u64) -> SimulationReport {
+        let config = CoinSelectionConfig {
+            strategy,
+            exact_match_tolerance: 10_000,
+            dust_threshold,
+            ..Default::default()
+        };
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+        simulate(&config, &mut rng, 400, 20, 1_000_000, (40_000, 200_000), 10)
+    }
+
+    #[test]
+    fn largest_first_picks_the_biggest_coins() {
+        let values = [10, 40, 30, 20];
+
+        assert_eq!(largest_first(&values, 35), vec![1]);
+        assert_eq!(largest_first(&values, 50), vec![1, 2]);
+        assert_eq!(largest_first(&values, 0), vec![1]);
+    }
+
+    #[test]
+    fn smallest_sufficient_picks_the_smallest_covering_coin() {
+        let values = [10, 40, 30, 20];
+
+        assert_eq!(smallest_sufficient(&values, 25), vec![2]);
+        assert_eq!(smallest_sufficient(&values, 40), vec![1]);
+        // none covers it, so the biggest one is taken first
+        assert_eq!(smallest_sufficient(&values, 60), vec![1, 3]);
+    }
+
+    #[test]
+    fn branch_and_bound_finds_exact_matches() {
+        let values = [10, 40, 30, 20];
+
+        let selected = branch_and_bound(&values, 60, 0).unwrap();
+        assert_eq!(selected.iter().map(|i| values[*i]).sum::<u128>(), 60);
+
+        let selected = branch_and_bound(&values, 45, 5).unwrap();
+        assert_eq!(selected.iter().map(|i| values[*i]).sum::<u128>(), 50);
+
+        assert!(branch_and_bound(&values, 45, 4).is_none());
+        assert!(branch_and_bound(&values, 101, 10).is_none());
+    }
+
+    #[test]
+    fn random_improve_keeps_the_change_under_twice_the_target() {
+        let values = [10, 20, 30, 40, 50, 60, 70, 80, 90, 100];
+
+        for seed in 0..100 {
+            let mut rng = ChaCha20Rng::seed_from_u64(seed);
+            let selected = random_improve(&values, 50, &mut rng);
+            let total = selected.iter().map(|i| values[*i]).sum::<u128>();
+
+            assert!(total >= 50);
+            // only the first coins, taken until the target is covered, can go
+            // over three times the target.
+            assert!(total <= 150 || selected.len() == 1);
+        }
+    }
+
+    #[test]
+    fn select_fails_without_enough_coins() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+        for strategy in [
+            Strategy::LargestFirst,
+            Strategy::SmallestSufficient,
+            Strategy::BranchAndBound,
+            Strategy::RandomImprove,
+        ] {
+            let config = CoinSelectionConfig {
+                strategy,
+                ..Default::default()
+            };
+
+            assert!(config.select(&[10, 20], 31, &mut rng).is_none());
+            assert!(config.select(&[10, 20], 30, &mut rng).is_some());
+        }
+    }
+
+    #[test]
+    fn largest_first_fragmentation() {
+        let report = simulate_strategy(Strategy::LargestFirst, 0);
+
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.max_inputs, 1);
+        assert!(report.coins_left <= 60);
+    }
+
+    #[test]
+    fn smallest_sufficient_fragmentation() {
+        let report = simulate_strategy(Strategy::SmallestSufficient, 0);
+
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.max_inputs, 1);
+        // it keeps the big coins and leaves the small change behind
+        assert!(report.dust_left * 2 > report.coins_left);
+    }
+
+    #[test]
+    fn branch_and_bound_fragmentation() {
+        let report = simulate_strategy(Strategy::BranchAndBound, 0);
+
+        assert_eq!(report.failed, 0);
+        assert!(report.max_inputs <= 10);
+        assert!(report.coins_left <= 50);
+    }
+
+    #[test]
+    fn random_improve_fragmentation() {
+        let report = simulate_strategy(Strategy::RandomImprove, 0);
+
+        assert_eq!(report.failed, 0);
+        assert!(report.max_inputs <= 4);
+        assert!(report.coins_left <= 30);
+    }
+
+    #[test]
+    fn dust_inputs_merge_the_change() {
+        for strategy in [
+            Strategy::LargestFirst,
+            Strategy::SmallestSufficient,
+            Strategy::BranchAndBound,
+            Strategy::RandomImprove,
+        ] {
+            let report = simulate_strategy(strategy, 200_000);
+
+            assert_eq!(report.failed, 0, "{strategy:?}");
+            assert!(report.max_inputs <= 3, "{strategy:?}");
+            assert_eq!(report.dust_left, 0, "{strategy:?}");
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Offline operations, which only need the seed and the database.
+
+use crate::{
+    addresses, config::Config, db::Db, keystore, wallet_from_seed, whitelisting, STABLE_STATE_ID,
+};
+use clap::{arg, ArgAction, ArgMatches, Command};
+use midnight_zswap::serialize::serialize;
+use std::path::PathBuf;
+
+pub fn init_command() -> Command {
+    Command::new("init").about("Generate a new seed at the --secret path and print its address")
+}
+
+pub fn address_command() -> Command {
+    Command::new("address")
+        .about("Print the batcher address, as a Bech32m shielded address")
+        .arg(arg!(--legacy "print the coin_pk|enc_pk form instead").action(ArgAction::SetTrue))
+}
+
+pub fn status_command() -> Command {
+    Command::new("status").about("Print the coins, pending spends and contracts in the database")
+}
+
+pub fn resync_command() -> Command {
+    Command::new("resync")
+        .about(
+            "Clear the wallet state and the contracts, so the next run rebuilds them from genesis",
+        )
+        .arg(
+            arg!(--from <START> "where the next run syncs from, only 'genesis' is supported")
+                .default_value("genesis"),
+        )
+}
+
+pub fn verify_contract_command() -> Command {
+    Command::new("verify-contract")
+        .about("Print the contract constraints loaded from a keys directory")
+        .arg(arg!(<KEYS_DIR>).value_parser(clap::value_parser!(PathBuf)))
+}
+
+pub fn init(config: &Config, passphrase: keystore::PassphraseSource) -> anyhow::Result<()> {
+    let network_id = config.network.network_id();
+
+    let seed = keystore::create_seed_file(&config.secret, network_id, passphrase)?;
+
+    println!(
+        "{}",
+        addresses::shielded_address(&wallet_from_seed(seed), network_id)?
+    );
+
+    Ok(())
+}
+
+pub fn address(
+    config: &Config,
+    passphrase: keystore::PassphraseSource,
+    matches: &ArgMatches,
+) -> anyhow::Result<()> {
+    let network_id = config.network.network_id();
+
+    let state = wallet_from_seed(keystore::load_seed(&config.secret, network_id, passphrase)?);
+
+    if matches.get_flag("legacy") {
+        println!("{}", crate::address(&state));
+    } else {
+        println!("{}", addresses::shielded_address(&state, network_id)?);
+    }
+
+    Ok(())
+}
+
+async fn open_db(
+    config: &Config,
+    passphrase: keystore::PassphraseSource,
+) -> anyhow::Result<(Db, [u8; 32])> {
+    let network_id = config.network.network_id();
+
+    let seed = keystore::load_seed(&config.secret, network_id, passphrase)?;
+
+    let db = Db::open_db(&config.db, network_id)
+        .await?
+        .with_state_encryption(&seed);
+
+    Ok((db, seed))
+}
+
+pub async fn status(config: &Config, passphrase: keystore::PassphraseSource) -> anyhow::Result<()> {
+    let network_id = config.network.network_id();
+
+    let (db, _) = open_db(config, passphrase).await?;
+
+    match db.get_state(STABLE_STATE_ID).await? {
+        Some((hash, state)) => {
+            println!(
+                "address: {}",
+                addresses::shielded_address(&state, network_id)?
+            );
+            println!("legacy address: {}", crate::address(&state));
+            println!("last transaction: {}", hash);
+
+            println!("coins:");
+            for (nul, coin) in state.coins.iter() {
+                let mut buf = vec![];
+                serialize(&nul, &mut buf, network_id)?;
+                println!("  {} {}", hex::encode(buf), coin.value);
+            }
+
+            println!("pending spends:");
+            for (nul, _) in state.pending_spends.iter() {
+                let mut buf = vec![];
+                serialize(&nul, &mut buf, network_id)?;
+                println!("  {}", hex::encode(buf));
+            }
+        }
+        None => println!("wallet not synced yet"),
+    }
+
+    println!("contracts: {}", db.count_contracts().await?);
+
+    Ok(())
+}
+
+pub async fn resync(
+    config: &Config,
+    passphrase: keystore::PassphraseSource,
+    matches: &ArgMatches,
+) -> anyhow::Result<()> {
+    let from = matches.get_one::<String>("from").expect("default");
+
+    // the wallet state includes the merkle tree of every coin commitment on
+    // chain, which can't be rebuilt from a seed at an arbitrary transaction.
+    if from != "genesis" {
+        anyhow::bail!(
+            "can't resync from {}, only `--from genesis` is supported: the wallet state can't be rebuilt from the seed after a given transaction",
+            from
+        );
+    }
+
+    let (db, _) = open_db(config, passphrase).await?;
+
+    db.delete_state(STABLE_STATE_ID).await?;
+    db.clear_contracts().await?;
+
+    println!("state cleared, the next run will sync from genesis");
+
+    Ok(())
+}
+
+pub fn verify_contract(config: &Config, matches: &ArgMatches) -> anyhow::Result<()> {
+    let keys_dir = matches.get_one::<PathBuf>("KEYS_DIR").expect("required");
+
+    let constraints = whitelisting::read_constraints(keys_dir, config.network.network_id())?;
+
+    // the checksums of the verifier keys are logged by read_constraints.
+    let mut entry_points = constraints
+        .keys()
+        .map(|entry_point| String::from_utf8_lossy(&entry_point.0).to_string())
+        .collect::<Vec<_>>();
+
+    entry_points.sort();
+
+    println!("{} entry points:", entry_points.len());
+    for entry_point in entry_points {
+        println!("  {}", entry_point);
+    }
+
+    Ok(())
+}
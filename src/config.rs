@@ -0,0 +1,388 @@
+use crate::{
+    coin_selection::CoinSelectionConfig, consolidation::ConsolidationConfig,
+    player_limits::PlayerLimits,
+};
+use anyhow::Context as _;
+use clap::ArgMatches;
+use midnight_zswap::serialize::NetworkId;
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, path::PathBuf};
+use url::Url;
+
+const WS_INDEXER_LOCALHOST: &str = "ws://127.0.0.1:8088/api/v1/graphql/ws";
+const HTTP_INDEXER_LOCALHOST: &str = "http://127.0.0.1:8088/api/v1/graphql";
+const NODE_LOCALHOST: &str = "ws://127.0.0.1:9944";
+
+/// Batcher settings. Values are taken from the cli flags first, then from the
+/// environment variables, then from the config file, and finally from the
+/// defaults.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub indexer_ws: String,
+    pub indexer_http: String,
+    pub node: String,
+    pub network: Network,
+    pub db: PathBuf,
+    pub secret: PathBuf,
+    pub contract: Option<ContractProfile>,
+    /// a toml file with the PRC-1 achievements, defaults to the single
+    /// "played_first_match" one
+    pub achievements: Option<PathBuf>,
+    pub fees: FeePolicy,
+    pub coin_selection: CoinSelectionConfig,
+    pub consolidation: ConsolidationConfig,
+    pub proving: ProvingConfig,
+    pub server: ServerConfig,
+    /// only taken from the cli, since it should be a one-off
+    #[serde(skip)]
+    pub reset_state: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    TestNet,
+    Undeployed,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContractProfile {
+    /// a path to the 'keys' directory as generated by compact
+    pub keys: PathBuf,
+    /// a toml file describing the contract state fields to index, defaults to
+    /// the pvp lobby layout
+    pub schema: Option<PathBuf>,
+    /// also query the contract state from the indexer after each call, and
+    /// use it if it doesn't match the locally computed one
+    #[serde(default)]
+    pub verify_state: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeePolicy {
+    /// fees of an input added by the batcher and its change output, charged
+    /// on top of the cost of the unbalanced transaction, half for each input
+    /// and output
+    pub zswap_cost_estimation: u64,
+    pub require_api_key: bool,
+    pub player_requests_per_minute: Option<u32>,
+    pub player_daily_fee_budget: Option<u64>,
+    /// native tokens the batcher adds on top of the fees when the guaranteed
+    /// offer of a transaction spends more than it has
+    pub max_native_deficit: u64,
+    /// also cover what the fallible offer of a transaction is short of, with
+    /// the same limits as the guaranteed one. Fees are always paid from the
+    /// guaranteed offer
+    pub balance_fallible: bool,
+    /// custom tokens the batcher gives out to balance transactions
+    pub tokens: Vec<TokenPolicy>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenPolicy {
+    /// hex encoded token type
+    pub token_type: String,
+    /// most of the token given to a single transaction
+    pub max_per_transaction: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvingConfig {
+    /// size of the rayon pool used for proving, defaults to the number of cpus
+    pub threads: Option<usize>,
+}
+
+/// Overrides for the Rocket configuration, which is still read from
+/// Rocket.toml and the ROCKET_* environment variables.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: Option<IpAddr>,
+    pub port: Option<u16>,
+    /// origins allowed to make cross-origin requests, with credentials.
+    /// Defaults to any origin without credentials, or none when api keys are
+    /// required
+    pub allowed_origins: Option<Vec<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            indexer_ws: WS_INDEXER_LOCALHOST.to_string(),
+            indexer_http: HTTP_INDEXER_LOCALHOST.to_string(),
+            node: NODE_LOCALHOST.to_string(),
+            network: Network::Undeployed,
+            db: PathBuf::from("./db.sqlite"),
+            secret: PathBuf::from("./seed"),
+            contract: None,
+            achievements: None,
+            fees: FeePolicy::default(),
+            coin_selection: CoinSelectionConfig::default(),
+            consolidation: ConsolidationConfig::default(),
+            proving: ProvingConfig::default(),
+            server: ServerConfig::default(),
+            reset_state: false,
+        }
+    }
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        Self {
+            // a single input and a single output, scaled with their number
+            zswap_cost_estimation: 40000,
+            require_api_key: false,
+            player_requests_per_minute: None,
+            player_daily_fee_budget: None,
+            max_native_deficit: 0,
+            balance_fallible: false,
+            tokens: vec![],
+        }
+    }
+}
+
+impl Network {
+    pub fn network_id(&self) -> NetworkId {
+        match self {
+            Network::TestNet => NetworkId::TestNet,
+            Network::Undeployed => NetworkId::Undeployed,
+        }
+    }
+}
+
+impl FeePolicy {
+    pub fn player_limits(&self) -> PlayerLimits {
+        PlayerLimits {
+            requests_per_minute: self.player_requests_per_minute,
+            daily_fee_budget: self.player_daily_fee_budget,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(matches: &ArgMatches) -> anyhow::Result<Self> {
+        let mut config = if let Some(path) = matches.get_one::<PathBuf>("config") {
+            let raw = std::fs::read_to_string(path)
+                .context(format!("Failed to read config file {}", path.display()))?;
+
+            toml::from_str(&raw).context("Invalid config file")?
+        } else {
+            Config::default()
+        };
+
+        if let Some(indexer_ws) = matches.get_one::<String>("indexer-ws") {
+            config.indexer_ws = indexer_ws.clone();
+        }
+
+        if let Some(indexer_http) = matches.get_one::<String>("indexer-http") {
+            config.indexer_http = indexer_http.clone();
+        }
+
+        if let Some(node) = matches.get_one::<String>("node") {
+            config.node = node.clone();
+        }
+
+        if let Some(secret) = matches.get_one::<PathBuf>("secret") {
+            config.secret = secret.clone();
+        }
+
+        if let Some(network) = matches.get_one::<String>("network") {
+            config.network = match network.as_ref() {
+                "testnet" => Network::TestNet,
+                "undeployed" => Network::Undeployed,
+                _ => anyhow::bail!("invalid network"),
+            };
+        }
+
+        if let Some(db) = matches.get_one::<PathBuf>("db") {
+            config.db = db.clone();
+        }
+
+        if let Some(keys) = matches.get_one::<PathBuf>("allowed-contract") {
+            let previous = config.contract.take();
+
+            config.contract = Some(ContractProfile {
+                keys: keys.clone(),
+                schema: previous
+                    .as_ref()
+                    .and_then(|contract| contract.schema.clone()),
+                verify_state: previous.is_some_and(|contract| contract.verify_state),
+            });
+        }
+
+        if matches.get_flag("require-api-key") {
+            config.fees.require_api_key = true;
+        }
+
+        if let Some(limit) = matches.get_one::<u32>("player-requests-per-minute") {
+            config.fees.player_requests_per_minute = Some(*limit);
+        }
+
+        if let Some(budget) = matches.get_one::<u64>("player-daily-fee-budget") {
+            config.fees.player_daily_fee_budget = Some(*budget);
+        }
+
+        if let Some(threads) = matches.get_one::<usize>("proving-threads") {
+            config.proving.threads = Some(*threads);
+        }
+
+        config.reset_state = matches.get_flag("reset-state");
+
+        if let Some(port) = matches.get_one::<u16>("port") {
+            config.server.port = Some(*port);
+        }
+
+        if config.consolidation.enabled {
+            anyhow::ensure!(
+                config.coin_selection.dust_threshold > 0,
+                "consolidation needs a coin_selection dust_threshold above 0"
+            );
+            anyhow::ensure!(
+                config.consolidation.max_inputs >= 2,
+                "consolidation max_inputs must be at least 2"
+            );
+        }
+
+        Ok(config)
+    }
+
+    /// The config with credentials embedded in the urls removed, so that it
+    /// can be logged.
+    pub fn redacted(&self) -> Self {
+        fn redact_url(url: &str) -> String {
+            let Ok(mut url) = Url::parse(url) else {
+                return url.to_string();
+            };
+
+            if url.password().is_some() {
+                let _ = url.set_password(Some("REDACTED"));
+            }
+
+            if url.query().is_some() {
+                url.set_query(Some("REDACTED"));
+            }
+
+            url.to_string()
+        }
+
+        let mut config = self.clone();
+
+        config.indexer_ws = redact_url(&config.indexer_ws);
+        config.indexer_http = redact_url(&config.indexer_http);
+        config.node = redact_url(&config.node);
+
+        if let Some(db) = config.db.to_str().filter(|db| db.contains("://")) {
+            config.db = PathBuf::from(redact_url(db));
+        }
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str]) -> anyhow::Result<Config> {
+        let matches = crate::cli()
+            .try_get_matches_from(std::iter::once("batcher").chain(args.iter().copied()))?;
+
+        Config::load(&matches)
+    }
+
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "batcher-config-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn settings_are_taken_from_the_cli_then_the_env_then_the_file() {
+        let path = config_file(
+            "precedence",
+            r#"
+            indexer_ws = "ws://file:8088/ws"
+            indexer_http = "http://file:8088/graphql"
+            node = "ws://file:9944"
+
+            [server]
+            port = 9000
+            "#,
+        );
+
+        // the only test setting it, so that the others don't see it.
+        std::env::set_var("BATCHER_INDEXER_HTTP", "http://env:8088/graphql");
+
+        let config = load(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--node",
+            "ws://cli:9944",
+        ])
+        .unwrap();
+
+        std::env::remove_var("BATCHER_INDEXER_HTTP");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.node, "ws://cli:9944");
+        assert_eq!(config.indexer_http, "http://env:8088/graphql");
+        assert_eq!(config.indexer_ws, "ws://file:8088/ws");
+        assert_eq!(config.server.port, Some(9000));
+        assert_eq!(config.db, PathBuf::from("./db.sqlite"));
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        let path = config_file("unknown", "[server]\nhost = \"0.0.0.0\"\n");
+
+        let result = load(&["--config", path.to_str().unwrap()]);
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn consolidation_needs_a_dust_threshold() {
+        let path = config_file("consolidation", "[consolidation]\nenabled = true\n");
+
+        let result = load(&["--config", path.to_str().unwrap()]);
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn redacted_hides_the_url_credentials() {
+        let config = Config {
+            indexer_ws: "ws://user:secret@indexer:8088/ws".to_string(),
+            indexer_http: "http://indexer:8088/graphql?apikey=secret".to_string(),
+            node: "ws://127.0.0.1:9944".to_string(),
+            db: PathBuf::from("postgres://batcher:secret@db:5432/batcher"),
+            ..Default::default()
+        }
+        .redacted();
+
+        assert_eq!(config.indexer_ws, "ws://user:REDACTED@indexer:8088/ws");
+        assert_eq!(config.indexer_http, "http://indexer:8088/graphql?REDACTED");
+        assert_eq!(config.node, "ws://127.0.0.1:9944/");
+        assert_eq!(
+            config.db,
+            PathBuf::from("postgres://batcher:REDACTED@db:5432/batcher")
+        );
+
+        let config = Config::default().redacted();
+        assert_eq!(config.db, PathBuf::from("./db.sqlite"));
+    }
+}
@@ -0,0 +1,140 @@
+//! Background job merging the small native coins left by the fee payments and
+//! change outputs, so that requests don't need many spend proofs. It only runs
+//! while no transaction is being balanced, and at most once per interval.
+
+use crate::{
+    balancing::{consolidate_dust, ProvingParams},
+    config::FeePolicy,
+    preproofing::PreProvingServiceChannelTx,
+    utils::unix_now,
+    SyncStatus,
+};
+use midnight_zswap::{local::State, serialize::NetworkId};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use subxt::{OnlineClient, SubstrateConfig};
+use tokio::sync::{Mutex, RwLock};
+
+/// How often the job checks whether the batcher is idle.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsolidationConfig {
+    /// merges the native coins worth at most the coin selection
+    /// `dust_threshold`, which has to be above 0
+    pub enabled: bool,
+    /// fewest dust coins worth a consolidation transaction
+    pub min_coins: usize,
+    /// most coins merged by a single transaction
+    pub max_inputs: usize,
+    /// seconds without submitTx requests before consolidating
+    pub idle_seconds: u64,
+    /// least seconds between two consolidation transactions
+    pub interval_seconds: u64,
+}
+
+impl Default for ConsolidationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_coins: 4,
+            max_inputs: 8,
+            idle_seconds: 60,
+            interval_seconds: 3600,
+        }
+    }
+}
+
+/// Tracks the submitTx requests, to tell when the batcher is idle.
+#[derive(Default)]
+pub struct Activity {
+    in_flight: AtomicUsize,
+    last_request: AtomicU64,
+}
+
+/// Marks a request as in flight until dropped.
+pub struct RequestGuard(Arc<Activity>);
+
+impl Activity {
+    pub fn start_request(self: &Arc<Self>) -> RequestGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        self.last_request.store(unix_now(), Ordering::SeqCst);
+
+        RequestGuard(Arc::clone(self))
+    }
+
+    fn is_idle_for(&self, seconds: u64) -> bool {
+        self.in_flight.load(Ordering::SeqCst) == 0
+            && unix_now().saturating_sub(self.last_request.load(Ordering::SeqCst)) >= seconds
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.last_request.store(unix_now(), Ordering::SeqCst);
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn consolidation_service(
+    state: Arc<Mutex<State>>,
+    prover_params: Arc<ProvingParams>,
+    api: OnlineClient<SubstrateConfig>,
+    network_id: NetworkId,
+    inputs_service: PreProvingServiceChannelTx,
+    sync_status: Arc<RwLock<SyncStatus>>,
+    activity: Arc<Activity>,
+    fee_policy: FeePolicy,
+    dust_threshold: u64,
+    config: ConsolidationConfig,
+) {
+    let interval = Duration::from_secs(config.interval_seconds);
+
+    // wait a full interval after startup too, so that a restart loop can't
+    // submit more often.
+    let mut last_consolidation = tokio::time::Instant::now();
+
+    loop {
+        tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+
+        if last_consolidation.elapsed() < interval
+            || !activity.is_idle_for(config.idle_seconds)
+            || !matches!(*sync_status.read().await, SyncStatus::UpToDate)
+        {
+            continue;
+        }
+
+        last_consolidation = tokio::time::Instant::now();
+
+        match consolidate_dust(
+            Arc::clone(&prover_params),
+            &api,
+            Arc::clone(&state),
+            network_id,
+            inputs_service.clone(),
+            &fee_policy,
+            dust_threshold,
+            &config,
+        )
+        .await
+        {
+            Ok(Some((tx_hash, coins))) => {
+                tracing::info!(tx_hash, coins, "consolidated dust coins");
+            }
+            Ok(None) => {
+                tracing::debug!("not enough dust coins to consolidate");
+            }
+            Err(error) => {
+                tracing::error!(?error, "failed to consolidate dust coins");
+            }
+        }
+    }
+}
@@ -0,0 +1,632 @@
+//! Decoding of the states of the indexed contracts. Which ledger fields are
+//! kept, and how they are decoded, is described by a schema file loaded with
+//! the contract profile.
+
+use anyhow::Context as _;
+use midnight_ledger::onchain_runtime::state::StateValue;
+use midnight_zswap::base_crypto::fab::{AlignedValue, AlignmentAtom, AlignmentSegment};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+    #[serde(default)]
+    pub fields: Vec<FieldSchema>,
+    /// fields backing the lobby endpoints
+    pub lobby: Option<LobbyFields>,
+    /// fields identifying the player sending a transaction
+    pub players: Option<PlayerFields>,
+    #[serde(default)]
+    pub outcomes: Outcomes,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FieldSchema {
+    pub name: String,
+    /// indexes into the nested arrays of the state, negative ones count from
+    /// the end
+    pub path: Vec<i64>,
+    /// index into the cells of the first two levels of arrays instead, which
+    /// is how older versions read the pvp lobbies
+    #[serde(default)]
+    pub flatten: bool,
+    #[serde(flatten)]
+    pub kind: Kind,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Kind {
+    /// a `Bytes<N>` cell, as hex
+    Bytes,
+    /// a field element, as little endian hex
+    Field,
+    Bool,
+    /// a single byte cell, as the name in `variants` if given
+    Enum {
+        #[serde(default)]
+        variants: Vec<String>,
+    },
+    /// every atom of the cell as hex, joined with `;`
+    Cell,
+    Map {
+        key: Box<Kind>,
+        value: Box<Kind>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LobbyFields {
+    pub game_state: String,
+    pub p1_public_key: String,
+    pub p2_public_key: String,
+}
+
+/// The field holding the public key that a transaction is bound to, which is
+/// the identity the player limits apply to.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlayerFields {
+    /// the key of the player deploying the contract
+    pub deploy: Option<String>,
+    /// the key each circuit checks the caller against, by circuit name
+    #[serde(default)]
+    pub calls: HashMap<String, String>,
+}
+
+/// Values of the lobby game state once a match is over.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Outcomes {
+    pub p1_wins: Vec<String>,
+    pub p2_wins: Vec<String>,
+    pub draws: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchResult {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl Default for Outcomes {
+    /// The final states of the pvp contract.
+    fn default() -> Self {
+        Self {
+            p1_wins: vec!["07".to_string()],
+            p2_wins: vec!["08".to_string()],
+            draws: vec!["09".to_string()],
+        }
+    }
+}
+
+impl Outcomes {
+    /// The result for the first player, `None` if the match is not over.
+    pub fn p1_result(&self, game_state: &str) -> Option<MatchResult> {
+        let is = |states: &[String]| states.iter().any(|state| state == game_state);
+
+        if is(&self.p1_wins) {
+            Some(MatchResult::Win)
+        } else if is(&self.p2_wins) {
+            Some(MatchResult::Loss)
+        } else if is(&self.draws) {
+            Some(MatchResult::Draw)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_finished(&self, game_state: &str) -> bool {
+        self.p1_result(game_state).is_some()
+    }
+}
+
+impl MatchResult {
+    pub fn opposite(self) -> Self {
+        match self {
+            MatchResult::Win => MatchResult::Loss,
+            MatchResult::Loss => MatchResult::Win,
+            MatchResult::Draw => MatchResult::Draw,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchResult::Win => "win",
+            MatchResult::Loss => "loss",
+            MatchResult::Draw => "draw",
+        }
+    }
+
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "win" => Ok(MatchResult::Win),
+            "loss" => Ok(MatchResult::Loss),
+            "draw" => Ok(MatchResult::Draw),
+            _ => anyhow::bail!("unknown match result {}", s),
+        }
+    }
+}
+
+/// The public key of the second player from its lobby column, which is
+/// `00;` until someone joins and `01;<public key>` after.
+pub fn parse_p2_public_key(column: &str) -> Option<String> {
+    column
+        .split(";")
+        .nth(1)
+        .map(|s| s.to_string())
+        .filter(|s| !s.is_empty())
+}
+
+impl Default for Schema {
+    /// The layout of the pvp contract: the last three cells are the game
+    /// state and the public keys of the players.
+    fn default() -> Self {
+        let field = |name: &str, index| FieldSchema {
+            name: name.to_string(),
+            path: vec![index],
+            flatten: true,
+            kind: Kind::Cell,
+        };
+
+        Self {
+            fields: vec![
+                field("game_state", -3),
+                field("p1_public_key", -2),
+                field("p2_public_key", -1),
+            ],
+            lobby: Some(LobbyFields {
+                game_state: "game_state".to_string(),
+                p1_public_key: "p1_public_key".to_string(),
+                p2_public_key: "p2_public_key".to_string(),
+            }),
+            players: Some(PlayerFields {
+                deploy: Some("p1_public_key".to_string()),
+                calls: HashMap::new(),
+            }),
+            outcomes: Outcomes::default(),
+        }
+    }
+}
+
+impl Schema {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+
+        let raw = std::fs::read_to_string(path)
+            .context(format!("Failed to read contract schema {}", path.display()))?;
+
+        let schema: Schema = toml::from_str(&raw).context("Invalid contract schema")?;
+
+        if let Some(lobby) = &schema.lobby {
+            for name in [
+                &lobby.game_state,
+                &lobby.p1_public_key,
+                &lobby.p2_public_key,
+            ] {
+                if !schema.fields.iter().any(|field| &field.name == name) {
+                    anyhow::bail!("lobby field {} is not defined in the contract schema", name);
+                }
+            }
+        }
+
+        if let Some(players) = &schema.players {
+            for name in players.deploy.iter().chain(players.calls.values()) {
+                if !schema.fields.iter().any(|field| &field.name == name) {
+                    anyhow::bail!(
+                        "player field {} is not defined in the contract schema",
+                        name
+                    );
+                }
+            }
+        }
+
+        Ok(schema)
+    }
+
+    /// Decodes every field of the schema. Fields that don't match the state
+    /// are logged and left out.
+    pub fn decode(&self, state: &StateValue) -> Vec<(String, Value)> {
+        self.fields
+            .iter()
+            .filter_map(|field| match field.decode(state) {
+                Ok(value) => Some((field.name.clone(), value)),
+                Err(error) => {
+                    tracing::warn!(field = %field.name, ?error, "failed to decode contract field");
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// The public key held by the field, which is the last atom of a cell so
+    /// that optional keys like `01;<public key>` are read too. `None` if the
+    /// field is missing or empty.
+    pub fn player_key(&self, name: &str, state: &StateValue) -> Option<String> {
+        let field = self.fields.iter().find(|field| field.name == name)?;
+
+        let Value::String(value) = field.decode(state).ok()? else {
+            return None;
+        };
+
+        value
+            .rsplit(';')
+            .next()
+            .filter(|key| !key.is_empty())
+            .map(|key| key.to_string())
+    }
+
+    /// The game state and the public keys of the players, in the format
+    /// returned by the lobby endpoints.
+    pub fn lobby_columns(&self, values: &[(String, Value)]) -> Option<[String; 3]> {
+        let lobby = self.lobby.as_ref()?;
+
+        let column = |name: &String| {
+            values
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| match value {
+                    Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                })
+        };
+
+        Some([
+            column(&lobby.game_state)?,
+            column(&lobby.p1_public_key)?,
+            column(&lobby.p2_public_key)?,
+        ])
+    }
+}
+
+impl FieldSchema {
+    fn decode(&self, state: &StateValue) -> anyhow::Result<Value> {
+        if self.flatten {
+            let &[index] = &self.path[..] else {
+                anyhow::bail!("flattened fields take a single index");
+            };
+
+            let cells = flattened_cells(state)?;
+            let cell = pick(&cells, index)?;
+
+            return self.kind.decode(&StateValue::Cell(Arc::clone(cell)));
+        }
+
+        self.kind.decode(&select(state, &self.path)?)
+    }
+}
+
+impl Kind {
+    fn decode(&self, value: &StateValue) -> anyhow::Result<Value> {
+        match self {
+            Kind::Map { key, value: kind } => {
+                let StateValue::Map(map) = value else {
+                    anyhow::bail!("expected a map");
+                };
+
+                let mut res = serde_json::Map::new();
+
+                for (k, v) in map.iter() {
+                    let k = match key.decode_cell(&k)? {
+                        Value::String(s) => s,
+                        k => k.to_string(),
+                    };
+
+                    res.insert(k, kind.decode(&v)?);
+                }
+
+                Ok(Value::Object(res))
+            }
+            kind => match value {
+                StateValue::Cell(cell) => kind.decode_cell(cell),
+                _ => anyhow::bail!("expected a cell"),
+            },
+        }
+    }
+
+    fn decode_cell(&self, cell: &AlignedValue) -> anyhow::Result<Value> {
+        match self {
+            Kind::Bytes => {
+                let (value, AlignmentAtom::Bytes { length }) = single_atom(cell)? else {
+                    anyhow::bail!("expected a bytes alignment");
+                };
+
+                Ok(Value::String(padded_hex(value, *length as usize)))
+            }
+            Kind::Field => {
+                let (value, AlignmentAtom::Field) = single_atom(cell)? else {
+                    anyhow::bail!("expected a field alignment");
+                };
+
+                Ok(Value::String(hex::encode(value)))
+            }
+            Kind::Bool => match single_byte(cell)? {
+                0 => Ok(Value::Bool(false)),
+                1 => Ok(Value::Bool(true)),
+                b => anyhow::bail!("invalid boolean {}", b),
+            },
+            Kind::Enum { variants } => {
+                let b = single_byte(cell)?;
+
+                if variants.is_empty() {
+                    return Ok(Value::from(b));
+                }
+
+                variants
+                    .get(b as usize)
+                    .map(|variant| Value::String(variant.clone()))
+                    .ok_or_else(|| anyhow::anyhow!("unknown enum variant {}", b))
+            }
+            Kind::Cell => Ok(Value::String(render_cell(cell)?)),
+            Kind::Map { .. } => anyhow::bail!("maps can't be stored in a cell"),
+        }
+    }
+}
+
+fn pick<T>(entries: &[T], index: i64) -> anyhow::Result<&T> {
+    let resolved = if index < 0 {
+        entries.len().checked_sub(index.unsigned_abs() as usize)
+    } else {
+        Some(index as usize)
+    };
+
+    resolved
+        .and_then(|i| entries.get(i))
+        .ok_or_else(|| anyhow::anyhow!("index {} out of bounds ({})", index, entries.len()))
+}
+
+fn select(state: &StateValue, path: &[i64]) -> anyhow::Result<StateValue> {
+    let Some((index, rest)) = path.split_first() else {
+        return Ok(state.clone());
+    };
+
+    let StateValue::Array(arr) = state else {
+        anyhow::bail!("expected an array at index {}", index);
+    };
+
+    let entries = arr.iter().collect::<Vec<_>>();
+
+    select(pick(&entries, *index)?, rest)
+}
+
+/// The cells in the top level array of the state, and in the arrays nested
+/// in it.
+fn flattened_cells(state: &StateValue) -> anyhow::Result<Vec<Arc<AlignedValue>>> {
+    let StateValue::Array(arr) = state else {
+        anyhow::bail!("expected the contract state to be an array");
+    };
+
+    let mut flattened_entries = vec![];
+
+    for entry in arr.iter() {
+        match &*entry {
+            StateValue::Array(arr) => {
+                for entry in arr.iter() {
+                    if let StateValue::Cell(cell) = &*entry {
+                        flattened_entries.push(Arc::clone(cell));
+                    }
+                }
+            }
+            StateValue::Cell(cell) => {
+                flattened_entries.push(Arc::clone(cell));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(flattened_entries)
+}
+
+fn single_atom(cell: &AlignedValue) -> anyhow::Result<(&[u8], &AlignmentAtom)> {
+    match (&cell.value.0[..], &cell.alignment.0[..]) {
+        ([value], [AlignmentSegment::Atom(atom)]) => Ok((value.0.as_slice(), atom)),
+        _ => anyhow::bail!("expected a single atom"),
+    }
+}
+
+fn single_byte(cell: &AlignedValue) -> anyhow::Result<u8> {
+    let (value, AlignmentAtom::Bytes { length: 1 }) = single_atom(cell)? else {
+        anyhow::bail!("expected a single byte alignment");
+    };
+
+    // values are stored without the trailing zeroes.
+    Ok(value.first().copied().unwrap_or(0))
+}
+
+fn padded_hex(value: &[u8], length: usize) -> String {
+    let mut s = hex::encode(value);
+
+    if let Some(missing_zeroes) = length.checked_sub(value.len()) {
+        s.extend(std::iter::repeat('0').take(missing_zeroes * 2));
+    }
+
+    s
+}
+
+/// Every atom of the cell as hex, joined with `;`.
+fn render_cell(cell: &AlignedValue) -> anyhow::Result<String> {
+    let atoms = cell
+        .value
+        .0
+        .iter()
+        .zip(cell.alignment.0.iter())
+        .map(|(value, alignment)| match &alignment {
+            AlignmentSegment::Atom(atom) => match &atom {
+                AlignmentAtom::Compress => Ok("".to_string()),
+                AlignmentAtom::Bytes { length } => Ok(padded_hex(&value.0, *length as usize)),
+                AlignmentAtom::Field => Ok(hex::encode(&value.0)),
+            },
+            _ => anyhow::bail!("option alignments are not supported"),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(atoms.join(";"))
+}
+
+/// The whole state as JSON: arrays as arrays, maps as lists of key/value
+/// pairs and cells as the list of their atoms. Values that can't be rendered
+/// are tagged as `{"unsupported": <type>}`.
+pub fn to_json(value: &StateValue) -> Value {
+    match value {
+        StateValue::Null => Value::Null,
+        StateValue::Cell(cell) => json!({ "cell": cell_to_json(cell) }),
+        StateValue::Array(arr) => Value::Array(arr.iter().map(|entry| to_json(&entry)).collect()),
+        StateValue::Map(map) => Value::Array(
+            map.iter()
+                .map(|(k, v)| json!({ "key": cell_to_json(&k), "value": to_json(&v) }))
+                .collect(),
+        ),
+        // merkle trees are the only other values, and the contract state only
+        // keeps what is needed for their root, so there are no entries to show.
+        _ => json!({ "unsupported": "bounded_merkle_tree" }),
+    }
+}
+
+fn cell_to_json(cell: &AlignedValue) -> Value {
+    let mut atoms = vec![];
+    let mut values = cell.value.0.iter();
+
+    for alignment in cell.alignment.0.iter() {
+        let AlignmentSegment::Atom(atom) = alignment else {
+            // the number of atoms of an option depends on the variant, so the
+            // rest can't be matched with the alignment.
+            break;
+        };
+
+        let Some(value) = values.next() else {
+            break;
+        };
+
+        atoms.push(match atom {
+            AlignmentAtom::Compress => json!({ "compress": hex::encode(&value.0) }),
+            AlignmentAtom::Bytes { length } => {
+                json!({ "bytes": padded_hex(&value.0, *length as usize) })
+            }
+            AlignmentAtom::Field => json!({ "field": hex::encode(&value.0) }),
+        });
+    }
+
+    atoms.extend(values.map(|value| json!({ "raw": hex::encode(&value.0) })));
+
+    Value::Array(atoms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midnight_zswap::base_crypto::fab::{Alignment, Value as FabValue, ValueAtom};
+
+    /// A cell of `Bytes` atoms, given as their value and length.
+    fn cell(atoms: &[(&[u8], u32)]) -> StateValue {
+        StateValue::Cell(Arc::new(AlignedValue {
+            value: FabValue(
+                atoms
+                    .iter()
+                    .map(|(value, _)| ValueAtom(value.to_vec()))
+                    .collect(),
+            ),
+            alignment: Alignment(
+                atoms
+                    .iter()
+                    .map(|(_, length)| {
+                        AlignmentSegment::Atom(AlignmentAtom::Bytes { length: *length })
+                    })
+                    .collect(),
+            ),
+        }))
+    }
+
+    /// A pvp lobby with a joined second player, after a nested array.
+    fn lobby_state() -> StateValue {
+        StateValue::Array(
+            vec![
+                StateValue::Array(vec![cell(&[(&[1], 1)])].into()),
+                cell(&[(&[1], 1)]),
+                cell(&[(&[0xaa; 32], 32)]),
+                cell(&[(&[1], 1), (&[0xbb; 32], 32)]),
+            ]
+            .into(),
+        )
+    }
+
+    #[test]
+    fn default_schema_decodes_the_pvp_lobby() {
+        let schema = Schema::default();
+        let values = schema.decode(&lobby_state());
+
+        let [game_state, p1_public_key, p2_public_key] = schema.lobby_columns(&values).unwrap();
+
+        assert_eq!(game_state, "01");
+        assert_eq!(p1_public_key, "aa".repeat(32));
+        assert_eq!(p2_public_key, format!("01;{}", "bb".repeat(32)));
+
+        assert_eq!(parse_p2_public_key(&p2_public_key), Some("bb".repeat(32)));
+        assert_eq!(
+            schema.player_key("p2_public_key", &lobby_state()),
+            Some("bb".repeat(32))
+        );
+    }
+
+    #[test]
+    fn fields_are_decoded_by_path_and_kind() {
+        let schema: Schema = toml::from_str(
+            r#"
+            [[fields]]
+            name = "phase"
+            path = [0, 0]
+            kind = "enum"
+            variants = ["waiting", "playing"]
+
+            [[fields]]
+            name = "started"
+            path = [1]
+            kind = "bool"
+
+            [[fields]]
+            name = "p1"
+            path = [-2]
+            kind = "bytes"
+
+            [[fields]]
+            name = "missing"
+            path = [7]
+            kind = "bytes"
+            "#,
+        )
+        .unwrap();
+
+        let values = schema.decode(&lobby_state());
+
+        // fields that don't match the state are left out
+        assert_eq!(
+            values,
+            vec![
+                ("phase".to_string(), json!("playing")),
+                ("started".to_string(), json!(true)),
+                ("p1".to_string(), json!("aa".repeat(32))),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_second_player_has_no_key() {
+        assert_eq!(parse_p2_public_key("00;"), None);
+    }
+
+    #[test]
+    fn outcomes_give_the_result_of_each_player() {
+        let outcomes = Outcomes::default();
+
+        assert_eq!(outcomes.p1_result("07"), Some(MatchResult::Win));
+        assert_eq!(
+            outcomes.p1_result("08").map(MatchResult::opposite),
+            Some(MatchResult::Win)
+        );
+        assert_eq!(outcomes.p1_result("09"), Some(MatchResult::Draw));
+        assert!(!outcomes.is_finished("01"));
+    }
+}
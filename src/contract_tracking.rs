@@ -0,0 +1,137 @@
+//! Local copies of the states of the whitelisted contracts, updated by
+//! running the transcripts of each call, so that the indexer doesn't have to
+//! be queried after every transaction.
+
+use crate::addresses::{AddressFormat, ContractAddress};
+use anyhow::Context as _;
+use midnight_ledger::{
+    onchain_runtime::{
+        context::QueryContext, cost_model::INITIAL_COST_MODEL, state::ContractState,
+    },
+    structure::{ContractAction, Transaction},
+};
+use midnight_transient_crypto::proofs::Proof;
+use midnight_zswap::serialize::{deserialize, serialize, NetworkId};
+use serde_json::json;
+use url::Url;
+
+/// The state of the contract at `contract_address` (stored form) after `tx`.
+/// The calls to it run in order, the guaranteed transcripts first and then,
+/// if `fallible_applied` tells that the fallible part of the transaction
+/// succeeded, the fallible ones.
+pub fn apply_transaction(
+    previous: Option<&ContractState>,
+    tx: &Transaction<Proof>,
+    contract_address: &str,
+    network_id: NetworkId,
+    fallible_applied: bool,
+) -> anyhow::Result<ContractState> {
+    let Transaction::Standard(stx) = tx else {
+        anyhow::bail!("not a contract transaction");
+    };
+
+    let Some(contract_calls) = &stx.contract_calls else {
+        anyhow::bail!("not a contract transaction");
+    };
+
+    let mut state = previous.cloned();
+    let mut calls = vec![];
+
+    for action in contract_calls.calls.iter() {
+        let mut buf = vec![];
+
+        if let ContractAction::Deploy(deploy) = action {
+            serialize(&deploy.address(), &mut buf, network_id)?;
+
+            if ContractAddress::from_serialized(&buf, network_id)?.to_stored() == contract_address {
+                state = Some(deploy.initial_state.clone());
+            }
+
+            continue;
+        }
+
+        let ContractAction::Call(call) = action else {
+            anyhow::bail!("unsupported contract action");
+        };
+
+        serialize(&call.address, &mut buf, network_id)?;
+
+        if ContractAddress::from_serialized(&buf, network_id)?.to_stored() == contract_address {
+            calls.push(call);
+        }
+    }
+
+    let mut state = state.ok_or_else(|| anyhow::anyhow!("no local state for the contract"))?;
+
+    let guaranteed = calls
+        .iter()
+        .filter_map(|call| Some((call, call.guaranteed_transcript.as_ref()?)));
+    let fallible = calls
+        .iter()
+        .filter(|_| fallible_applied)
+        .filter_map(|call| Some((call, call.fallible_transcript.as_ref()?)));
+
+    for (call, transcript) in guaranteed.chain(fallible) {
+        let results = QueryContext::new(state.data, call.address)
+            .query(&transcript.program, None, &INITIAL_COST_MODEL)
+            .map_err(|e| anyhow::anyhow!("Failed to run the call transcript: {:?}", e))?;
+
+        state.data = results.context.state;
+    }
+
+    Ok(state)
+}
+
+/// The state of the contract at `contract_address` (stored form) after the
+/// transaction, as seen by the indexer.
+pub async fn fetch_from_indexer(
+    indexer_http_url: &Url,
+    contract_address: &str,
+    tx_hash: &str,
+    network_id: NetworkId,
+) -> anyhow::Result<ContractState> {
+    // the indexer only knows the serialized addresses.
+    let contract_address = ContractAddress::from_stored(contract_address, network_id)?
+        .encode(AddressFormat::Prefixed)?;
+
+    let res: serde_json::Value = reqwest::Client::new()
+        .post(indexer_http_url.to_string())
+        .json(&json!({
+            "query": format!(r#"{{
+                contract(address: "{}", transactionOffset: {{ hash: "{}" }} ) {{
+                    state
+                }}
+            }}"#, contract_address, tx_hash),
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    let state_raw = res
+        .get("data")
+        .and_then(|data| data.get("contract"))
+        .and_then(|contract| contract.get("state"))
+        .and_then(|state| state.as_str())
+        .ok_or(anyhow::anyhow!(
+            "Unexpected format for contract state query {}",
+            res.to_string()
+        ))?;
+
+    let state_raw = hex::decode(state_raw).context(anyhow::anyhow!(
+        "Expected hex string for the contract statestate"
+    ))?;
+
+    Ok(deserialize(std::io::Cursor::new(state_raw), network_id)?)
+}
+
+pub fn encode(state: &ContractState, network_id: NetworkId) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![];
+    serialize(state, &mut buf, network_id)?;
+
+    Ok(buf)
+}
+
+pub fn decode(raw: Vec<u8>, network_id: NetworkId) -> anyhow::Result<ContractState> {
+    deserialize(std::io::Cursor::new(raw), network_id).context("Can't deserialize contract state")
+}
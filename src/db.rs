@@ -1,282 +1,503 @@
 use anyhow::Context as _;
-use deadpool_sqlite::{Config, Pool, Runtime};
+use chacha20poly1305::{
+    aead::{Aead as _, KeyInit as _},
+    ChaCha20Poly1305, Key, Nonce,
+};
 use midnight_zswap::{
     local::State,
     serialize::{deserialize, serialize, NetworkId},
 };
-use rusqlite::OptionalExtension as _;
-use std::path::Path;
+use rand::{rngs::OsRng, Rng as _};
+use std::{ops::Deref, path::Path, sync::Arc};
+
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sqlite;
+#[cfg(test)]
+mod tests;
+
+/// Prefix of the encrypted state blobs, followed by the nonce and the
+/// ciphertext. Blobs without it are plaintext states from older versions.
+const ENCRYPTED_STATE_MAGIC: &[u8] = b"BENC1";
+
+#[derive(Debug)]
+pub struct ApiKey {
+    pub name: String,
+    pub budget: Option<u64>,
+    pub spent: u64,
+    pub requests_per_minute: Option<u32>,
+}
 
-#[derive(Clone)]
-pub struct Db {
-    pool: Pool,
-    network_id: NetworkId,
+pub enum ApiKeyRequest {
+    Unknown,
+    RateLimited { retry_after: u64 },
+    Allowed,
 }
 
-impl Db {
-    pub async fn open_db(path: impl AsRef<Path>, network_id: NetworkId) -> anyhow::Result<Self> {
-        let cfg = Config::new(path.as_ref());
-        let pool = cfg
-            .create_pool(Runtime::Tokio1)
-            .context("Failed to initialize sqlite pool")?;
+pub enum PlayerFeeReservation {
+    Reserved(i64),
+    Exceeded { retry_after: u64 },
+}
 
-        let res = Self { pool, network_id };
+/// The fields decoded by the contract schema after a transaction.
+pub struct ContractStateChange {
+    pub id: i64,
+    pub tx_hash: String,
+    pub block_number: u64,
+    pub fields: serde_json::Value,
+}
 
-        res.create_tables().await?;
+pub struct Lobby {
+    pub address: String,
+    pub game_state: String,
+    pub block_number: u64,
+    /// block of the deploy
+    pub created_block: u64,
+    pub p1_public_key: String,
+    pub p2_public_key: String,
+}
 
-        Ok(res)
-    }
+/// Lobbies are sorted by creation block, newest first, then by address. The
+/// cursor is the last lobby of the previous page.
+pub struct LobbyCursor {
+    pub created_block: u64,
+    pub address: String,
+}
 
-    pub async fn persist_state(&self, id: &str, hash: &str, state: &State) -> anyhow::Result<()> {
-        let mut buf = vec![];
-        serialize(&state, &mut buf, self.network_id)?;
+/// Optional constraints on the lobbies, all bounds are inclusive.
+#[derive(Debug, Default)]
+pub struct LobbyFilter {
+    pub game_state: Option<String>,
+    /// bounds of the block of the last update
+    pub from_block: Option<u64>,
+    pub to_block: Option<u64>,
+    /// bounds of the block of the deploy
+    pub created_from_block: Option<u64>,
+    pub created_to_block: Option<u64>,
+}
 
-        let conn = self.pool.get().await.unwrap();
+#[derive(Debug, Default)]
+pub struct PlayerStats {
+    pub player: String,
+    pub games_played: u64,
+    pub wins: u64,
+    pub losses: u64,
+    pub draws: u64,
+    pub last_played_block: Option<u64>,
+}
 
-        let id = id.to_string();
-        let hash = hash.to_string();
+/// Storage operations implemented by each database engine. The wallet state
+/// is handled as an opaque blob here, serialization and encryption are done by
+/// [`Db`].
+#[async_trait::async_trait]
+pub trait Backend: Send + Sync {
+    async fn put_state_blob(&self, id: &str, hash: &str, blob: Vec<u8>) -> anyhow::Result<()>;
 
-        conn.interact(move |conn| {
-            conn.execute(
-                "INSERT OR REPLACE INTO state (id, hash, state) VALUES (?1, ?2, ?3)",
-                (&id, &hash, &buf),
-            )
-        })
-        .await
-        .unwrap()
-        .context("Db error persisting state")?;
+    async fn get_state_blob(&self, id: &str) -> anyhow::Result<Option<(String, Vec<u8>)>>;
 
-        Ok(())
-    }
+    async fn get_state_blobs(&self) -> anyhow::Result<Vec<(String, Vec<u8>)>>;
 
-    pub async fn get_state(&self, id: &str) -> anyhow::Result<Option<(String, State)>> {
-        let conn = self.pool.get().await.unwrap();
+    async fn update_state_blob(&self, id: &str, blob: Vec<u8>) -> anyhow::Result<()>;
 
-        let id = id.to_string();
+    /// Removes the stored wallet state, so that it gets rebuilt from the seed
+    /// and synced again from genesis.
+    async fn delete_state(&self, id: &str) -> anyhow::Result<()>;
 
-        let row = conn
-            .interact(move |conn| -> anyhow::Result<Option<(String, Vec<u8>)>> {
-                let mut stmt = conn.prepare(
-                    "SELECT hash, state FROM state WHERE id = ?1 ORDER BY rowid DESC LIMIT 1",
-                )?;
+    /// The public keys (in the format of the batcher address) of the wallet the
+    /// state with this id belongs to.
+    async fn get_state_public_keys(&self, id: &str) -> anyhow::Result<Option<String>>;
 
-                let row = stmt
-                    .query_row([id], |row| {
-                        Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
-                    })
-                    .optional()
-                    .context("Database access error")?;
+    async fn set_state_public_keys(&self, id: &str, public_keys: &str) -> anyhow::Result<()>;
 
-                Ok(row)
-            })
-            .await
-            .unwrap()?;
+    async fn count_contracts(&self) -> anyhow::Result<u64>;
 
-        if let Some((hash, unserialized_state)) = row {
-            let state = deserialize(std::io::Cursor::new(unserialized_state), self.network_id)
-                .context("Can't deserialize state object")?;
+    /// Forgets the indexed contracts, so that they get inserted again when the
+    /// transactions are replayed.
+    async fn clear_contracts(&self) -> anyhow::Result<()>;
 
-            Ok(Some((hash, state)))
-        } else {
-            Ok(None)
-        }
-    }
+    async fn check_address(&self, address: &str) -> anyhow::Result<bool>;
 
-    pub async fn check_address(&self, address: impl AsRef<str>) -> anyhow::Result<bool> {
-        let conn = self.pool.get().await.unwrap();
+    async fn insert_contract_address(&self, id: &str, block_number: u64) -> anyhow::Result<()>;
 
-        let address = address.as_ref().to_string();
+    async fn update_contract_state(
+        &self,
+        contract_address: &str,
+        game_state: &str,
+        p1_public_key: &str,
+        p2_public_key: &str,
+        block_number: u64,
+    ) -> anyhow::Result<()>;
 
-        conn.interact(move |conn| {
-            let mut stmt = conn.prepare("SELECT 1 FROM contract_address WHERE id = ?1")?;
+    /// Stores the fields decoded by the contract schema, replacing the
+    /// previous values of the same fields.
+    async fn put_contract_state_values(
+        &self,
+        contract_address: &str,
+        values: Vec<(String, serde_json::Value)>,
+        block_number: u64,
+    ) -> anyhow::Result<()>;
 
-            let mut rows = stmt.query([address]).context("Database access error")?;
+    /// Replaces the last known state of the contract, as rendered by
+    /// [`crate::contract_state::to_json`].
+    async fn set_contract_state_json(
+        &self,
+        contract_address: &str,
+        state: serde_json::Value,
+        block_number: u64,
+    ) -> anyhow::Result<()>;
 
-            Ok(rows.next()?.is_some())
-        })
-        .await
-        .unwrap()
-    }
+    /// The last known state of the contract and the block it was read at.
+    async fn get_contract_state_json(
+        &self,
+        contract_address: &str,
+    ) -> anyhow::Result<Option<(serde_json::Value, u64)>>;
 
-    pub async fn insert_contract_address(&self, id: &str) -> anyhow::Result<()> {
-        let conn = self.pool.get().await.unwrap();
+    /// The serialized `ContractState` kept up to date by the indexer.
+    async fn get_contract_ledger_state(
+        &self,
+        contract_address: &str,
+    ) -> anyhow::Result<Option<Vec<u8>>>;
 
-        let id = id.to_string();
+    /// Whether the ledger state of the contract already includes `tx_hash`,
+    /// in which case replaying the transaction would apply it twice.
+    async fn is_contract_transaction_applied(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+    ) -> anyhow::Result<bool>;
 
-        conn.interact(|conn| {
-            conn.execute(
-                "INSERT OR REPLACE INTO contract_address (id) VALUES (?1)",
-                [id],
-            )
-        })
-        .await
-        .unwrap()?;
+    /// Stores the ledger state of the contract after `tx_hash`, and marks the
+    /// transaction as applied, atomically. Returns `false` without changing
+    /// the state if it was already applied.
+    async fn set_contract_ledger_state(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+        state: Vec<u8>,
+    ) -> anyhow::Result<bool>;
 
-        Ok(())
-    }
+    /// Appends the change made by `tx_hash`, unless it's already recorded
+    /// because the transaction is being replayed.
+    async fn insert_contract_state_change(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+        block_number: u64,
+        fields: serde_json::Value,
+    ) -> anyhow::Result<()>;
 
-    pub async fn update_contract_state(
+    /// The changes of the contract in the order they happened, starting after
+    /// the one with id `after`.
+    async fn get_contract_state_history(
+        &self,
+        contract_address: &str,
+        after: Option<i64>,
+        count: Option<u8>,
+    ) -> anyhow::Result<Vec<ContractStateChange>>;
+
+    /// Stores the lobby event of `kind` caused by `tx_hash`, returning its id,
+    /// or `None` if it's already stored because the transaction is being
+    /// replayed.
+    async fn insert_lobby_event(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+        kind: &str,
+        block_number: u64,
+        columns: [String; 3],
+    ) -> anyhow::Result<Option<i64>>;
+
+    /// Lobby events from `from_block` on with an id greater than `after`,
+    /// oldest first, as (id, kind, contract address, block number, [game
+    /// state, p1, p2]).
+    async fn get_lobby_events(
+        &self,
+        from_block: u64,
+        player: Option<String>,
+        address: Option<String>,
+        after: Option<i64>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<(i64, String, String, u64, [String; 3])>>;
+
+    /// A page of the lobbies matching the filter, and the number of matching
+    /// lobbies across all the pages.
+    async fn get_lobbies_waiting_for_p2(
+        &self,
+        filter: LobbyFilter,
+        exclude_player: Option<String>,
+        after: Option<LobbyCursor>,
+        count: u32,
+    ) -> anyhow::Result<(Vec<Lobby>, u64)>;
+
+    /// Same as [`Backend::get_lobbies_waiting_for_p2`], for the lobbies the
+    /// player is part of.
+    async fn get_player_lobbies(
+        &self,
+        public_key: String,
+        filter: LobbyFilter,
+        after: Option<LobbyCursor>,
+        count: u32,
+    ) -> anyhow::Result<(Vec<Lobby>, u64)>;
+
+    /// The cursor pointing at the lobby of `address`, for the clients paging
+    /// by the address of the last lobby they got.
+    async fn get_lobby_cursor(&self, address: &str) -> anyhow::Result<Option<LobbyCursor>>;
+
+    /// Records the result of a finished match for both players. Returns
+    /// `false` if it was already recorded.
+    async fn record_match(
         &self,
         contract_address: &str,
-        game_state: &str,
         p1_public_key: &str,
         p2_public_key: &str,
+        p1_result: &str,
         block_number: u64,
-    ) -> anyhow::Result<()> {
-        let conn = self.pool.get().await.unwrap();
-
-        let contract_address = contract_address.to_string();
-        let game_state = game_state.to_string();
-        let p1_public_key = p1_public_key.to_string();
-        let p2_public_key = p2_public_key.to_string();
-
-        conn.interact(move |conn| {
-            conn.execute(
-                "UPDATE contract_address SET game_state = ?1, p1_public_key = ?2, p2_public_key = ?3, block_number = ?5 WHERE id = ?4 ",
-                (game_state, p1_public_key, p2_public_key, contract_address, block_number),
-            )
-        })
-        .await
-        .unwrap()?;
+    ) -> anyhow::Result<bool>;
 
-        Ok(())
-    }
+    /// (opponent, result) of the matches of the player, oldest first.
+    async fn get_player_matches(&self, player: &str) -> anyhow::Result<Vec<(String, String)>>;
 
-    pub async fn get_lobbies_waiting_for_p2(
+    async fn complete_achievement(
         &self,
-        after: Option<String>,
-        count: Option<u8>,
-        exclude_player: Option<String>,
-    ) -> anyhow::Result<Vec<(String, u64, String)>> {
-        let conn = self.pool.get().await.unwrap();
-
-        conn.interact(move |conn| {
-            let mut stmt = conn.prepare(
-                "
-                SELECT id, block_number, p1_public_key FROM contract_address
-                WHERE p2_public_key = '00;' AND
-                    (?1 IS NULL OR rowid < (SELECT max(rowid) FROM contract_address WHERE id = ?1)) AND
-                    (?3 IS NULL OR p1_public_key <> ?3)
-                ORDER BY rowid DESC
-                LIMIT ?2",
-            )?;
-
-            let rows = stmt
-                .query_map((after, count.unwrap_or(10).to_string(), exclude_player), |row| {
-                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-                })
-                .context("Database error")?;
-
-            let mut res = vec![];
-            for row in rows {
-                res.push(row?);
+        player: &str,
+        name: &str,
+        block_number: u64,
+    ) -> anyhow::Result<()>;
+
+    /// (name, block number) of the achievements completed by the player.
+    async fn get_completed_achievements(&self, player: &str) -> anyhow::Result<Vec<(String, u64)>>;
+
+    /// Aggregated results of the player, over the matches finished between
+    /// the given blocks (inclusive).
+    async fn get_player_stats(
+        &self,
+        player: &str,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+    ) -> anyhow::Result<PlayerStats>;
+
+    /// Players sorted by wins, then draws, then fewest losses.
+    async fn get_leaderboard(
+        &self,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+        offset: u64,
+        count: u8,
+    ) -> anyhow::Result<Vec<PlayerStats>>;
+
+    /// The block of the last contract update seen by the indexer.
+    async fn last_contract_block(&self) -> anyhow::Result<Option<u64>>;
+
+    async fn insert_api_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        budget: Option<u64>,
+        requests_per_minute: Option<u32>,
+    ) -> anyhow::Result<()>;
+
+    async fn revoke_api_key(&self, name: &str) -> anyhow::Result<bool>;
+
+    async fn get_api_keys(&self) -> anyhow::Result<Vec<ApiKey>>;
+
+    /// Counts a request against the key's rate limit, which uses fixed windows
+    /// of 60 seconds.
+    async fn consume_api_key_request(
+        &self,
+        key_hash: &str,
+        now: u64,
+    ) -> anyhow::Result<ApiKeyRequest>;
+
+    /// Adds `amount` to the spent counter of the key, unless that would go over
+    /// its budget. Returns `false` if the budget can't cover the amount.
+    async fn reserve_api_key_budget(&self, key_hash: &str, amount: u64) -> anyhow::Result<bool>;
+
+    async fn release_api_key_budget(&self, key_hash: &str, amount: u64) -> anyhow::Result<()>;
+
+    /// Counts a request for the player in a rolling window of 60 seconds.
+    /// Returns the seconds until the next request is allowed if the limit is
+    /// already reached.
+    async fn consume_player_request(
+        &self,
+        player: &str,
+        now: u64,
+        requests_per_minute: u32,
+    ) -> anyhow::Result<Option<u64>>;
+
+    /// Records `amount` as spent by the player, unless it doesn't fit in the
+    /// budget for the last 24 hours.
+    async fn reserve_player_fee(
+        &self,
+        player: &str,
+        amount: u64,
+        daily_budget: u64,
+        now: u64,
+    ) -> anyhow::Result<PlayerFeeReservation>;
+
+    async fn release_player_fee(&self, id: i64) -> anyhow::Result<()>;
+
+    /// Deletes the requests and fees of every player that are out of their
+    /// window, including those of players that never come back.
+    async fn prune_player_usage(&self, now: u64) -> anyhow::Result<()>;
+
+    /// Brings the schema up to date, applying the pending migrations in a
+    /// single transaction.
+    async fn migrate(&self) -> anyhow::Result<()>;
+}
+
+#[cfg(feature = "postgres")]
+fn open_postgres(url: &str) -> anyhow::Result<Arc<dyn Backend>> {
+    Ok(Arc::new(postgres::PostgresDb::open(url)?))
+}
+
+#[cfg(not(feature = "postgres"))]
+fn open_postgres(_url: &str) -> anyhow::Result<Arc<dyn Backend>> {
+    anyhow::bail!("postgres support is not enabled, rebuild with `--features postgres`")
+}
+
+/// Handle to the database. The backend operations are available through
+/// `Deref`.
+#[derive(Clone)]
+pub struct Db {
+    backend: Arc<dyn Backend>,
+    network_id: NetworkId,
+    state_cipher: Option<Arc<ChaCha20Poly1305>>,
+}
+
+impl Deref for Db {
+    type Target = dyn Backend;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.backend
+    }
+}
+
+impl Db {
+    /// Opens the sqlite database at `path`, or connects to postgres if it's a
+    /// `postgres://` url.
+    pub async fn open_db(path: impl AsRef<Path>, network_id: NetworkId) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+
+        let backend: Arc<dyn Backend> = match path.to_str() {
+            Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+                open_postgres(url)?
             }
+            _ => Arc::new(sqlite::SqliteDb::open(path)?),
+        };
+
+        let res = Self {
+            backend,
+            network_id,
+            state_cipher: None,
+        };
 
-            Ok(res)
-        })
-        .await
-        .unwrap()
+        res.migrate().await?;
+
+        Ok(res)
     }
 
-    pub async fn get_player_lobbies(
-        &self,
-        public_key: String,
-        limit: Option<u8>,
-        after: Option<String>,
-    ) -> anyhow::Result<Vec<(String, String, u64, String, String)>> {
-        let conn = self.pool.get().await.unwrap();
-
-        let public_key = public_key.to_string();
-
-        conn.interact(move |conn| {
-            let mut stmt = conn.prepare(
-                "SELECT id, game_state, block_number, p1_public_key, p2_public_key FROM contract_address
-                WHERE
-                    (p1_public_key = ?1 OR p2_public_key = ('01;' || ?1) ) AND
-                    (?3 IS NULL OR (rowid < (SELECT max(rowid) FROM contract_address WHERE id = ?3)))
-                ORDER BY rowid DESC
-                LIMIT ?2",
-            )?;
-
-            let rows = stmt
-                .query_map((public_key, limit.unwrap_or(10), after), |row| {
-                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
-                })
-                .context("Database error")?;
-
-            let mut res = vec![];
-            for row in rows {
-                res.push(row?);
+    /// Encrypts the wallet state (which contains the secret keys) with a key
+    /// derived from the wallet seed.
+    pub fn with_state_encryption(mut self, seed: &[u8; 32]) -> Self {
+        let mut preimage = b"midnight-batcher state encryption".to_vec();
+        preimage.extend_from_slice(seed);
+
+        let key = hex::decode(sha256::digest(&preimage)).expect("sha256 digest is hex");
+
+        self.state_cipher = Some(Arc::new(ChaCha20Poly1305::new(Key::from_slice(&key))));
+
+        self
+    }
+
+    fn encrypt_state(&self, plaintext: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let Some(cipher) = &self.state_cipher else {
+            return Ok(plaintext);
+        };
+
+        let nonce: [u8; 12] = OsRng.gen();
+
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt state"))?;
+
+        let mut buf = ENCRYPTED_STATE_MAGIC.to_vec();
+        buf.extend_from_slice(&nonce);
+        buf.extend(ciphertext);
+
+        Ok(buf)
+    }
+
+    fn decrypt_state(&self, blob: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let Some(encrypted) = blob.strip_prefix(ENCRYPTED_STATE_MAGIC) else {
+            return Ok(blob);
+        };
+
+        let Some(cipher) = &self.state_cipher else {
+            anyhow::bail!("the stored state is encrypted, but no key was provided");
+        };
+
+        if encrypted.len() < 12 {
+            anyhow::bail!("encrypted state is too short");
+        }
+
+        let (nonce, ciphertext) = encrypted.split_at(12);
+
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Failed to decrypt the stored state, it was encrypted with a different seed"
+                )
+            })
+    }
+
+    /// Re-writes states persisted by older versions in plaintext with
+    /// encryption enabled.
+    pub async fn encrypt_plaintext_states(&self) -> anyhow::Result<()> {
+        if self.state_cipher.is_none() {
+            return Ok(());
+        }
+
+        let rows = self.get_state_blobs().await?;
+
+        for (id, blob) in rows {
+            if blob.starts_with(ENCRYPTED_STATE_MAGIC) {
+                continue;
             }
 
-            Ok(res)
-        })
-        .await
-        .unwrap()
+            let encrypted = self.encrypt_state(blob)?;
+
+            tracing::info!(id, "encrypting plaintext wallet state");
+
+            self.update_state_blob(&id, encrypted).await?;
+        }
+
+        Ok(())
     }
 
-    pub async fn played_first_match_achievement_completed(
-        &self,
-        public_key: String,
-    ) -> anyhow::Result<bool> {
-        let conn = self.pool.get().await.unwrap();
-
-        let public_key = public_key.to_string();
-
-        conn.interact(move |conn| {
-            let mut stmt = conn.prepare(
-                "SELECT COUNT(*) FROM contract_address
-                WHERE
-                    (p1_public_key = ?1 OR p2_public_key = ('01;' || ?1) ) AND
-                    (game_state = '07' OR game_state = '08' OR game_state = '09')",
-            )?;
-
-            let row = stmt
-                .query_row([public_key], |row| Ok(row.get::<_, u64>(0)? > 0))
-                .context("Database error")?;
-
-            Ok(row)
-        })
-        .await
-        .unwrap()
+    pub async fn persist_state(&self, id: &str, hash: &str, state: &State) -> anyhow::Result<()> {
+        let mut buf = vec![];
+        serialize(&state, &mut buf, self.network_id)?;
+
+        let buf = self.encrypt_state(buf)?;
+
+        self.put_state_blob(id, hash, buf).await?;
+
+        Ok(())
     }
 
-    async fn create_tables(&self) -> anyhow::Result<()> {
-        let conn = self.pool.get().await.unwrap();
-
-        conn.interact(|conn| {
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS state (
-                id TEXT PRIMARY KEY,
-                hash TEXT NOT NULL,
-                state BLOB NOT NULL
-            )",
-                (),
-            )?;
-
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS contract_address (
-                id TEXT PRIMARY KEY,
-                game_state TEXT,
-                p1_public_key TEXT,
-                p2_public_key TEXT,
-                block_number INTEGER
-            )",
-                (),
-            )?;
-
-            conn.execute(
-                "CREATE INDEX IF NOT EXISTS idx_p1_public_key ON contract_address (p1_public_key)",
-                (),
-            )?;
-            conn.execute(
-                "CREATE INDEX IF NOT EXISTS idx_p2_public_key ON contract_address (p2_public_key)",
-                (),
-            )?;
-
-            Ok(())
-        })
-        .await
-        .unwrap()
+    pub async fn get_state(&self, id: &str) -> anyhow::Result<Option<(String, State)>> {
+        let row = self.get_state_blob(id).await?;
+
+        if let Some((hash, unserialized_state)) = row {
+            let unserialized_state = self.decrypt_state(unserialized_state)?;
+
+            let state = deserialize(std::io::Cursor::new(unserialized_state), self.network_id)
+                .context("Can't deserialize state object")?;
+
+            Ok(Some((hash, state)))
+        } else {
+            Ok(None)
+        }
     }
 }
@@ -0,0 +1,1258 @@
+use super::{
+    ApiKey, ApiKeyRequest, Backend, ContractStateChange, Lobby, LobbyCursor, LobbyFilter,
+    PlayerFeeReservation, PlayerStats,
+};
+use anyhow::Context as _;
+use deadpool_postgres::{Config, Pool, Runtime};
+use tokio_postgres::{NoTls, Row};
+
+pub struct PostgresDb {
+    pub(super) pool: Pool,
+}
+
+impl PostgresDb {
+    pub fn open(url: &str) -> anyhow::Result<Self> {
+        let cfg = Config {
+            url: Some(url.to_string()),
+            ..Default::default()
+        };
+
+        let pool = cfg
+            .create_pool(Some(Runtime::Tokio1), NoTls)
+            .context("Failed to initialize postgres pool")?;
+
+        Ok(Self { pool })
+    }
+}
+
+fn to_i64(value: u64) -> anyhow::Result<i64> {
+    i64::try_from(value).context("value doesn't fit in a BIGINT column")
+}
+
+/// Reads a BIGINT column holding an unsigned value.
+fn get_u64(row: &Row, idx: usize) -> anyhow::Result<u64> {
+    u64::try_from(row.try_get::<_, i64>(idx)?).context("negative value in an unsigned column")
+}
+
+fn get_opt_u64(row: &Row, idx: usize) -> anyhow::Result<Option<u64>> {
+    row.try_get::<_, Option<i64>>(idx)?
+        .map(|value| u64::try_from(value).context("negative value in an unsigned column"))
+        .transpose()
+}
+
+#[async_trait::async_trait]
+impl Backend for PostgresDb {
+    async fn put_state_blob(&self, id: &str, hash: &str, blob: Vec<u8>) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute(
+            "INSERT INTO state (id, hash, state) VALUES ($1, $2, $3)
+            ON CONFLICT (id) DO UPDATE SET hash = EXCLUDED.hash, state = EXCLUDED.state",
+            &[&id, &hash, &blob],
+        )
+        .await
+        .context("Db error persisting state")?;
+
+        Ok(())
+    }
+
+    async fn get_state_blob(&self, id: &str) -> anyhow::Result<Option<(String, Vec<u8>)>> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_opt("SELECT hash, state FROM state WHERE id = $1", &[&id])
+            .await
+            .context("Database access error")?;
+
+        row.map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+            .transpose()
+    }
+
+    async fn get_state_blobs(&self) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let conn = self.pool.get().await?;
+
+        let rows = conn
+            .query("SELECT id, state FROM state", &[])
+            .await
+            .context("Database error")?;
+
+        rows.into_iter()
+            .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+            .collect()
+    }
+
+    async fn update_state_blob(&self, id: &str, blob: Vec<u8>) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute("UPDATE state SET state = $2 WHERE id = $1", &[&id, &blob])
+            .await
+            .context("Db error updating state")?;
+
+        Ok(())
+    }
+
+    async fn delete_state(&self, id: &str) -> anyhow::Result<()> {
+        let mut conn = self.pool.get().await?;
+
+        let db_tx = conn.transaction().await?;
+
+        db_tx
+            .execute("DELETE FROM state WHERE id = $1", &[&id])
+            .await?;
+        db_tx
+            .execute("DELETE FROM state_public_keys WHERE id = $1", &[&id])
+            .await?;
+
+        db_tx.commit().await.context("Db error deleting state")?;
+
+        Ok(())
+    }
+
+    async fn get_state_public_keys(&self, id: &str) -> anyhow::Result<Option<String>> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_opt(
+                "SELECT public_keys FROM state_public_keys WHERE id = $1",
+                &[&id],
+            )
+            .await
+            .context("Database access error")?;
+
+        row.map(|row| Ok(row.try_get(0)?)).transpose()
+    }
+
+    async fn set_state_public_keys(&self, id: &str, public_keys: &str) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute(
+            "INSERT INTO state_public_keys (id, public_keys) VALUES ($1, $2)
+            ON CONFLICT (id) DO UPDATE SET public_keys = EXCLUDED.public_keys",
+            &[&id, &public_keys],
+        )
+        .await
+        .context("Db error persisting state public keys")?;
+
+        Ok(())
+    }
+
+    async fn count_contracts(&self) -> anyhow::Result<u64> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_one("SELECT COUNT(*) FROM contract_address", &[])
+            .await
+            .context("Database error")?;
+
+        get_u64(&row, 0)
+    }
+
+    async fn clear_contracts(&self) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.batch_execute(
+            "DELETE FROM contract_address; DELETE FROM contract_state; DELETE FROM contract_state_history; DELETE FROM lobby_event; DELETE FROM player_match; DELETE FROM achievement_completion; DELETE FROM contract_transaction;",
+        )
+            .await
+            .context("Db error clearing contracts")?;
+
+        Ok(())
+    }
+
+    async fn check_address(&self, address: &str) -> anyhow::Result<bool> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_opt("SELECT 1 FROM contract_address WHERE id = $1", &[&address])
+            .await
+            .context("Database access error")?;
+
+        Ok(row.is_some())
+    }
+
+    async fn insert_contract_address(&self, id: &str, block_number: u64) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute(
+            "INSERT INTO contract_address (id, created_block) VALUES ($1, $2) ON CONFLICT (id) DO NOTHING",
+            &[&id, &to_i64(block_number)?],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn update_contract_state(
+        &self,
+        contract_address: &str,
+        game_state: &str,
+        p1_public_key: &str,
+        p2_public_key: &str,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute(
+            "UPDATE contract_address SET game_state = $1, p1_public_key = $2, p2_public_key = $3, block_number = $5 WHERE id = $4",
+            &[&game_state, &p1_public_key, &p2_public_key, &contract_address, &to_i64(block_number)?],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn put_contract_state_values(
+        &self,
+        contract_address: &str,
+        values: Vec<(String, serde_json::Value)>,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.pool.get().await?;
+
+        let block_number = to_i64(block_number)?;
+
+        let db_tx = conn.transaction().await?;
+
+        for (key, value) in values {
+            db_tx
+                .execute(
+                    "INSERT INTO contract_state (contract_address, key, value, block_number) VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (contract_address, key) DO UPDATE SET value = EXCLUDED.value, block_number = EXCLUDED.block_number",
+                    &[&contract_address, &key, &value.to_string(), &block_number],
+                )
+                .await?;
+        }
+
+        db_tx
+            .commit()
+            .await
+            .context("Db error persisting contract state")?;
+
+        Ok(())
+    }
+
+    async fn set_contract_state_json(
+        &self,
+        contract_address: &str,
+        state: serde_json::Value,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute(
+            "UPDATE contract_address SET state_json = $2, state_block_number = $3 WHERE id = $1",
+            &[
+                &contract_address,
+                &state.to_string(),
+                &to_i64(block_number)?,
+            ],
+        )
+        .await
+        .context("Db error persisting contract state")?;
+
+        Ok(())
+    }
+
+    async fn get_contract_state_json(
+        &self,
+        contract_address: &str,
+    ) -> anyhow::Result<Option<(serde_json::Value, u64)>> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_opt(
+                "SELECT state_json, state_block_number FROM contract_address
+                WHERE id = $1 AND state_json IS NOT NULL",
+                &[&contract_address],
+            )
+            .await
+            .context("Database access error")?;
+
+        row.map(|row| {
+            Ok((
+                serde_json::from_str(row.try_get::<_, &str>(0)?)
+                    .context("Invalid stored contract state")?,
+                get_u64(&row, 1)?,
+            ))
+        })
+        .transpose()
+    }
+
+    async fn get_contract_ledger_state(
+        &self,
+        contract_address: &str,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_opt(
+                "SELECT ledger_state FROM contract_address WHERE id = $1",
+                &[&contract_address],
+            )
+            .await
+            .context("Database access error")?;
+
+        Ok(row.map(|row| row.try_get(0)).transpose()?.flatten())
+    }
+
+    async fn is_contract_transaction_applied(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+    ) -> anyhow::Result<bool> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_opt(
+                "SELECT 1 FROM contract_transaction WHERE contract_address = $1 AND tx_hash = $2",
+                &[&contract_address, &tx_hash],
+            )
+            .await
+            .context("Database access error")?;
+
+        Ok(row.is_some())
+    }
+
+    async fn set_contract_ledger_state(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+        state: Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.pool.get().await?;
+
+        let db_tx = conn.transaction().await?;
+
+        let inserted = db_tx
+            .execute(
+                "INSERT INTO contract_transaction (contract_address, tx_hash) VALUES ($1, $2)
+                ON CONFLICT DO NOTHING",
+                &[&contract_address, &tx_hash],
+            )
+            .await?;
+
+        if inserted == 0 {
+            return Ok(false);
+        }
+
+        db_tx
+            .execute(
+                "UPDATE contract_address SET ledger_state = $2 WHERE id = $1",
+                &[&contract_address, &state],
+            )
+            .await?;
+
+        db_tx
+            .commit()
+            .await
+            .context("Db error persisting contract ledger state")?;
+
+        Ok(true)
+    }
+
+    async fn insert_contract_state_change(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+        block_number: u64,
+        fields: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute(
+            "INSERT INTO contract_state_history (contract_address, tx_hash, block_number, fields) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (contract_address, tx_hash) DO NOTHING",
+            &[&contract_address, &tx_hash, &to_i64(block_number)?, &fields.to_string()],
+        )
+        .await
+        .context("Db error persisting contract state change")?;
+
+        Ok(())
+    }
+
+    async fn get_contract_state_history(
+        &self,
+        contract_address: &str,
+        after: Option<i64>,
+        count: Option<u8>,
+    ) -> anyhow::Result<Vec<ContractStateChange>> {
+        let conn = self.pool.get().await?;
+
+        let rows = conn
+            .query(
+                "SELECT id, tx_hash, block_number, fields FROM contract_state_history
+                WHERE
+                    contract_address = $1 AND
+                    ($2::BIGINT IS NULL OR id > $2)
+                ORDER BY id
+                LIMIT $3",
+                &[&contract_address, &after, &i64::from(count.unwrap_or(10))],
+            )
+            .await
+            .context("Database error")?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(ContractStateChange {
+                    id: row.try_get(0)?,
+                    tx_hash: row.try_get(1)?,
+                    block_number: get_u64(&row, 2)?,
+                    fields: serde_json::from_str(row.try_get::<_, &str>(3)?)
+                        .context("Invalid stored contract state change")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn insert_lobby_event(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+        kind: &str,
+        block_number: u64,
+        [game_state, p1_public_key, p2_public_key]: [String; 3],
+    ) -> anyhow::Result<Option<i64>> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_opt(
+                "INSERT INTO lobby_event (contract_address, tx_hash, kind, block_number, game_state, p1_public_key, p2_public_key) VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT (contract_address, tx_hash, kind) DO NOTHING
+                RETURNING id",
+                &[
+                    &contract_address,
+                    &tx_hash,
+                    &kind,
+                    &to_i64(block_number)?,
+                    &game_state,
+                    &p1_public_key,
+                    &p2_public_key,
+                ],
+            )
+            .await
+            .context("Db error persisting lobby event")?;
+
+        row.map(|row| Ok(row.try_get(0)?)).transpose()
+    }
+
+    async fn get_lobby_events(
+        &self,
+        from_block: u64,
+        player: Option<String>,
+        address: Option<String>,
+        after: Option<i64>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<(i64, String, String, u64, [String; 3])>> {
+        let conn = self.pool.get().await?;
+
+        let rows = conn
+            .query(
+                "SELECT id, kind, contract_address, block_number, game_state, p1_public_key, p2_public_key FROM lobby_event
+                WHERE
+                    block_number >= $1 AND
+                    ($2::TEXT IS NULL OR p1_public_key = $2 OR p2_public_key = ('01;' || $2)) AND
+                    ($3::TEXT IS NULL OR contract_address = $3) AND
+                    ($4::BIGINT IS NULL OR id > $4)
+                ORDER BY id
+                LIMIT $5",
+                &[&to_i64(from_block)?, &player, &address, &after, &i64::from(limit)],
+            )
+            .await
+            .context("Database error")?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok((
+                    row.try_get(0)?,
+                    row.try_get(1)?,
+                    row.try_get(2)?,
+                    get_u64(&row, 3)?,
+                    [row.try_get(4)?, row.try_get(5)?, row.try_get(6)?],
+                ))
+            })
+            .collect()
+    }
+
+    async fn get_lobbies_waiting_for_p2(
+        &self,
+        filter: LobbyFilter,
+        exclude_player: Option<String>,
+        after: Option<LobbyCursor>,
+        count: u32,
+    ) -> anyhow::Result<(Vec<Lobby>, u64)> {
+        let conn = self.pool.get().await?;
+
+        query_lobbies(
+            &conn,
+            "p2_public_key = '00;' AND ($6::TEXT IS NULL OR p1_public_key <> $6)",
+            filter,
+            exclude_player,
+            after,
+            count,
+        )
+        .await
+    }
+
+    async fn get_player_lobbies(
+        &self,
+        public_key: String,
+        filter: LobbyFilter,
+        after: Option<LobbyCursor>,
+        count: u32,
+    ) -> anyhow::Result<(Vec<Lobby>, u64)> {
+        let conn = self.pool.get().await?;
+
+        query_lobbies(
+            &conn,
+            "(p1_public_key = $6::TEXT OR p2_public_key = ('01;' || $6::TEXT))",
+            filter,
+            Some(public_key),
+            after,
+            count,
+        )
+        .await
+    }
+
+    async fn get_lobby_cursor(&self, address: &str) -> anyhow::Result<Option<LobbyCursor>> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_opt(
+                "SELECT created_block, id FROM contract_address WHERE id = $1",
+                &[&address],
+            )
+            .await
+            .context("Database error")?;
+
+        row.map(|row| {
+            Ok(LobbyCursor {
+                created_block: get_u64(&row, 0)?,
+                address: row.try_get(1)?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn record_match(
+        &self,
+        contract_address: &str,
+        p1_public_key: &str,
+        p2_public_key: &str,
+        p1_result: &str,
+        block_number: u64,
+    ) -> anyhow::Result<bool> {
+        let mut conn = self.pool.get().await?;
+
+        let p2_result = match p1_result {
+            "win" => "loss",
+            "loss" => "win",
+            result => result,
+        };
+        let block_number = to_i64(block_number)?;
+
+        let db_tx = conn.transaction().await?;
+
+        let inserted = db_tx
+            .execute(
+                "INSERT INTO player_match (player, contract_address, opponent, result, block_number) VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT DO NOTHING",
+                &[&p1_public_key, &contract_address, &p2_public_key, &p1_result, &block_number],
+            )
+            .await?;
+
+        db_tx
+            .execute(
+                "INSERT INTO player_match (player, contract_address, opponent, result, block_number) VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT DO NOTHING",
+                &[&p2_public_key, &contract_address, &p1_public_key, &p2_result, &block_number],
+            )
+            .await?;
+
+        db_tx.commit().await?;
+
+        Ok(inserted > 0)
+    }
+
+    async fn get_player_matches(&self, player: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let conn = self.pool.get().await?;
+
+        let rows = conn
+            .query(
+                "SELECT opponent, result FROM player_match WHERE player = $1 ORDER BY block_number, seq",
+                &[&player],
+            )
+            .await
+            .context("Database error")?;
+
+        rows.into_iter()
+            .map(|row| Ok((row.try_get(0)?, row.try_get(1)?)))
+            .collect()
+    }
+
+    async fn complete_achievement(
+        &self,
+        player: &str,
+        name: &str,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute(
+            "INSERT INTO achievement_completion (player, name, block_number) VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING",
+            &[&player, &name, &to_i64(block_number)?],
+        )
+        .await
+        .context("Db error persisting achievement")?;
+
+        Ok(())
+    }
+
+    async fn get_completed_achievements(&self, player: &str) -> anyhow::Result<Vec<(String, u64)>> {
+        let conn = self.pool.get().await?;
+
+        let rows = conn
+            .query(
+                "SELECT name, block_number FROM achievement_completion WHERE player = $1",
+                &[&player],
+            )
+            .await
+            .context("Database error")?;
+
+        rows.into_iter()
+            .map(|row| Ok((row.try_get(0)?, get_u64(&row, 1)?)))
+            .collect()
+    }
+
+    async fn get_player_stats(
+        &self,
+        player: &str,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+    ) -> anyhow::Result<PlayerStats> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_opt(
+                "SELECT
+                        player,
+                        COUNT(*),
+                        SUM(CASE WHEN result = 'win' THEN 1 ELSE 0 END),
+                        SUM(CASE WHEN result = 'loss' THEN 1 ELSE 0 END),
+                        SUM(CASE WHEN result = 'draw' THEN 1 ELSE 0 END),
+                        max(block_number)
+                    FROM player_match
+                    WHERE
+                        player = $1 AND
+                        ($2::BIGINT IS NULL OR block_number >= $2) AND
+                        ($3::BIGINT IS NULL OR block_number <= $3)
+                    GROUP BY player",
+                &[
+                    &player,
+                    &from_block.map(to_i64).transpose()?,
+                    &to_block.map(to_i64).transpose()?,
+                ],
+            )
+            .await
+            .context("Database error")?;
+
+        Ok(row
+            .as_ref()
+            .map(player_stats_from_row)
+            .transpose()?
+            .unwrap_or(PlayerStats {
+                player: player.to_string(),
+                ..Default::default()
+            }))
+    }
+
+    async fn get_leaderboard(
+        &self,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+        offset: u64,
+        count: u8,
+    ) -> anyhow::Result<Vec<PlayerStats>> {
+        let conn = self.pool.get().await?;
+
+        let rows = conn
+            .query(
+                "SELECT
+                        player,
+                        COUNT(*),
+                        SUM(CASE WHEN result = 'win' THEN 1 ELSE 0 END),
+                        SUM(CASE WHEN result = 'loss' THEN 1 ELSE 0 END),
+                        SUM(CASE WHEN result = 'draw' THEN 1 ELSE 0 END),
+                        max(block_number)
+                    FROM player_match
+                    WHERE
+                        ($1::BIGINT IS NULL OR block_number >= $1) AND
+                        ($2::BIGINT IS NULL OR block_number <= $2)
+                    GROUP BY player
+                    ORDER BY 3 DESC, 5 DESC, 4 ASC, player
+                    LIMIT $4 OFFSET $3",
+                &[
+                    &from_block.map(to_i64).transpose()?,
+                    &to_block.map(to_i64).transpose()?,
+                    &to_i64(offset)?,
+                    &i64::from(count),
+                ],
+            )
+            .await
+            .context("Database error")?;
+
+        rows.iter().map(player_stats_from_row).collect()
+    }
+
+    async fn last_contract_block(&self) -> anyhow::Result<Option<u64>> {
+        let conn = self.pool.get().await?;
+
+        let row = conn
+            .query_one("SELECT max(block_number) FROM contract_address", &[])
+            .await
+            .context("Database error")?;
+
+        get_opt_u64(&row, 0)
+    }
+
+    async fn insert_api_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        budget: Option<u64>,
+        requests_per_minute: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute(
+            "INSERT INTO api_key (name, key_hash, budget, requests_per_minute) VALUES ($1, $2, $3, $4)",
+            &[
+                &name,
+                &key_hash,
+                &budget.map(to_i64).transpose()?,
+                &requests_per_minute.map(i64::from),
+            ],
+        )
+        .await
+        .context("Db error inserting api key")?;
+
+        Ok(())
+    }
+
+    async fn revoke_api_key(&self, name: &str) -> anyhow::Result<bool> {
+        let conn = self.pool.get().await?;
+
+        let deleted = conn
+            .execute("DELETE FROM api_key WHERE name = $1", &[&name])
+            .await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn get_api_keys(&self) -> anyhow::Result<Vec<ApiKey>> {
+        let conn = self.pool.get().await?;
+
+        let rows = conn
+            .query(
+                "SELECT name, budget, spent, requests_per_minute FROM api_key ORDER BY name",
+                &[],
+            )
+            .await
+            .context("Database error")?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(ApiKey {
+                    name: row.try_get(0)?,
+                    budget: get_opt_u64(&row, 1)?,
+                    spent: get_u64(&row, 2)?,
+                    requests_per_minute: get_opt_u64(&row, 3)?.map(u32::try_from).transpose()?,
+                })
+            })
+            .collect()
+    }
+
+    async fn consume_api_key_request(
+        &self,
+        key_hash: &str,
+        now: u64,
+    ) -> anyhow::Result<ApiKeyRequest> {
+        let mut conn = self.pool.get().await?;
+
+        let db_tx = conn.transaction().await?;
+
+        let row = db_tx
+            .query_opt(
+                "SELECT requests_per_minute, window_start, window_requests FROM api_key WHERE key_hash = $1 FOR UPDATE",
+                &[&key_hash],
+            )
+            .await
+            .context("Database access error")?;
+
+        let Some(row) = row else {
+            return Ok(ApiKeyRequest::Unknown);
+        };
+
+        let requests_per_minute = row.try_get::<_, Option<i64>>(0)?;
+        let window_start = get_u64(&row, 1)?;
+        let window_requests = row.try_get::<_, i64>(2)?;
+
+        let (window_start, window_requests) = if now >= window_start + 60 {
+            (now, 0)
+        } else {
+            (window_start, window_requests)
+        };
+
+        if let Some(limit) = requests_per_minute {
+            if window_requests >= limit {
+                return Ok(ApiKeyRequest::RateLimited {
+                    retry_after: window_start + 60 - now,
+                });
+            }
+        }
+
+        db_tx
+            .execute(
+                "UPDATE api_key SET window_start = $2, window_requests = $3 WHERE key_hash = $1",
+                &[&key_hash, &to_i64(window_start)?, &(window_requests + 1)],
+            )
+            .await?;
+
+        db_tx.commit().await?;
+
+        Ok(ApiKeyRequest::Allowed)
+    }
+
+    async fn reserve_api_key_budget(&self, key_hash: &str, amount: u64) -> anyhow::Result<bool> {
+        let conn = self.pool.get().await?;
+
+        let updated = conn
+            .execute(
+                "UPDATE api_key SET spent = spent + $2
+                WHERE key_hash = $1 AND (budget IS NULL OR spent + $2 <= budget)",
+                &[&key_hash, &to_i64(amount)?],
+            )
+            .await?;
+
+        Ok(updated > 0)
+    }
+
+    async fn release_api_key_budget(&self, key_hash: &str, amount: u64) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute(
+            "UPDATE api_key SET spent = GREATEST(spent - $2, 0) WHERE key_hash = $1",
+            &[&key_hash, &to_i64(amount)?],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn consume_player_request(
+        &self,
+        player: &str,
+        now: u64,
+        requests_per_minute: u32,
+    ) -> anyhow::Result<Option<u64>> {
+        let mut conn = self.pool.get().await?;
+
+        let now = to_i64(now)?;
+
+        let db_tx = conn.transaction().await?;
+
+        // serializes the requests of the same player across replicas.
+        db_tx
+            .execute("SELECT pg_advisory_xact_lock(hashtext($1))", &[&player])
+            .await?;
+
+        db_tx
+            .execute(
+                "DELETE FROM player_request WHERE player = $1 AND timestamp + 60 <= $2",
+                &[&player, &now],
+            )
+            .await?;
+
+        let row = db_tx
+            .query_one(
+                "SELECT COUNT(*), min(timestamp) FROM player_request WHERE player = $1",
+                &[&player],
+            )
+            .await
+            .context("Database access error")?;
+
+        let count = row.try_get::<_, i64>(0)?;
+        let oldest = row.try_get::<_, Option<i64>>(1)?;
+
+        if count >= i64::from(requests_per_minute) {
+            return Ok(Some(
+                oldest
+                    .map(|oldest| (oldest + 60 - now) as u64)
+                    .unwrap_or(60),
+            ));
+        }
+
+        db_tx
+            .execute(
+                "INSERT INTO player_request (player, timestamp) VALUES ($1, $2)",
+                &[&player, &now],
+            )
+            .await?;
+
+        db_tx.commit().await?;
+
+        Ok(None)
+    }
+
+    async fn reserve_player_fee(
+        &self,
+        player: &str,
+        amount: u64,
+        daily_budget: u64,
+        now: u64,
+    ) -> anyhow::Result<PlayerFeeReservation> {
+        const DAY: i64 = 24 * 60 * 60;
+
+        let mut conn = self.pool.get().await?;
+
+        let now = to_i64(now)?;
+        let amount = to_i64(amount)?;
+
+        let db_tx = conn.transaction().await?;
+
+        db_tx
+            .execute("SELECT pg_advisory_xact_lock(hashtext($1))", &[&player])
+            .await?;
+
+        db_tx
+            .execute(
+                "DELETE FROM player_fee WHERE player = $1 AND timestamp + $2 <= $3",
+                &[&player, &DAY, &now],
+            )
+            .await?;
+
+        let row = db_tx
+            .query_one(
+                "SELECT COALESCE(SUM(amount), 0)::BIGINT, min(timestamp) FROM player_fee WHERE player = $1",
+                &[&player],
+            )
+            .await
+            .context("Database access error")?;
+
+        let spent = row.try_get::<_, i64>(0)?;
+        let oldest = row.try_get::<_, Option<i64>>(1)?;
+
+        if spent.saturating_add(amount) as u64 > daily_budget {
+            return Ok(PlayerFeeReservation::Exceeded {
+                retry_after: oldest
+                    .map(|oldest| (oldest + DAY - now) as u64)
+                    .unwrap_or(DAY as u64),
+            });
+        }
+
+        let row = db_tx
+            .query_one(
+                "INSERT INTO player_fee (player, timestamp, amount) VALUES ($1, $2, $3) RETURNING id",
+                &[&player, &now, &amount],
+            )
+            .await?;
+
+        db_tx.commit().await?;
+
+        Ok(PlayerFeeReservation::Reserved(row.try_get(0)?))
+    }
+
+    async fn release_player_fee(&self, id: i64) -> anyhow::Result<()> {
+        let conn = self.pool.get().await?;
+
+        conn.execute("DELETE FROM player_fee WHERE id = $1", &[&id])
+            .await?;
+
+        Ok(())
+    }
+
+    async fn prune_player_usage(&self, now: u64) -> anyhow::Result<()> {
+        const DAY: i64 = 24 * 60 * 60;
+
+        let conn = self.pool.get().await?;
+
+        let now = to_i64(now)?;
+
+        conn.execute(
+            "DELETE FROM player_request WHERE timestamp + 60 <= $1",
+            &[&now],
+        )
+        .await?;
+        conn.execute(
+            "DELETE FROM player_fee WHERE timestamp + $1 <= $2",
+            &[&DAY, &now],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn migrate(&self) -> anyhow::Result<()> {
+        let mut conn = self.pool.get().await?;
+
+        let db_tx = conn.transaction().await?;
+
+        db_tx
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS schema_version (version BIGINT NOT NULL);
+                LOCK TABLE schema_version IN EXCLUSIVE MODE;",
+            )
+            .await?;
+
+        let version = match db_tx
+            .query_opt("SELECT version FROM schema_version", &[])
+            .await?
+        {
+            Some(row) => usize::try_from(get_u64(&row, 0)?)?,
+            None => 0,
+        };
+
+        if version > MIGRATIONS.len() {
+            anyhow::bail!(
+                "database schema version {} is newer than the latest supported version {}",
+                version,
+                MIGRATIONS.len()
+            );
+        }
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            tracing::info!("applying database migration {}", i + 1);
+
+            db_tx
+                .batch_execute(migration)
+                .await
+                .context(format!("Failed to apply database migration {}", i + 1))?;
+        }
+
+        db_tx.execute("DELETE FROM schema_version", &[]).await?;
+        db_tx
+            .execute(
+                "INSERT INTO schema_version (version) VALUES ($1)",
+                &[&(MIGRATIONS.len() as i64)],
+            )
+            .await?;
+
+        db_tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Constraints of [`LobbyFilter`], over the parameters $1 to $5.
+const LOBBY_FILTER: &str = "
+    ($1::TEXT IS NULL OR game_state = $1) AND
+    ($2::BIGINT IS NULL OR block_number >= $2) AND
+    ($3::BIGINT IS NULL OR block_number <= $3) AND
+    ($4::BIGINT IS NULL OR created_block >= $4) AND
+    ($5::BIGINT IS NULL OR created_block <= $5)";
+
+/// Pages through the lobbies selected by `condition`, which can use `param`
+/// as $6.
+async fn query_lobbies(
+    conn: &deadpool_postgres::Client,
+    condition: &str,
+    filter: LobbyFilter,
+    param: Option<String>,
+    after: Option<LobbyCursor>,
+    count: u32,
+) -> anyhow::Result<(Vec<Lobby>, u64)> {
+    let from_block = filter.from_block.map(to_i64).transpose()?;
+    let to_block = filter.to_block.map(to_i64).transpose()?;
+    let created_from_block = filter.created_from_block.map(to_i64).transpose()?;
+    let created_to_block = filter.created_to_block.map(to_i64).transpose()?;
+    let after_block = after
+        .as_ref()
+        .map(|cursor| to_i64(cursor.created_block))
+        .transpose()?;
+    let after_address = after.as_ref().map(|cursor| cursor.address.as_str());
+
+    let total = conn
+        .query_one(
+            &format!("SELECT COUNT(*) FROM contract_address WHERE {condition} AND {LOBBY_FILTER}"),
+            &[
+                &filter.game_state,
+                &from_block,
+                &to_block,
+                &created_from_block,
+                &created_to_block,
+                &param,
+            ],
+        )
+        .await
+        .context("Database error")?;
+    let total = get_u64(&total, 0)?;
+
+    let rows = conn
+        .query(
+            &format!(
+                "SELECT id, game_state, block_number, created_block, p1_public_key, p2_public_key FROM contract_address
+                WHERE
+                    {condition} AND {LOBBY_FILTER} AND
+                    ($7::BIGINT IS NULL OR (created_block, id) < ($7, $8::TEXT))
+                ORDER BY created_block DESC, id DESC
+                LIMIT $9"
+            ),
+            &[
+                &filter.game_state,
+                &from_block,
+                &to_block,
+                &created_from_block,
+                &created_to_block,
+                &param,
+                &after_block,
+                &after_address,
+                &i64::from(count),
+            ],
+        )
+        .await
+        .context("Database error")?;
+
+    let lobbies = rows
+        .into_iter()
+        .map(|row| {
+            Ok(Lobby {
+                address: row.try_get(0)?,
+                game_state: row.try_get(1)?,
+                block_number: get_u64(&row, 2)?,
+                created_block: get_u64(&row, 3)?,
+                p1_public_key: row.try_get(4)?,
+                p2_public_key: row.try_get(5)?,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok((lobbies, total))
+}
+
+fn player_stats_from_row(row: &Row) -> anyhow::Result<PlayerStats> {
+    Ok(PlayerStats {
+        player: row.try_get(0)?,
+        games_played: get_u64(row, 1)?,
+        wins: get_u64(row, 2)?,
+        losses: get_u64(row, 3)?,
+        draws: get_u64(row, 4)?,
+        last_played_block: get_opt_u64(row, 5)?,
+    })
+}
+
+/// The sqlite migrations translated to postgres, so that both backends have
+/// the same tables and columns at each version. Migrations up to 11 had extra
+/// `seq` columns here, which the sqlite tables got in 12 in place of their
+/// implicit rowids.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE state (
+        id TEXT PRIMARY KEY,
+        hash TEXT NOT NULL,
+        state BYTEA NOT NULL
+    );
+    CREATE TABLE contract_address (
+        id TEXT PRIMARY KEY,
+        game_state TEXT,
+        p1_public_key TEXT,
+        p2_public_key TEXT,
+        block_number BIGINT
+    );
+    CREATE INDEX idx_p1_public_key ON contract_address (p1_public_key);
+    CREATE INDEX idx_p2_public_key ON contract_address (p2_public_key);",
+    // 2: api keys
+    "CREATE TABLE api_key (
+        name TEXT PRIMARY KEY,
+        key_hash TEXT NOT NULL UNIQUE,
+        budget BIGINT,
+        spent BIGINT NOT NULL DEFAULT 0,
+        requests_per_minute BIGINT,
+        window_start BIGINT NOT NULL DEFAULT 0,
+        window_requests BIGINT NOT NULL DEFAULT 0
+    );",
+    // 3: per player limits
+    "CREATE TABLE player_request (
+        player TEXT NOT NULL,
+        timestamp BIGINT NOT NULL
+    );
+    CREATE TABLE player_fee (
+        id BIGSERIAL PRIMARY KEY,
+        player TEXT NOT NULL,
+        timestamp BIGINT NOT NULL,
+        amount BIGINT NOT NULL
+    );
+    CREATE INDEX idx_player_request ON player_request (player, timestamp);
+    CREATE INDEX idx_player_fee ON player_fee (player, timestamp);",
+    // 4: public keys of the wallet state
+    "CREATE TABLE state_public_keys (
+        id TEXT PRIMARY KEY,
+        public_keys TEXT NOT NULL
+    );",
+    // 5: contract fields decoded with the contract schema
+    "CREATE TABLE contract_state (
+        contract_address TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        block_number BIGINT NOT NULL,
+        PRIMARY KEY (contract_address, key)
+    );",
+    // 6: whole contract state as json
+    "ALTER TABLE contract_address ADD COLUMN state_json TEXT;
+    ALTER TABLE contract_address ADD COLUMN state_block_number BIGINT;",
+    // 7: log of the decoded contract fields, one entry per transaction
+    "CREATE TABLE contract_state_history (
+        id BIGSERIAL PRIMARY KEY,
+        contract_address TEXT NOT NULL,
+        tx_hash TEXT NOT NULL,
+        block_number BIGINT NOT NULL,
+        fields TEXT NOT NULL
+    );
+    CREATE INDEX idx_contract_state_history ON contract_state_history (contract_address, id);
+    CREATE UNIQUE INDEX idx_contract_state_history_tx ON contract_state_history (contract_address, tx_hash);",
+    // 8: local copy of the ledger state of the contracts, and the transactions
+    // included in it, so that replays skip them
+    "ALTER TABLE contract_address ADD COLUMN ledger_state BYTEA;
+    CREATE TABLE contract_transaction (
+        contract_address TEXT NOT NULL,
+        tx_hash TEXT NOT NULL,
+        PRIMARY KEY (contract_address, tx_hash)
+    );",
+    // 9: lobby events, for clients resuming their event stream, once per
+    // transaction so that replays don't repeat them
+    "CREATE TABLE lobby_event (
+        id BIGSERIAL PRIMARY KEY,
+        contract_address TEXT NOT NULL,
+        tx_hash TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        block_number BIGINT NOT NULL,
+        game_state TEXT NOT NULL,
+        p1_public_key TEXT NOT NULL,
+        p2_public_key TEXT NOT NULL
+    );
+    CREATE INDEX idx_lobby_event_block ON lobby_event (block_number);
+    CREATE UNIQUE INDEX idx_lobby_event_tx ON lobby_event (contract_address, tx_hash, kind);",
+    // 10: finished matches and achievements
+    "CREATE TABLE player_match (
+        seq BIGSERIAL PRIMARY KEY,
+        player TEXT NOT NULL,
+        contract_address TEXT NOT NULL,
+        opponent TEXT NOT NULL,
+        result TEXT NOT NULL,
+        block_number BIGINT NOT NULL,
+        UNIQUE (player, contract_address)
+    );
+    CREATE INDEX idx_player_match_block ON player_match (block_number);
+    CREATE TABLE achievement_completion (
+        player TEXT NOT NULL,
+        name TEXT NOT NULL,
+        block_number BIGINT NOT NULL,
+        PRIMARY KEY (player, name)
+    );",
+    // 11: creation block of the lobbies, for stable pagination
+    "ALTER TABLE contract_address ADD COLUMN created_block BIGINT;
+    UPDATE contract_address SET created_block = COALESCE(
+        (SELECT min(block_number) FROM contract_state_history WHERE contract_address = contract_address.id),
+        block_number
+    );
+    CREATE INDEX idx_contract_address_created ON contract_address (created_block, id);",
+];
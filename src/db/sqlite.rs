@@ -0,0 +1,1362 @@
+use super::{
+    ApiKey, ApiKeyRequest, Backend, ContractStateChange, Lobby, LobbyCursor, LobbyFilter,
+    PlayerFeeReservation, PlayerStats,
+};
+use anyhow::Context as _;
+use deadpool_sqlite::{Config, Pool, Runtime};
+use rusqlite::{OptionalExtension as _, TransactionBehavior};
+use std::path::Path;
+
+pub struct SqliteDb {
+    pub(super) pool: Pool,
+}
+
+impl SqliteDb {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let cfg = Config::new(path.as_ref());
+        let pool = cfg
+            .create_pool(Runtime::Tokio1)
+            .context("Failed to initialize sqlite pool")?;
+
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for SqliteDb {
+    async fn put_state_blob(&self, id: &str, hash: &str, blob: Vec<u8>) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let id = id.to_string();
+        let hash = hash.to_string();
+
+        conn.interact(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO state (id, hash, state) VALUES (?1, ?2, ?3)",
+                (&id, &hash, &blob),
+            )
+        })
+        .await
+        .unwrap()
+        .context("Db error persisting state")?;
+
+        Ok(())
+    }
+
+    async fn get_state_blob(&self, id: &str) -> anyhow::Result<Option<(String, Vec<u8>)>> {
+        let conn = self.pool.get().await.unwrap();
+
+        let id = id.to_string();
+
+        conn.interact(move |conn| -> anyhow::Result<Option<(String, Vec<u8>)>> {
+            let mut stmt = conn.prepare(
+                "SELECT hash, state FROM state WHERE id = ?1 ORDER BY rowid DESC LIMIT 1",
+            )?;
+
+            let row = stmt
+                .query_row([id], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+                })
+                .optional()
+                .context("Database access error")?;
+
+            Ok(row)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_state_blobs(&self) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(|conn| -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+            let mut stmt = conn.prepare("SELECT id, state FROM state")?;
+
+            let rows = stmt
+                .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+                .context("Database error")?;
+
+            let mut res = vec![];
+            for row in rows {
+                res.push(row?);
+            }
+
+            Ok(res)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn update_state_blob(&self, id: &str, blob: Vec<u8>) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let id = id.to_string();
+
+        conn.interact(move |conn| {
+            conn.execute("UPDATE state SET state = ?2 WHERE id = ?1", (id, blob))
+        })
+        .await
+        .unwrap()
+        .context("Db error updating state")?;
+
+        Ok(())
+    }
+
+    async fn delete_state(&self, id: &str) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let id = id.to_string();
+
+        conn.interact(move |conn| -> anyhow::Result<()> {
+            let db_tx = conn.transaction()?;
+
+            db_tx.execute("DELETE FROM state WHERE id = ?1", [&id])?;
+            db_tx.execute("DELETE FROM state_public_keys WHERE id = ?1", [&id])?;
+
+            db_tx.commit()?;
+
+            Ok(())
+        })
+        .await
+        .unwrap()
+        .context("Db error deleting state")?;
+
+        Ok(())
+    }
+
+    async fn get_state_public_keys(&self, id: &str) -> anyhow::Result<Option<String>> {
+        let conn = self.pool.get().await.unwrap();
+
+        let id = id.to_string();
+
+        conn.interact(move |conn| {
+            conn.query_row(
+                "SELECT public_keys FROM state_public_keys WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Database access error")
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn set_state_public_keys(&self, id: &str, public_keys: &str) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let id = id.to_string();
+        let public_keys = public_keys.to_string();
+
+        conn.interact(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO state_public_keys (id, public_keys) VALUES (?1, ?2)",
+                (id, public_keys),
+            )
+        })
+        .await
+        .unwrap()
+        .context("Db error persisting state public keys")?;
+
+        Ok(())
+    }
+
+    async fn count_contracts(&self) -> anyhow::Result<u64> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(|conn| {
+            conn.query_row("SELECT COUNT(*) FROM contract_address", (), |row| {
+                row.get(0)
+            })
+            .context("Database error")
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn clear_contracts(&self) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(|conn| {
+            conn.execute_batch(
+                "DELETE FROM contract_address; DELETE FROM contract_state; DELETE FROM contract_state_history; DELETE FROM lobby_event; DELETE FROM player_match; DELETE FROM achievement_completion; DELETE FROM contract_transaction;",
+            )
+        })
+        .await
+        .unwrap()
+        .context("Db error clearing contracts")?;
+
+        Ok(())
+    }
+
+    async fn check_address(&self, address: &str) -> anyhow::Result<bool> {
+        let conn = self.pool.get().await.unwrap();
+
+        let address = address.to_string();
+
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare("SELECT 1 FROM contract_address WHERE id = ?1")?;
+
+            let mut rows = stmt.query([address]).context("Database access error")?;
+
+            Ok(rows.next()?.is_some())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn insert_contract_address(&self, id: &str, block_number: u64) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let id = id.to_string();
+
+        conn.interact(move |conn| {
+            conn.execute(
+                "INSERT INTO contract_address (id, created_block) VALUES (?1, ?2) ON CONFLICT (id) DO NOTHING",
+                (id, block_number),
+            )
+        })
+        .await
+        .unwrap()?;
+
+        Ok(())
+    }
+
+    async fn update_contract_state(
+        &self,
+        contract_address: &str,
+        game_state: &str,
+        p1_public_key: &str,
+        p2_public_key: &str,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+        let game_state = game_state.to_string();
+        let p1_public_key = p1_public_key.to_string();
+        let p2_public_key = p2_public_key.to_string();
+
+        conn.interact(move |conn| {
+            conn.execute(
+                "UPDATE contract_address SET game_state = ?1, p1_public_key = ?2, p2_public_key = ?3, block_number = ?5 WHERE id = ?4 ",
+                (game_state, p1_public_key, p2_public_key, contract_address, block_number),
+            )
+        })
+        .await
+        .unwrap()?;
+
+        Ok(())
+    }
+
+    async fn put_contract_state_values(
+        &self,
+        contract_address: &str,
+        values: Vec<(String, serde_json::Value)>,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+
+        conn.interact(move |conn| {
+            let db_tx = conn.transaction()?;
+
+            for (key, value) in values {
+                db_tx.execute(
+                    "INSERT OR REPLACE INTO contract_state (contract_address, key, value, block_number) VALUES (?1, ?2, ?3, ?4)",
+                    (&contract_address, key, value.to_string(), block_number),
+                )?;
+            }
+
+            db_tx.commit()
+        })
+        .await
+        .unwrap()
+        .context("Db error persisting contract state")?;
+
+        Ok(())
+    }
+
+    async fn set_contract_state_json(
+        &self,
+        contract_address: &str,
+        state: serde_json::Value,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+
+        conn.interact(move |conn| {
+            conn.execute(
+                "UPDATE contract_address SET state_json = ?2, state_block_number = ?3 WHERE id = ?1",
+                (contract_address, state.to_string(), block_number),
+            )
+        })
+        .await
+        .unwrap()
+        .context("Db error persisting contract state")?;
+
+        Ok(())
+    }
+
+    async fn get_contract_state_json(
+        &self,
+        contract_address: &str,
+    ) -> anyhow::Result<Option<(serde_json::Value, u64)>> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+
+        let row = conn
+            .interact(move |conn| -> anyhow::Result<Option<(String, u64)>> {
+                let mut stmt = conn.prepare(
+                    "SELECT state_json, state_block_number FROM contract_address
+                    WHERE id = ?1 AND state_json IS NOT NULL",
+                )?;
+
+                let mut rows = stmt
+                    .query([contract_address])
+                    .context("Database access error")?;
+
+                let Some(row) = rows.next()? else {
+                    return Ok(None);
+                };
+
+                Ok(Some((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)))
+            })
+            .await
+            .unwrap()?;
+
+        row.map(|(state, block_number)| {
+            Ok((
+                serde_json::from_str(&state).context("Invalid stored contract state")?,
+                block_number,
+            ))
+        })
+        .transpose()
+    }
+
+    async fn get_contract_ledger_state(
+        &self,
+        contract_address: &str,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+
+        conn.interact(move |conn| {
+            conn.query_row(
+                "SELECT ledger_state FROM contract_address WHERE id = ?1",
+                [contract_address],
+                |row| row.get::<_, Option<Vec<u8>>>(0),
+            )
+            .optional()
+            .map(Option::flatten)
+            .context("Database access error")
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn is_contract_transaction_applied(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+    ) -> anyhow::Result<bool> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+        let tx_hash = tx_hash.to_string();
+
+        conn.interact(move |conn| {
+            conn.query_row(
+                "SELECT 1 FROM contract_transaction WHERE contract_address = ?1 AND tx_hash = ?2",
+                (contract_address, tx_hash),
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|row| row.is_some())
+            .context("Database access error")
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn set_contract_ledger_state(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+        state: Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+        let tx_hash = tx_hash.to_string();
+
+        conn.interact(move |conn| -> rusqlite::Result<bool> {
+            let db_tx = conn.transaction()?;
+
+            let inserted = db_tx.execute(
+                "INSERT INTO contract_transaction (contract_address, tx_hash) VALUES (?1, ?2)
+                ON CONFLICT DO NOTHING",
+                (&contract_address, &tx_hash),
+            )?;
+
+            if inserted == 0 {
+                return Ok(false);
+            }
+
+            db_tx.execute(
+                "UPDATE contract_address SET ledger_state = ?2 WHERE id = ?1",
+                (&contract_address, state),
+            )?;
+
+            db_tx.commit()?;
+
+            Ok(true)
+        })
+        .await
+        .unwrap()
+        .context("Db error persisting contract ledger state")
+    }
+
+    async fn insert_contract_state_change(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+        block_number: u64,
+        fields: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+        let tx_hash = tx_hash.to_string();
+
+        conn.interact(move |conn| {
+            conn.execute(
+                "INSERT INTO contract_state_history (contract_address, tx_hash, block_number, fields) VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (contract_address, tx_hash) DO NOTHING",
+                (contract_address, tx_hash, block_number, fields.to_string()),
+            )
+        })
+        .await
+        .unwrap()
+        .context("Db error persisting contract state change")?;
+
+        Ok(())
+    }
+
+    async fn get_contract_state_history(
+        &self,
+        contract_address: &str,
+        after: Option<i64>,
+        count: Option<u8>,
+    ) -> anyhow::Result<Vec<ContractStateChange>> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, tx_hash, block_number, fields FROM contract_state_history
+                WHERE
+                    contract_address = ?1 AND
+                    (?2 IS NULL OR id > ?2)
+                ORDER BY id
+                LIMIT ?3",
+            )?;
+
+            let rows = stmt
+                .query_map((contract_address, after, count.unwrap_or(10)), |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, u64>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                })
+                .context("Database error")?;
+
+            let mut res = vec![];
+            for row in rows {
+                let (id, tx_hash, block_number, fields) = row?;
+
+                res.push(ContractStateChange {
+                    id,
+                    tx_hash,
+                    block_number,
+                    fields: serde_json::from_str(&fields)
+                        .context("Invalid stored contract state change")?,
+                });
+            }
+
+            Ok(res)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn insert_lobby_event(
+        &self,
+        contract_address: &str,
+        tx_hash: &str,
+        kind: &str,
+        block_number: u64,
+        [game_state, p1_public_key, p2_public_key]: [String; 3],
+    ) -> anyhow::Result<Option<i64>> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+        let tx_hash = tx_hash.to_string();
+        let kind = kind.to_string();
+
+        conn.interact(move |conn| {
+            let inserted = conn.execute(
+                "INSERT INTO lobby_event (contract_address, tx_hash, kind, block_number, game_state, p1_public_key, p2_public_key) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT (contract_address, tx_hash, kind) DO NOTHING",
+                (contract_address, tx_hash, kind, block_number, game_state, p1_public_key, p2_public_key),
+            )?;
+
+            Ok((inserted > 0).then(|| conn.last_insert_rowid()))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_lobby_events(
+        &self,
+        from_block: u64,
+        player: Option<String>,
+        address: Option<String>,
+        after: Option<i64>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<(i64, String, String, u64, [String; 3])>> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, kind, contract_address, block_number, game_state, p1_public_key, p2_public_key FROM lobby_event
+                WHERE
+                    block_number >= ?1 AND
+                    (?2 IS NULL OR p1_public_key = ?2 OR p2_public_key = ('01;' || ?2)) AND
+                    (?3 IS NULL OR contract_address = ?3) AND
+                    (?4 IS NULL OR id > ?4)
+                ORDER BY id
+                LIMIT ?5",
+            )?;
+
+            let rows = stmt
+                .query_map((from_block, player, address, after, limit), |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        [row.get(4)?, row.get(5)?, row.get(6)?],
+                    ))
+                })
+                .context("Database error")?;
+
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_lobbies_waiting_for_p2(
+        &self,
+        filter: LobbyFilter,
+        exclude_player: Option<String>,
+        after: Option<LobbyCursor>,
+        count: u32,
+    ) -> anyhow::Result<(Vec<Lobby>, u64)> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(move |conn| {
+            query_lobbies(
+                conn,
+                "p2_public_key = '00;' AND (?6 IS NULL OR p1_public_key <> ?6)",
+                filter,
+                exclude_player,
+                after,
+                count,
+            )
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_player_lobbies(
+        &self,
+        public_key: String,
+        filter: LobbyFilter,
+        after: Option<LobbyCursor>,
+        count: u32,
+    ) -> anyhow::Result<(Vec<Lobby>, u64)> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(move |conn| {
+            query_lobbies(
+                conn,
+                "(p1_public_key = ?6 OR p2_public_key = ('01;' || ?6))",
+                filter,
+                Some(public_key),
+                after,
+                count,
+            )
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_lobby_cursor(&self, address: &str) -> anyhow::Result<Option<LobbyCursor>> {
+        let conn = self.pool.get().await.unwrap();
+
+        let address = address.to_string();
+
+        conn.interact(move |conn| {
+            conn.query_row(
+                "SELECT created_block, id FROM contract_address WHERE id = ?1",
+                [address],
+                |row| {
+                    Ok(LobbyCursor {
+                        created_block: row.get(0)?,
+                        address: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+            .context("Database error")
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn record_match(
+        &self,
+        contract_address: &str,
+        p1_public_key: &str,
+        p2_public_key: &str,
+        p1_result: &str,
+        block_number: u64,
+    ) -> anyhow::Result<bool> {
+        let conn = self.pool.get().await.unwrap();
+
+        let contract_address = contract_address.to_string();
+        let p1_public_key = p1_public_key.to_string();
+        let p2_public_key = p2_public_key.to_string();
+        let p1_result = p1_result.to_string();
+        let p2_result = match p1_result.as_str() {
+            "win" => "loss",
+            "loss" => "win",
+            result => result,
+        }
+        .to_string();
+
+        conn.interact(move |conn| {
+            let db_tx = conn.transaction()?;
+
+            let inserted = db_tx.execute(
+                "INSERT OR IGNORE INTO player_match (player, contract_address, opponent, result, block_number) VALUES (?1, ?2, ?3, ?4, ?5)",
+                (&p1_public_key, &contract_address, &p2_public_key, p1_result, block_number),
+            )?;
+
+            db_tx.execute(
+                "INSERT OR IGNORE INTO player_match (player, contract_address, opponent, result, block_number) VALUES (?1, ?2, ?3, ?4, ?5)",
+                (&p2_public_key, &contract_address, &p1_public_key, p2_result, block_number),
+            )?;
+
+            db_tx.commit()?;
+
+            Ok(inserted > 0)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_player_matches(&self, player: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let conn = self.pool.get().await.unwrap();
+
+        let player = player.to_string();
+
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT opponent, result FROM player_match WHERE player = ?1 ORDER BY block_number, seq",
+            )?;
+
+            let rows = stmt
+                .query_map([player], |row| Ok((row.get(0)?, row.get(1)?)))
+                .context("Database error")?;
+
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn complete_achievement(
+        &self,
+        player: &str,
+        name: &str,
+        block_number: u64,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let player = player.to_string();
+        let name = name.to_string();
+
+        conn.interact(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO achievement_completion (player, name, block_number) VALUES (?1, ?2, ?3)",
+                (player, name, block_number),
+            )
+        })
+        .await
+        .unwrap()
+        .context("Db error persisting achievement")?;
+
+        Ok(())
+    }
+
+    async fn get_completed_achievements(&self, player: &str) -> anyhow::Result<Vec<(String, u64)>> {
+        let conn = self.pool.get().await.unwrap();
+
+        let player = player.to_string();
+
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT name, block_number FROM achievement_completion WHERE player = ?1",
+            )?;
+
+            let rows = stmt
+                .query_map([player], |row| Ok((row.get(0)?, row.get(1)?)))
+                .context("Database error")?;
+
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_player_stats(
+        &self,
+        player: &str,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+    ) -> anyhow::Result<PlayerStats> {
+        let conn = self.pool.get().await.unwrap();
+
+        let player = player.to_string();
+
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT
+                    player,
+                    COUNT(*),
+                    SUM(CASE WHEN result = 'win' THEN 1 ELSE 0 END),
+                    SUM(CASE WHEN result = 'loss' THEN 1 ELSE 0 END),
+                    SUM(CASE WHEN result = 'draw' THEN 1 ELSE 0 END),
+                    max(block_number)
+                FROM player_match
+                WHERE
+                    player = ?1 AND
+                    (?2 IS NULL OR block_number >= ?2) AND
+                    (?3 IS NULL OR block_number <= ?3)
+                GROUP BY player",
+            )?;
+
+            let stats = stmt
+                .query_row((&player, from_block, to_block), player_stats_from_row)
+                .optional()
+                .context("Database error")?;
+
+            Ok(stats.unwrap_or(PlayerStats {
+                player,
+                ..Default::default()
+            }))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn get_leaderboard(
+        &self,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+        offset: u64,
+        count: u8,
+    ) -> anyhow::Result<Vec<PlayerStats>> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT
+                    player,
+                    COUNT(*),
+                    SUM(CASE WHEN result = 'win' THEN 1 ELSE 0 END),
+                    SUM(CASE WHEN result = 'loss' THEN 1 ELSE 0 END),
+                    SUM(CASE WHEN result = 'draw' THEN 1 ELSE 0 END),
+                    max(block_number)
+                FROM player_match
+                WHERE
+                    (?1 IS NULL OR block_number >= ?1) AND
+                    (?2 IS NULL OR block_number <= ?2)
+                GROUP BY player
+                ORDER BY 3 DESC, 5 DESC, 4 ASC, player
+                LIMIT ?4 OFFSET ?3",
+            )?;
+
+            let rows = stmt
+                .query_map((from_block, to_block, offset, count), player_stats_from_row)
+                .context("Database error")?;
+
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn last_contract_block(&self) -> anyhow::Result<Option<u64>> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(|conn| {
+            conn.query_row(
+                "SELECT max(block_number) FROM contract_address",
+                (),
+                |row| row.get(0),
+            )
+            .context("Database error")
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn insert_api_key(
+        &self,
+        name: &str,
+        key_hash: &str,
+        budget: Option<u64>,
+        requests_per_minute: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let name = name.to_string();
+        let key_hash = key_hash.to_string();
+
+        conn.interact(move |conn| {
+            conn.execute(
+                "INSERT INTO api_key (name, key_hash, budget, requests_per_minute) VALUES (?1, ?2, ?3, ?4)",
+                (name, key_hash, budget, requests_per_minute),
+            )
+        })
+        .await
+        .unwrap()
+        .context("Db error inserting api key")?;
+
+        Ok(())
+    }
+
+    async fn revoke_api_key(&self, name: &str) -> anyhow::Result<bool> {
+        let conn = self.pool.get().await.unwrap();
+
+        let name = name.to_string();
+
+        let deleted = conn
+            .interact(move |conn| conn.execute("DELETE FROM api_key WHERE name = ?1", [name]))
+            .await
+            .unwrap()?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn get_api_keys(&self) -> anyhow::Result<Vec<ApiKey>> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT name, budget, spent, requests_per_minute FROM api_key ORDER BY name",
+            )?;
+
+            let rows = stmt
+                .query_map((), |row| {
+                    Ok(ApiKey {
+                        name: row.get(0)?,
+                        budget: row.get(1)?,
+                        spent: row.get(2)?,
+                        requests_per_minute: row.get(3)?,
+                    })
+                })
+                .context("Database error")?;
+
+            let mut res = vec![];
+            for row in rows {
+                res.push(row?);
+            }
+
+            Ok(res)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn consume_api_key_request(
+        &self,
+        key_hash: &str,
+        now: u64,
+    ) -> anyhow::Result<ApiKeyRequest> {
+        let conn = self.pool.get().await.unwrap();
+
+        let key_hash = key_hash.to_string();
+
+        conn.interact(move |conn| {
+            // takes the write lock up front, so that concurrent requests wait
+            // for each other instead of failing to upgrade their read lock.
+            let db_tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let row = db_tx
+                .query_row(
+                    "SELECT requests_per_minute, window_start, window_requests FROM api_key WHERE key_hash = ?1",
+                    [&key_hash],
+                    |row| {
+                        Ok((
+                            row.get::<_, Option<u32>>(0)?,
+                            row.get::<_, u64>(1)?,
+                            row.get::<_, u32>(2)?,
+                        ))
+                    },
+                )
+                .optional()
+                .context("Database access error")?;
+
+            let Some((requests_per_minute, window_start, window_requests)) = row else {
+                return Ok(ApiKeyRequest::Unknown);
+            };
+
+            let (window_start, window_requests) = if now >= window_start + 60 {
+                (now, 0)
+            } else {
+                (window_start, window_requests)
+            };
+
+            if let Some(limit) = requests_per_minute {
+                if window_requests >= limit {
+                    return Ok(ApiKeyRequest::RateLimited {
+                        retry_after: window_start + 60 - now,
+                    });
+                }
+            }
+
+            db_tx.execute(
+                "UPDATE api_key SET window_start = ?2, window_requests = ?3 WHERE key_hash = ?1",
+                (&key_hash, window_start, window_requests + 1),
+            )?;
+
+            db_tx.commit()?;
+
+            Ok(ApiKeyRequest::Allowed)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn reserve_api_key_budget(&self, key_hash: &str, amount: u64) -> anyhow::Result<bool> {
+        let conn = self.pool.get().await.unwrap();
+
+        let key_hash = key_hash.to_string();
+
+        let updated = conn
+            .interact(move |conn| {
+                conn.execute(
+                    "UPDATE api_key SET spent = spent + ?2
+                    WHERE key_hash = ?1 AND (budget IS NULL OR spent + ?2 <= budget)",
+                    (key_hash, amount),
+                )
+            })
+            .await
+            .unwrap()?;
+
+        Ok(updated > 0)
+    }
+
+    async fn release_api_key_budget(&self, key_hash: &str, amount: u64) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        let key_hash = key_hash.to_string();
+
+        conn.interact(move |conn| {
+            conn.execute(
+                "UPDATE api_key SET spent = max(spent - ?2, 0) WHERE key_hash = ?1",
+                (key_hash, amount),
+            )
+        })
+        .await
+        .unwrap()?;
+
+        Ok(())
+    }
+
+    async fn consume_player_request(
+        &self,
+        player: &str,
+        now: u64,
+        requests_per_minute: u32,
+    ) -> anyhow::Result<Option<u64>> {
+        let conn = self.pool.get().await.unwrap();
+
+        let player = player.to_string();
+
+        conn.interact(move |conn| {
+            let db_tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            db_tx.execute(
+                "DELETE FROM player_request WHERE player = ?1 AND timestamp + 60 <= ?2",
+                (&player, now),
+            )?;
+
+            let (count, oldest) = db_tx
+                .query_row(
+                    "SELECT COUNT(*), min(timestamp) FROM player_request WHERE player = ?1",
+                    [&player],
+                    |row| Ok((row.get::<_, u32>(0)?, row.get::<_, Option<u64>>(1)?)),
+                )
+                .context("Database access error")?;
+
+            if count >= requests_per_minute {
+                return Ok(Some(oldest.map(|oldest| oldest + 60 - now).unwrap_or(60)));
+            }
+
+            db_tx.execute(
+                "INSERT INTO player_request (player, timestamp) VALUES (?1, ?2)",
+                (&player, now),
+            )?;
+
+            db_tx.commit()?;
+
+            Ok(None)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn reserve_player_fee(
+        &self,
+        player: &str,
+        amount: u64,
+        daily_budget: u64,
+        now: u64,
+    ) -> anyhow::Result<PlayerFeeReservation> {
+        const DAY: u64 = 24 * 60 * 60;
+
+        let conn = self.pool.get().await.unwrap();
+
+        let player = player.to_string();
+
+        conn.interact(move |conn| {
+            let db_tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            db_tx.execute(
+                "DELETE FROM player_fee WHERE player = ?1 AND timestamp + ?2 <= ?3",
+                (&player, DAY, now),
+            )?;
+
+            let (spent, oldest) = db_tx
+                .query_row(
+                    "SELECT COALESCE(SUM(amount), 0), min(timestamp) FROM player_fee WHERE player = ?1",
+                    [&player],
+                    |row| Ok((row.get::<_, u64>(0)?, row.get::<_, Option<u64>>(1)?)),
+                )
+                .context("Database access error")?;
+
+            if spent.saturating_add(amount) > daily_budget {
+                return Ok(PlayerFeeReservation::Exceeded {
+                    retry_after: oldest.map(|oldest| oldest + DAY - now).unwrap_or(DAY),
+                });
+            }
+
+            db_tx.execute(
+                "INSERT INTO player_fee (player, timestamp, amount) VALUES (?1, ?2, ?3)",
+                (&player, now, amount),
+            )?;
+
+            let id = db_tx.last_insert_rowid();
+
+            db_tx.commit()?;
+
+            Ok(PlayerFeeReservation::Reserved(id))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn release_player_fee(&self, id: i64) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(move |conn| conn.execute("DELETE FROM player_fee WHERE id = ?1", [id]))
+            .await
+            .unwrap()?;
+
+        Ok(())
+    }
+
+    async fn prune_player_usage(&self, now: u64) -> anyhow::Result<()> {
+        const DAY: u64 = 24 * 60 * 60;
+
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(move |conn| -> rusqlite::Result<()> {
+            conn.execute(
+                "DELETE FROM player_request WHERE timestamp + 60 <= ?1",
+                [now],
+            )?;
+            conn.execute(
+                "DELETE FROM player_fee WHERE timestamp + ?1 <= ?2",
+                (DAY, now),
+            )?;
+
+            Ok(())
+        })
+        .await
+        .unwrap()?;
+
+        Ok(())
+    }
+
+    async fn migrate(&self) -> anyhow::Result<()> {
+        let conn = self.pool.get().await.unwrap();
+
+        conn.interact(|conn| -> anyhow::Result<()> {
+            let db_tx = conn.transaction()?;
+
+            let version: usize = db_tx.query_row("PRAGMA user_version", (), |row| row.get(0))?;
+
+            if version > MIGRATIONS.len() {
+                anyhow::bail!(
+                    "database schema version {} is newer than the latest supported version {}",
+                    version,
+                    MIGRATIONS.len()
+                );
+            }
+
+            for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+                tracing::info!("applying database migration {}", i + 1);
+
+                db_tx
+                    .execute_batch(migration)
+                    .context(format!("Failed to apply database migration {}", i + 1))?;
+            }
+
+            db_tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+
+            db_tx.commit()?;
+
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+}
+
+/// Constraints of [`LobbyFilter`], over the parameters ?1 to ?5.
+const LOBBY_FILTER: &str = "
+    (?1 IS NULL OR game_state = ?1) AND
+    (?2 IS NULL OR block_number >= ?2) AND
+    (?3 IS NULL OR block_number <= ?3) AND
+    (?4 IS NULL OR created_block >= ?4) AND
+    (?5 IS NULL OR created_block <= ?5)";
+
+/// Pages through the lobbies selected by `condition`, which can use `param`
+/// as ?6.
+fn query_lobbies(
+    conn: &rusqlite::Connection,
+    condition: &str,
+    filter: LobbyFilter,
+    param: Option<String>,
+    after: Option<LobbyCursor>,
+    count: u32,
+) -> anyhow::Result<(Vec<Lobby>, u64)> {
+    let total = conn
+        .query_row(
+            &format!("SELECT COUNT(*) FROM contract_address WHERE {condition} AND {LOBBY_FILTER}"),
+            (
+                &filter.game_state,
+                filter.from_block,
+                filter.to_block,
+                filter.created_from_block,
+                filter.created_to_block,
+                &param,
+            ),
+            |row| row.get(0),
+        )
+        .context("Database error")?;
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, game_state, block_number, created_block, p1_public_key, p2_public_key FROM contract_address
+        WHERE
+            {condition} AND {LOBBY_FILTER} AND
+            (?7 IS NULL OR (created_block, id) < (?7, ?8))
+        ORDER BY created_block DESC, id DESC
+        LIMIT ?9"
+    ))?;
+
+    let rows = stmt
+        .query_map(
+            (
+                &filter.game_state,
+                filter.from_block,
+                filter.to_block,
+                filter.created_from_block,
+                filter.created_to_block,
+                &param,
+                after.as_ref().map(|cursor| cursor.created_block),
+                after.as_ref().map(|cursor| &cursor.address),
+                count,
+            ),
+            |row| {
+                Ok(Lobby {
+                    address: row.get(0)?,
+                    game_state: row.get(1)?,
+                    block_number: row.get(2)?,
+                    created_block: row.get(3)?,
+                    p1_public_key: row.get(4)?,
+                    p2_public_key: row.get(5)?,
+                })
+            },
+        )
+        .context("Database error")?;
+
+    Ok((rows.collect::<Result<Vec<_>, _>>()?, total))
+}
+
+fn player_stats_from_row(row: &rusqlite::Row) -> rusqlite::Result<PlayerStats> {
+    Ok(PlayerStats {
+        player: row.get(0)?,
+        games_played: row.get(1)?,
+        wins: row.get(2)?,
+        losses: row.get(3)?,
+        draws: row.get(4)?,
+        last_played_block: row.get(5)?,
+    })
+}
+
+/// Ordered schema migrations, the schema version stored in `user_version` is
+/// the number of migrations applied. Never edit an existing entry, append a
+/// new one instead.
+///
+/// The first ones use `IF NOT EXISTS` because they may have been created by
+/// versions before migrations were tracked.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema. The lobbies indexed before the migrations have the
+    // network id byte in their address, see `addresses`.
+    "CREATE TABLE IF NOT EXISTS state (
+        id TEXT PRIMARY KEY,
+        hash TEXT NOT NULL,
+        state BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS contract_address (
+        id TEXT PRIMARY KEY,
+        game_state TEXT,
+        p1_public_key TEXT,
+        p2_public_key TEXT,
+        block_number INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS idx_p1_public_key ON contract_address (p1_public_key);
+    CREATE INDEX IF NOT EXISTS idx_p2_public_key ON contract_address (p2_public_key);
+    UPDATE contract_address SET id = substr(id, 3) WHERE length(id) = 66;",
+    // 2: api keys
+    "CREATE TABLE IF NOT EXISTS api_key (
+        name TEXT PRIMARY KEY,
+        key_hash TEXT NOT NULL UNIQUE,
+        budget INTEGER,
+        spent INTEGER NOT NULL DEFAULT 0,
+        requests_per_minute INTEGER,
+        window_start INTEGER NOT NULL DEFAULT 0,
+        window_requests INTEGER NOT NULL DEFAULT 0
+    );",
+    // 3: per player limits
+    "CREATE TABLE IF NOT EXISTS player_request (
+        player TEXT NOT NULL,
+        timestamp INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS player_fee (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        player TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        amount INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_player_request ON player_request (player, timestamp);
+    CREATE INDEX IF NOT EXISTS idx_player_fee ON player_fee (player, timestamp);",
+    // 4: public keys of the wallet state
+    "CREATE TABLE IF NOT EXISTS state_public_keys (
+        id TEXT PRIMARY KEY,
+        public_keys TEXT NOT NULL
+    );",
+    // 5: contract fields decoded with the contract schema
+    "CREATE TABLE IF NOT EXISTS contract_state (
+        contract_address TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        block_number INTEGER NOT NULL,
+        PRIMARY KEY (contract_address, key)
+    );",
+    // 6: whole contract state as json
+    "ALTER TABLE contract_address ADD COLUMN state_json TEXT;
+    ALTER TABLE contract_address ADD COLUMN state_block_number INTEGER;",
+    // 7: log of the decoded contract fields, one entry per transaction
+    "CREATE TABLE IF NOT EXISTS contract_state_history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        contract_address TEXT NOT NULL,
+        tx_hash TEXT NOT NULL,
+        block_number INTEGER NOT NULL,
+        fields TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_contract_state_history ON contract_state_history (contract_address, id);
+    CREATE UNIQUE INDEX IF NOT EXISTS idx_contract_state_history_tx ON contract_state_history (contract_address, tx_hash);",
+    // 8: local copy of the ledger state of the contracts, and the transactions
+    // included in it, so that replays skip them
+    "ALTER TABLE contract_address ADD COLUMN ledger_state BLOB;
+    CREATE TABLE IF NOT EXISTS contract_transaction (
+        contract_address TEXT NOT NULL,
+        tx_hash TEXT NOT NULL,
+        PRIMARY KEY (contract_address, tx_hash)
+    );",
+    // 9: lobby events, for clients resuming their event stream, once per
+    // transaction so that replays don't repeat them
+    "CREATE TABLE IF NOT EXISTS lobby_event (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        contract_address TEXT NOT NULL,
+        tx_hash TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        block_number INTEGER NOT NULL,
+        game_state TEXT NOT NULL,
+        p1_public_key TEXT NOT NULL,
+        p2_public_key TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_lobby_event_block ON lobby_event (block_number);
+    CREATE UNIQUE INDEX IF NOT EXISTS idx_lobby_event_tx ON lobby_event (contract_address, tx_hash, kind);",
+    // 10: finished matches and achievements
+    "CREATE TABLE IF NOT EXISTS player_match (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        player TEXT NOT NULL,
+        contract_address TEXT NOT NULL,
+        opponent TEXT NOT NULL,
+        result TEXT NOT NULL,
+        block_number INTEGER NOT NULL,
+        UNIQUE (player, contract_address)
+    );
+    CREATE INDEX IF NOT EXISTS idx_player_match_block ON player_match (block_number);
+    CREATE TABLE IF NOT EXISTS achievement_completion (
+        player TEXT NOT NULL,
+        name TEXT NOT NULL,
+        block_number INTEGER NOT NULL,
+        PRIMARY KEY (player, name)
+    );",
+    // 11: creation block of the lobbies, for stable pagination
+    "ALTER TABLE contract_address ADD COLUMN created_block INTEGER;
+    UPDATE contract_address SET created_block = COALESCE(
+        (SELECT min(block_number) FROM contract_state_history WHERE contract_address = contract_address.id),
+        block_number
+    );
+    CREATE INDEX IF NOT EXISTS idx_contract_address_created ON contract_address (created_block, id);",
+];
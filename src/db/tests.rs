@@ -0,0 +1,774 @@
+//! The same suite runs against every backend. The postgres one needs a
+//! server, given by the `BATCHER_TEST_POSTGRES_URL` environment variable, and
+//! is skipped without it.
+
+use super::{
+    sqlite::SqliteDb, ApiKeyRequest, Backend, Db, LobbyCursor, LobbyFilter, NetworkId,
+    PlayerFeeReservation, PlayerStats, ENCRYPTED_STATE_MAGIC,
+};
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Access to the raw tables, for checking what the backend methods don't
+/// return.
+#[async_trait::async_trait]
+trait RawTables {
+    async fn count_rows(&self, table: &str) -> u64;
+
+    /// The tables and their columns.
+    async fn columns(&self) -> BTreeSet<(String, String)>;
+}
+
+#[async_trait::async_trait]
+impl RawTables for SqliteDb {
+    async fn count_rows(&self, table: &str) -> u64 {
+        let query = format!("SELECT COUNT(*) FROM {}", table);
+
+        self.pool
+            .get()
+            .await
+            .unwrap()
+            .interact(move |conn| conn.query_row(&query, [], |row| row.get(0)))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn columns(&self) -> BTreeSet<(String, String)> {
+        self.pool
+            .get()
+            .await
+            .unwrap()
+            .interact(|conn| {
+                conn.prepare(
+                    "SELECT m.name, c.name FROM sqlite_master m, pragma_table_info(m.name) c
+                    WHERE m.type = 'table' AND m.name NOT LIKE 'sqlite_%' AND m.name <> 'schema_version'",
+                )?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()
+            })
+            .await
+            .unwrap()
+            .unwrap()
+    }
+}
+
+/// The tables created by the versions before the migrations, which left the
+/// sqlite schema version at 0.
+const BASELINE_SCHEMA: &str = "CREATE TABLE state (
+        id TEXT PRIMARY KEY,
+        hash TEXT NOT NULL,
+        state BLOB NOT NULL
+    );
+    CREATE TABLE contract_address (
+        id TEXT PRIMARY KEY,
+        game_state TEXT,
+        p1_public_key TEXT,
+        p2_public_key TEXT,
+        block_number INTEGER
+    );
+    CREATE INDEX idx_p1_public_key ON contract_address (p1_public_key);
+    CREATE INDEX idx_p2_public_key ON contract_address (p2_public_key);";
+
+/// A fresh in-memory database, shared by the connections of the pool.
+async fn sqlite() -> SqliteDb {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let db = SqliteDb::open(format!(
+        "file:batcher-test-{}?mode=memory&cache=shared",
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ))
+    .unwrap();
+
+    db.migrate().await.unwrap();
+
+    db
+}
+
+async fn user_version(db: &SqliteDb) -> usize {
+    db.pool
+        .get()
+        .await
+        .unwrap()
+        .interact(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get(0)))
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn baseline_databases_are_migrated() {
+    let db = SqliteDb::open("file:batcher-test-baseline?mode=memory&cache=shared").unwrap();
+
+    db.pool
+        .get()
+        .await
+        .unwrap()
+        .interact(|conn| {
+            conn.execute_batch(BASELINE_SCHEMA)?;
+            conn.execute(
+                "INSERT INTO state (id, hash, state) VALUES ('committed', 'hash', x'010203')",
+                [],
+            )
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+    db.migrate().await.unwrap();
+
+    let fresh = sqlite().await;
+
+    assert_eq!(
+        db.get_state_blob("committed").await.unwrap(),
+        Some(("hash".to_string(), vec![1, 2, 3]))
+    );
+    assert_eq!(db.columns().await, fresh.columns().await);
+    assert_eq!(user_version(&db).await, user_version(&fresh).await);
+}
+
+#[tokio::test]
+async fn newer_schemas_are_rejected() {
+    let db = sqlite().await;
+    let version = user_version(&db).await;
+
+    db.pool
+        .get()
+        .await
+        .unwrap()
+        .interact(move |conn| conn.pragma_update(None, "user_version", version + 1))
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(db.migrate().await.is_err());
+}
+
+#[tokio::test]
+async fn stored_addresses_lose_the_network_prefix() {
+    let db = SqliteDb::open("file:batcher-test-prefix?mode=memory&cache=shared").unwrap();
+
+    let address = "ab".repeat(32);
+    let prefixed = format!("02{}", address);
+
+    db.pool
+        .get()
+        .await
+        .unwrap()
+        .interact(move |conn| {
+            conn.execute_batch(BASELINE_SCHEMA)?;
+
+            conn.execute(
+                "INSERT INTO contract_address (id, game_state, p1_public_key, p2_public_key, block_number)
+                VALUES (?1, '00', 'p1', '00;', 1)",
+                [&prefixed],
+            )
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+    db.migrate().await.unwrap();
+
+    assert_eq!(
+        db.get_lobby_cursor(&address)
+            .await
+            .unwrap()
+            .unwrap()
+            .address,
+        address
+    );
+}
+
+/// A handle to `backend`, encrypting the states with the key of `seed`.
+fn db(backend: &Arc<SqliteDb>, seed: Option<[u8; 32]>) -> Db {
+    let db = Db {
+        backend: Arc::clone(backend) as Arc<dyn Backend>,
+        network_id: NetworkId::Undeployed,
+        state_cipher: None,
+    };
+
+    match seed {
+        Some(seed) => db.with_state_encryption(&seed),
+        None => db,
+    }
+}
+
+#[tokio::test]
+async fn states_are_only_decrypted_with_their_seed() {
+    let backend = Arc::new(sqlite().await);
+    let db_1 = db(&backend, Some([1; 32]));
+
+    let blob = db_1.encrypt_state(b"wallet state".to_vec()).unwrap();
+
+    assert!(blob.starts_with(ENCRYPTED_STATE_MAGIC));
+    assert!(!blob.windows(12).any(|window| window == b"wallet state"));
+    assert_eq!(db_1.decrypt_state(blob.clone()).unwrap(), b"wallet state");
+
+    assert!(db(&backend, Some([2; 32]))
+        .decrypt_state(blob.clone())
+        .is_err());
+    assert!(db(&backend, None).decrypt_state(blob).is_err());
+}
+
+#[tokio::test]
+async fn plaintext_states_are_encrypted() {
+    let backend = Arc::new(sqlite().await);
+    let db = db(&backend, Some([1; 32]));
+
+    backend
+        .put_state_blob("committed", "hash", b"wallet state".to_vec())
+        .await
+        .unwrap();
+
+    db.encrypt_plaintext_states().await.unwrap();
+
+    let (_, blob) = backend.get_state_blob("committed").await.unwrap().unwrap();
+
+    assert!(blob.starts_with(ENCRYPTED_STATE_MAGIC));
+    assert_eq!(db.decrypt_state(blob).unwrap(), b"wallet state");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn concurrent_api_key_requests_are_all_counted() {
+    // a file, since the shared in-memory databases lock tables instead of the
+    // whole database.
+    let path = std::env::temp_dir().join(format!(
+        "batcher-test-concurrent-{}.sqlite",
+        std::process::id()
+    ));
+    let db = Arc::new(SqliteDb::open(&path).unwrap());
+
+    db.migrate().await.unwrap();
+    db.insert_api_key("game", "hash", None, Some(100))
+        .await
+        .unwrap();
+
+    let requests = (0..200)
+        .map(|_| {
+            let db = Arc::clone(&db);
+            tokio::spawn(async move { db.consume_api_key_request("hash", 1000).await })
+        })
+        .collect::<Vec<_>>();
+
+    let mut allowed = 0;
+    for request in requests {
+        if let ApiKeyRequest::Allowed = request.await.unwrap().unwrap() {
+            allowed += 1;
+        }
+    }
+
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(allowed, 100);
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use super::RawTables;
+    use crate::db::{postgres::PostgresDb, Backend};
+    use std::{
+        collections::BTreeSet,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    const URL_ENV: &str = "BATCHER_TEST_POSTGRES_URL";
+
+    /// A database in its own schema, dropped by [`TestDb::drop`].
+    pub struct TestDb {
+        pub db: PostgresDb,
+        url: String,
+        schema: String,
+    }
+
+    #[async_trait::async_trait]
+    impl RawTables for PostgresDb {
+        async fn count_rows(&self, table: &str) -> u64 {
+            let conn = self.pool.get().await.unwrap();
+
+            let row = conn
+                .query_one(&format!("SELECT COUNT(*) FROM {}", table), &[])
+                .await
+                .unwrap();
+
+            row.get::<_, i64>(0) as u64
+        }
+
+        async fn columns(&self) -> BTreeSet<(String, String)> {
+            let conn = self.pool.get().await.unwrap();
+
+            conn.query(
+                "SELECT table_name::TEXT, column_name::TEXT FROM information_schema.columns
+                WHERE table_schema = current_schema() AND table_name <> 'schema_version'",
+                &[],
+            )
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect()
+        }
+    }
+
+    async fn execute(url: &str, statement: &str) {
+        let (client, connection) = tokio_postgres::connect(url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+
+        tokio::spawn(connection);
+
+        client.batch_execute(statement).await.unwrap();
+    }
+
+    pub async fn open() -> Option<TestDb> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let Ok(url) = std::env::var(URL_ENV) else {
+            eprintln!("{} is not set, skipping the postgres test", URL_ENV);
+            return None;
+        };
+
+        let schema = format!(
+            "batcher_test_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        );
+
+        execute(&url, &format!("CREATE SCHEMA {}", schema)).await;
+
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let db = PostgresDb::open(&format!(
+            "{}{}options=-csearch_path%3D{}",
+            url, separator, schema
+        ))
+        .unwrap();
+
+        db.migrate().await.unwrap();
+
+        Some(TestDb { db, url, schema })
+    }
+
+    impl TestDb {
+        pub async fn drop(self) {
+            execute(&self.url, &format!("DROP SCHEMA {} CASCADE", self.schema)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn tables_match_sqlite() {
+        let Some(db) = open().await else {
+            return;
+        };
+
+        assert_eq!(db.db.columns().await, super::sqlite().await.columns().await);
+
+        db.drop().await;
+    }
+}
+
+/// Runs each test of the suite against every backend.
+macro_rules! backend_tests {
+    ($($test:ident),* $(,)?) => {
+        mod sqlite_backend {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    super::$test(&super::sqlite().await).await;
+                }
+            )*
+        }
+
+        #[cfg(feature = "postgres")]
+        mod postgres_backend {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    let Some(db) = super::postgres::open().await else {
+                        return;
+                    };
+
+                    super::$test(&db.db).await;
+
+                    db.drop().await;
+                }
+            )*
+        }
+    };
+}
+
+backend_tests!(
+    api_key_rate_limit_uses_fixed_windows,
+    api_key_budget_is_reserved_and_released,
+    player_requests_use_a_rolling_window,
+    player_fees_are_reserved_and_released,
+    player_usage_out_of_the_window_is_pruned,
+    migrations_are_idempotent,
+    lobbies_are_paged_newest_first,
+    lobby_cursor_is_found_by_address,
+    matches_are_counted_once,
+    leaderboard_is_sorted_by_wins_then_draws_then_losses,
+    replayed_state_changes_are_recorded_once,
+    replayed_contract_transactions_are_applied_once,
+    replayed_lobby_events_are_stored_once,
+);
+
+async fn api_key_rate_limit_uses_fixed_windows(db: &impl Backend) {
+    db.insert_api_key("game", "hash", None, Some(2))
+        .await
+        .unwrap();
+
+    for now in [1000, 1010] {
+        assert!(matches!(
+            db.consume_api_key_request("hash", now).await.unwrap(),
+            ApiKeyRequest::Allowed
+        ));
+    }
+
+    assert!(matches!(
+        db.consume_api_key_request("hash", 1020).await.unwrap(),
+        ApiKeyRequest::RateLimited { retry_after: 40 }
+    ));
+
+    assert!(matches!(
+        db.consume_api_key_request("hash", 1060).await.unwrap(),
+        ApiKeyRequest::Allowed
+    ));
+
+    assert!(matches!(
+        db.consume_api_key_request("unknown", 1060).await.unwrap(),
+        ApiKeyRequest::Unknown
+    ));
+}
+
+async fn api_key_budget_is_reserved_and_released(db: &impl Backend) {
+    db.insert_api_key("game", "hash", Some(100), None)
+        .await
+        .unwrap();
+
+    assert!(db.reserve_api_key_budget("hash", 60).await.unwrap());
+    assert!(!db.reserve_api_key_budget("hash", 60).await.unwrap());
+
+    db.release_api_key_budget("hash", 60).await.unwrap();
+
+    assert!(db.reserve_api_key_budget("hash", 100).await.unwrap());
+    assert_eq!(db.get_api_keys().await.unwrap()[0].spent, 100);
+
+    // releasing more than was spent doesn't underflow.
+    db.release_api_key_budget("hash", 1000).await.unwrap();
+    assert_eq!(db.get_api_keys().await.unwrap()[0].spent, 0);
+}
+
+async fn player_requests_use_a_rolling_window(db: &impl Backend) {
+    assert_eq!(db.consume_player_request("p", 1000, 2).await.unwrap(), None);
+    assert_eq!(db.consume_player_request("p", 1030, 2).await.unwrap(), None);
+    assert_eq!(
+        db.consume_player_request("p", 1040, 2).await.unwrap(),
+        Some(20)
+    );
+
+    // other players have their own window.
+    assert_eq!(db.consume_player_request("q", 1040, 2).await.unwrap(), None);
+
+    assert_eq!(db.consume_player_request("p", 1060, 2).await.unwrap(), None);
+}
+
+const DAY: u64 = 24 * 60 * 60;
+
+async fn player_fees_are_reserved_and_released(db: &impl Backend) {
+    let PlayerFeeReservation::Reserved(first) =
+        db.reserve_player_fee("p", 60, 100, 1000).await.unwrap()
+    else {
+        panic!("the budget covers the first fee");
+    };
+
+    assert!(matches!(
+        db.reserve_player_fee("p", 60, 100, 2000).await.unwrap(),
+        PlayerFeeReservation::Exceeded { retry_after } if retry_after == DAY - 1000
+    ));
+
+    db.release_player_fee(first).await.unwrap();
+
+    assert!(matches!(
+        db.reserve_player_fee("p", 60, 100, 2000).await.unwrap(),
+        PlayerFeeReservation::Reserved(_)
+    ));
+
+    assert!(matches!(
+        db.reserve_player_fee("p", 60, 100, 2000 + DAY)
+            .await
+            .unwrap(),
+        PlayerFeeReservation::Reserved(_)
+    ));
+}
+
+async fn player_usage_out_of_the_window_is_pruned(db: &(impl Backend + RawTables)) {
+    assert_eq!(db.consume_player_request("p", 1000, 1).await.unwrap(), None);
+    assert!(matches!(
+        db.reserve_player_fee("p", 100, 100, 1000).await.unwrap(),
+        PlayerFeeReservation::Reserved(_)
+    ));
+
+    db.prune_player_usage(1059).await.unwrap();
+    assert_eq!(db.count_rows("player_request").await, 1);
+
+    db.prune_player_usage(1060).await.unwrap();
+    assert_eq!(db.count_rows("player_request").await, 0);
+    assert_eq!(db.count_rows("player_fee").await, 1);
+
+    db.prune_player_usage(1000 + DAY).await.unwrap();
+    assert_eq!(db.count_rows("player_fee").await, 0);
+}
+
+async fn migrations_are_idempotent(db: &(impl Backend + RawTables)) {
+    db.record_match("c", "p1", "p2", "win", 10).await.unwrap();
+
+    db.migrate().await.unwrap();
+
+    assert_eq!(
+        db.get_player_matches("p1").await.unwrap(),
+        vec![("p2".to_string(), "win".to_string())]
+    );
+    assert_eq!(db.count_rows("player_match").await, 2);
+}
+
+async fn lobbies_are_paged_newest_first(db: &impl Backend) {
+    for (address, block) in [("a", 1), ("b", 2), ("c", 2)] {
+        db.insert_contract_address(address, block).await.unwrap();
+        db.update_contract_state(address, "00", "p1", "00;", block)
+            .await
+            .unwrap();
+    }
+
+    db.update_contract_state("a", "01", "p1", "01;p2", 3)
+        .await
+        .unwrap();
+
+    let (page, total) = db
+        .get_lobbies_waiting_for_p2(LobbyFilter::default(), None, None, 1)
+        .await
+        .unwrap();
+
+    assert_eq!(total, 2);
+    assert_eq!(page[0].address, "c");
+
+    let (page, _) = db
+        .get_lobbies_waiting_for_p2(
+            LobbyFilter::default(),
+            None,
+            Some(LobbyCursor {
+                created_block: page[0].created_block,
+                address: page[0].address.clone(),
+            }),
+            10,
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        page.iter()
+            .map(|lobby| &lobby.address[..])
+            .collect::<Vec<_>>(),
+        ["b"]
+    );
+
+    let (page, total) = db
+        .get_player_lobbies("p2".to_string(), LobbyFilter::default(), None, 10)
+        .await
+        .unwrap();
+
+    assert_eq!(total, 1);
+    assert_eq!(page[0].address, "a");
+    assert_eq!(page[0].block_number, 3);
+    assert_eq!(page[0].created_block, 1);
+}
+
+async fn lobby_cursor_is_found_by_address(db: &impl Backend) {
+    db.insert_contract_address("aa", 5).await.unwrap();
+
+    let cursor = db.get_lobby_cursor("aa").await.unwrap().unwrap();
+
+    assert_eq!(cursor.created_block, 5);
+    assert_eq!(cursor.address, "aa");
+
+    assert!(db.get_lobby_cursor("bb").await.unwrap().is_none());
+}
+
+async fn matches_are_counted_once(db: &impl Backend) {
+    assert!(db.record_match("a", "p1", "p2", "win", 10).await.unwrap());
+    assert!(!db.record_match("a", "p1", "p2", "win", 11).await.unwrap());
+    assert!(db.record_match("b", "p2", "p1", "draw", 12).await.unwrap());
+
+    let stats = db.get_player_stats("p1", None, None).await.unwrap();
+
+    assert_eq!(
+        (stats.games_played, stats.wins, stats.losses, stats.draws),
+        (2, 1, 0, 1)
+    );
+    assert_eq!(stats.last_played_block, Some(12));
+
+    let leaderboard = db.get_leaderboard(None, None, 0, 10).await.unwrap();
+
+    assert_eq!(
+        leaderboard
+            .iter()
+            .map(|stats| &stats.player[..])
+            .collect::<Vec<_>>(),
+        ["p1", "p2"]
+    );
+
+    assert_eq!(
+        db.get_player_matches("p2").await.unwrap(),
+        vec![
+            ("p1".to_string(), "loss".to_string()),
+            ("p1".to_string(), "draw".to_string())
+        ]
+    );
+}
+
+async fn replayed_state_changes_are_recorded_once(db: &impl Backend) {
+    for (tx_hash, block) in [("t1", 1), ("t2", 2), ("t1", 1)] {
+        db.insert_contract_state_change("c", tx_hash, block, serde_json::json!({ "tx": tx_hash }))
+            .await
+            .unwrap();
+    }
+
+    let history = db
+        .get_contract_state_history("c", None, None)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        history
+            .iter()
+            .map(|change| &change.tx_hash[..])
+            .collect::<Vec<_>>(),
+        ["t1", "t2"]
+    );
+}
+
+async fn replayed_contract_transactions_are_applied_once(db: &impl Backend) {
+    db.insert_contract_address("c", 1).await.unwrap();
+
+    assert!(!db.is_contract_transaction_applied("c", "t1").await.unwrap());
+    assert!(db
+        .set_contract_ledger_state("c", "t1", vec![1])
+        .await
+        .unwrap());
+    assert!(db
+        .set_contract_ledger_state("c", "t2", vec![2])
+        .await
+        .unwrap());
+
+    // the indexer resumes from an older transaction after a restart.
+    assert!(db.is_contract_transaction_applied("c", "t1").await.unwrap());
+    assert!(!db
+        .set_contract_ledger_state("c", "t1", vec![3])
+        .await
+        .unwrap());
+
+    assert_eq!(
+        db.get_contract_ledger_state("c").await.unwrap(),
+        Some(vec![2])
+    );
+
+    db.clear_contracts().await.unwrap();
+    assert!(!db.is_contract_transaction_applied("c", "t1").await.unwrap());
+}
+
+async fn replayed_lobby_events_are_stored_once(db: &impl Backend) {
+    let columns = || ["0".to_string(), "p1".to_string(), "".to_string()];
+
+    let first = db
+        .insert_lobby_event("c", "t1", "new_lobby", 1, columns())
+        .await
+        .unwrap();
+    assert!(first.is_some());
+
+    // the same transaction can cause events of several kinds.
+    assert!(db
+        .insert_lobby_event("c", "t1", "state_changed", 1, columns())
+        .await
+        .unwrap()
+        .is_some());
+    assert!(db
+        .insert_lobby_event("c", "t1", "new_lobby", 1, columns())
+        .await
+        .unwrap()
+        .is_none());
+
+    for (block, tx_hash) in [(2, "t2"), (3, "t3")] {
+        db.insert_lobby_event("c", tx_hash, "state_changed", block, columns())
+            .await
+            .unwrap();
+    }
+
+    let ids = |events: Vec<(i64, String, String, u64, [String; 3])>| {
+        events.into_iter().map(|event| event.0).collect::<Vec<_>>()
+    };
+
+    let all = ids(db.get_lobby_events(0, None, None, None, 10).await.unwrap());
+    assert_eq!(all.len(), 4);
+    assert_eq!(all[0], first.unwrap());
+
+    let first_page = ids(db.get_lobby_events(0, None, None, None, 3).await.unwrap());
+    let second_page = ids(db
+        .get_lobby_events(0, None, None, first_page.last().copied(), 3)
+        .await
+        .unwrap());
+    assert_eq!([first_page, second_page].concat(), all);
+
+    assert_eq!(
+        ids(db
+            .get_lobby_events(3, Some("p1".to_string()), None, None, 10)
+            .await
+            .unwrap()),
+        all[3..]
+    );
+}
+
+async fn leaderboard_is_sorted_by_wins_then_draws_then_losses(db: &impl Backend) {
+    for (block, (player, result)) in [
+        ("b", "win"),
+        ("b", "win"),
+        ("b", "draw"),
+        ("c", "win"),
+        ("c", "win"),
+        ("a", "win"),
+        ("a", "win"),
+        ("a", "loss"),
+        ("d", "win"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        db.record_match(&format!("m{}", block), player, "z", result, block as u64)
+            .await
+            .unwrap();
+    }
+
+    let players = |leaderboard: Vec<PlayerStats>| {
+        leaderboard
+            .into_iter()
+            .map(|stats| stats.player)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        players(db.get_leaderboard(None, None, 0, 10).await.unwrap()),
+        ["b", "c", "a", "z", "d"]
+    );
+    assert_eq!(
+        players(db.get_leaderboard(None, None, 1, 2).await.unwrap()),
+        ["c", "a"]
+    );
+    // only the matches of a
+    assert_eq!(
+        players(db.get_leaderboard(Some(5), Some(7), 0, 10).await.unwrap()),
+        ["a", "z"]
+    );
+}
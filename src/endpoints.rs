@@ -1,18 +1,37 @@
 use crate::{
-    balancing::{balance_and_submit_tx, ProvingParams},
-    db::Db,
+    achievements::{self, AchievementsConfig},
+    addresses::{AddressFormat, ContractAddress},
+    api_keys::{self, API_KEY_HEADER},
+    balancing::{balance_and_submit_tx, FeeBudgets, ProvingParams},
+    coin_selection::CoinSelectionConfig,
+    config::{FeePolicy, ServerConfig},
+    consolidation::Activity,
+    contract_state::{parse_p2_public_key, Schema},
+    db::{self, ApiKeyRequest, Db, Lobby, LobbyCursor, LobbyFilter},
+    events::{EventSender, LobbyEvent, LobbyEventKind},
+    player_limits::{transaction_player, PlayerBudget},
     preproofing::PreProvingServiceChannelTx,
+    utils::unix_now,
     whitelisting, SyncStatus,
 };
+use midnight_transient_crypto::proofs::Proof;
 use midnight_zswap::serialize::{self, NetworkId};
 use rand::{rngs::OsRng, Rng};
-use rocket::{http::Method, serde::json::Json, State};
+use rocket::{
+    http::{Accept, Header, Method},
+    request::{FromRequest, Outcome},
+    response::stream::{Event, EventStream},
+    serde::json::Json,
+    tokio::sync::broadcast::error::RecvError,
+    Request, Shutdown, State,
+};
 use rocket_cors::{AllowedOrigins, CorsOptions};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use subxt::{OnlineClient, SubstrateConfig};
 use tokio::sync::{Mutex, RwLock};
 use tracing::Instrument as _;
+use utoipa::{IntoParams, OpenApi, ToSchema};
 
 struct AppState {
     proving_params: Arc<ProvingParams>,
@@ -24,38 +43,92 @@ struct AppState {
     whitelisting: Arc<Option<whitelisting::Constraints>>,
     db: Db,
     address: String,
+    shielded_address: String,
+    fee_policy: FeePolicy,
+    coin_selection: CoinSelectionConfig,
+    activity: Arc<Activity>,
+    events: EventSender,
+    achievements: Arc<AchievementsConfig>,
+    contract_schema: Arc<Schema>,
+}
+
+struct ApiKeyHeader(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKeyHeader {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ApiKeyHeader(
+            request
+                .headers()
+                .get_one(API_KEY_HEADER)
+                .map(|key| key.to_string()),
+        ))
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct Transaction {
+    /// hex encoded serialized unbalanced transaction
     tx: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 struct SubmitTxResponse {
     tx_hash: String,
     identifiers: Vec<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
+struct BatcherAddress {
+    /// Bech32m shielded address
+    address: String,
+    /// `coin_pk|enc_pk`, hex encoded
+    legacy: String,
+}
+
+#[derive(Responder)]
+enum AddressResponse {
+    Json(Json<BatcherAddress>),
+    Text(String),
+}
+
+#[derive(Serialize, ToSchema)]
 struct GetFundsResponse {
     coins: Vec<(String, String)>,
     pending: Vec<String>,
     sync_progress: f64,
 }
 
-#[derive(Serialize)]
+/// Constraints shared by the lobby endpoints, all bounds are inclusive.
+#[derive(FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LobbyFilterQuery {
+    /// Only lobbies in this game state
+    game_state: Option<String>,
+    /// Only lobbies last updated at or after this block height
+    from_block: Option<u64>,
+    /// Only lobbies last updated at or before this block height
+    to_block: Option<u64>,
+    /// Only lobbies created at or after this block height
+    created_from_block: Option<u64>,
+    /// Only lobbies created at or before this block height
+    created_to_block: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
 struct OpenLobby {
     address: String,
     block_height: u64,
     p1_public_key: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 #[serde(transparent)]
 struct GetOpenLobbiesResponse(Vec<OpenLobby>);
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 struct PlayerLobby {
     address: String,
     state: String,
@@ -64,14 +137,119 @@ struct PlayerLobby {
     p2_public_key: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 #[serde(transparent)]
 struct GetPlayerLobbiesResponse(Vec<PlayerLobby>);
 
-#[derive(Responder)]
+#[derive(Serialize, ToSchema)]
+struct PagedOpenLobby {
+    address: String,
+    block_height: u64,
+    created_block_height: u64,
+    p1_public_key: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct GetOpenLobbiesPageResponse {
+    lobbies: Vec<PagedOpenLobby>,
+    /// number of lobbies matching the filters, across all the pages
+    total: u64,
+    /// cursor of the next page, absent on the last one
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct PagedPlayerLobby {
+    address: String,
+    state: String,
+    block_height: u64,
+    created_block_height: u64,
+    p1_public_key: String,
+    p2_public_key: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct GetPlayerLobbiesPageResponse {
+    lobbies: Vec<PagedPlayerLobby>,
+    /// number of lobbies matching the filters, across all the pages
+    total: u64,
+    /// cursor of the next page, absent on the last one
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ContractStateResponse {
+    address: String,
+    block_height: u64,
+    /// arrays as arrays, maps as lists of `{key, value}` and cells as lists
+    /// of atoms, e.g. `{"cell": [{"bytes": "00"}]}`. Merkle trees are
+    /// `{"unsupported": "bounded_merkle_tree"}`
+    #[schema(value_type = Object)]
+    state: serde_json::Value,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ContractStateChange {
+    /// cursor for the `after` parameter
+    id: i64,
+    tx_hash: String,
+    block_height: u64,
+    /// the fields decoded with the contract schema
+    #[schema(value_type = Object)]
+    fields: serde_json::Value,
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(transparent)]
+struct GetContractHistoryResponse(Vec<ContractStateChange>);
+
+#[derive(Serialize, ToSchema)]
+struct PlayerStats {
+    player: String,
+    games_played: u64,
+    wins: u64,
+    losses: u64,
+    draws: u64,
+    /// block of the last finished match
+    last_played_block: Option<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct LeaderboardEntry {
+    /// 1-based position in the whole leaderboard
+    rank: u64,
+    #[serde(flatten)]
+    stats: PlayerStats,
+}
+
+#[derive(Serialize, ToSchema)]
+struct GetLeaderboardResponse(Vec<LeaderboardEntry>);
+
+impl From<db::PlayerStats> for PlayerStats {
+    fn from(stats: db::PlayerStats) -> Self {
+        Self {
+            player: stats.player,
+            games_played: stats.games_played,
+            wins: stats.wins,
+            losses: stats.losses,
+            draws: stats.draws,
+            last_played_block: stats.last_played_block,
+        }
+    }
+}
+
+#[derive(Debug, Responder)]
 pub enum Error {
     #[response(status = 400)]
     BadRequest(String),
+    #[response(status = 401)]
+    Unauthorized(String),
+    #[response(status = 403)]
+    Forbidden(String),
+    #[response(status = 404)]
+    NotFound(String),
+    #[response(status = 429)]
+    TooManyRequests(String, Header<'static>),
     #[response(status = 500)]
     #[allow(clippy::enum_variant_names)]
     InternalError(String),
@@ -104,16 +282,118 @@ async fn check_is_wallet_in_sync(state: &AppState) -> Result<(), Error> {
     Ok(())
 }
 
+/// Returns the hash of the api key if one was provided and it is within its
+/// rate limit.
+async fn check_api_key(state: &AppState, key: Option<String>) -> Result<Option<String>, Error> {
+    let Some(key) = key else {
+        if state.fee_policy.require_api_key {
+            return Err(Error::Unauthorized("Missing api key".to_string()));
+        }
+
+        return Ok(None);
+    };
+
+    let key_hash = api_keys::hash_key(&key);
+
+    match state
+        .db
+        .consume_api_key_request(&key_hash, unix_now())
+        .await?
+    {
+        ApiKeyRequest::Unknown => Err(Error::Unauthorized("Invalid api key".to_string())),
+        ApiKeyRequest::RateLimited { retry_after } => Err(Error::TooManyRequests(
+            format!("Rate limit exceeded, retry in {} seconds", retry_after),
+            Header::new("Retry-After", retry_after.to_string()),
+        )),
+        ApiKeyRequest::Allowed => Ok(Some(key_hash)),
+    }
+}
+
+/// Returns the identity of the player if per-player limits are enabled and the
+/// request is within the rate limit.
+async fn check_player_limits(state: &AppState, tx: &str) -> Result<Option<PlayerBudget>, Error> {
+    let player_limits = state.fee_policy.player_limits();
+
+    if !player_limits.is_enabled() {
+        return Ok(None);
+    }
+
+    let tx: midnight_ledger::structure::Transaction<Proof> =
+        serialize::deserialize(
+            std::io::Cursor::new(hex::decode(tx).map_err(|_| {
+                Error::BadRequest("Transaction payload is not valid hex".to_string())
+            })?),
+            state.network_id,
+        )
+        .map_err(|e| Error::BadRequest(format!("Invalid transaction. Error: {}", e)))?;
+
+    let player = transaction_player(&state.db, &state.contract_schema, &tx, state.network_id)
+        .await?
+        .ok_or_else(|| {
+            Error::BadRequest(
+                "The player of the transaction can't be identified from the contract".to_string(),
+            )
+        })?;
+
+    if let Some(requests_per_minute) = player_limits.requests_per_minute {
+        if let Some(retry_after) = state
+            .db
+            .consume_player_request(&player, unix_now(), requests_per_minute)
+            .await?
+        {
+            return Err(Error::TooManyRequests(
+                format!(
+                    "Player rate limit exceeded, retry in {} seconds",
+                    retry_after
+                ),
+                Header::new("Retry-After", retry_after.to_string()),
+            ));
+        }
+    }
+
+    Ok(player_limits
+        .daily_fee_budget
+        .map(|daily_fee_budget| PlayerBudget {
+            player,
+            daily_fee_budget,
+        }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/submitTx",
+    request_body = Transaction,
+    params(
+        ("X-Api-Key" = Option<String>, Header, description = "Required when the batcher runs with --require-api-key"),
+    ),
+    responses(
+        (status = 200, description = "Transaction balanced and finalized", body = SubmitTxResponse),
+        (status = 400, description = "Invalid or not allowed transaction", body = String),
+        (status = 401, description = "Missing or invalid api key", body = String),
+        (status = 403, description = "Api key budget exhausted", body = String),
+        (status = 429, description = "Rate limit or player daily fee budget exceeded, see the Retry-After header", body = String),
+        (status = 500, description = "Internal error", body = String),
+        (status = 503, description = "Wallet not in sync or no funds available", body = String),
+    )
+)]
 #[post("/submitTx", format = "json", data = "<transaction>")]
 async fn submit_tx(
     transaction: Json<Transaction>,
+    api_key: ApiKeyHeader,
     state: &State<AppState>,
 ) -> Result<Json<SubmitTxResponse>, Error> {
+    let _request = state.activity.start_request();
+
     let span_id: u128 = OsRng.gen();
     let span = tracing::info_span!("submit_tx handler", span_id);
 
+    // before the limits, so that a request rejected while syncing doesn't
+    // count against them.
     check_is_wallet_in_sync(state).await?;
 
+    let api_key = check_api_key(state, api_key.0).await?;
+    let player = check_player_limits(state, &transaction.tx).await?;
+
     let now = std::time::Instant::now();
 
     let (tx_hash, identifiers) = balance_and_submit_tx(
@@ -125,6 +405,9 @@ async fn submit_tx(
         state.inputs_service.clone(),
         &state.whitelisting,
         &state.db,
+        FeeBudgets { api_key, player },
+        &state.fee_policy,
+        &state.coin_selection,
     )
     .instrument(span.clone())
     .await?;
@@ -142,6 +425,11 @@ async fn submit_tx(
     }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/funds",
+    responses((status = 200, description = "Batcher wallet coins", body = GetFundsResponse))
+)]
 #[get("/funds")]
 async fn funds(state: &State<AppState>) -> Result<Json<GetFundsResponse>, Error> {
     let lock = state.zswap_state.lock().await;
@@ -183,77 +471,546 @@ async fn funds(state: &State<AppState>) -> Result<Json<GetFundsResponse>, Error>
     }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/address",
+    responses((
+        status = 200,
+        description = "Batcher address, in the legacy format in plain text, or in both formats in JSON",
+        content((String = "text/plain"), (BatcherAddress = "application/json"))
+    ))
+)]
 #[get("/address")]
-async fn address(state: &State<AppState>) -> String {
-    state.address.clone()
+async fn address(state: &State<AppState>, accept: Option<&Accept>) -> AddressResponse {
+    if accept.is_some_and(|accept| accept.preferred().media_type().is_json()) {
+        AddressResponse::Json(Json(BatcherAddress {
+            address: state.shielded_address.clone(),
+            legacy: state.address.clone(),
+        }))
+    } else {
+        AddressResponse::Text(state.address.clone())
+    }
+}
+
+/// Max page size of the lobby endpoints.
+const MAX_LOBBIES_PAGE_SIZE: u32 = 100;
+
+impl From<LobbyFilterQuery> for LobbyFilter {
+    fn from(query: LobbyFilterQuery) -> Self {
+        Self {
+            game_state: query.game_state,
+            from_block: query.from_block,
+            to_block: query.to_block,
+            created_from_block: query.created_from_block,
+            created_to_block: query.created_to_block,
+        }
+    }
+}
+
+/// The cursors are opaque to the clients: the hex encoded creation block and
+/// address of the last lobby of the page.
+fn encode_lobby_cursor(lobby: &Lobby) -> String {
+    hex::encode(format!("{}:{}", lobby.created_block, lobby.address))
+}
+
+fn decode_lobby_cursor(cursor: &str) -> Result<LobbyCursor, Error> {
+    let invalid = || Error::BadRequest("Invalid cursor".to_string());
+
+    let decoded = hex::decode(cursor)
+        .ok()
+        .and_then(|raw| String::from_utf8(raw).ok())
+        .ok_or_else(invalid)?;
+
+    let (created_block, address) = decoded.split_once(':').ok_or_else(invalid)?;
+
+    Ok(LobbyCursor {
+        created_block: created_block.parse().map_err(|_| invalid())?,
+        address: address.to_string(),
+    })
+}
+
+fn encode_address(
+    state: &AppState,
+    stored: &str,
+    format: Option<AddressFormat>,
+) -> Result<String, Error> {
+    Ok(ContractAddress::from_stored(stored, state.network_id)?
+        .encode(format.unwrap_or_default())?)
+}
+
+/// Parses a contract address sent by a client, in any format, into the form
+/// stored in the database.
+fn parse_address(state: &AppState, address: &str) -> Result<String, Error> {
+    ContractAddress::parse(address, state.network_id)
+        .map(|address| address.to_stored())
+        .map_err(|error| Error::BadRequest(error.to_string()))
+}
+
+/// Fetches one more lobby than requested, to know if there is a next page.
+fn paginate(mut lobbies: Vec<Lobby>, count: u32) -> (Vec<Lobby>, Option<String>) {
+    if lobbies.len() > count as usize {
+        lobbies.truncate(count as usize);
+
+        let next_cursor = lobbies.last().map(encode_lobby_cursor);
+
+        (lobbies, next_cursor)
+    } else {
+        (lobbies, None)
+    }
 }
 
-#[get("/lobbies/open?<after>&<count>&<exclude_player>")]
+#[utoipa::path(
+    get,
+    path = "/lobbies/open",
+    params(
+        ("after" = Option<String>, Query, description = "Address of the last lobby of the previous page"),
+        ("count" = Option<u8>, Query, description = "Page size, defaults to 10"),
+        ("exclude_player" = Option<String>, Query, description = "Skip lobbies created by this public key"),
+        ("address_format" = Option<AddressFormat>, Query, description = "Encoding of the lobby addresses, defaults to hex"),
+    ),
+    responses(
+        (status = 200, description = "Lobbies waiting for a second player, newest first", body = GetOpenLobbiesResponse),
+        (status = 400, description = "Invalid address", body = String),
+        (status = 500, description = "Internal error", body = String),
+        (status = 503, description = "Wallet not in sync", body = String),
+    )
+)]
+#[get("/lobbies/open?<after>&<count>&<exclude_player>&<address_format>")]
 async fn get_open_lobbies(
     state: &State<AppState>,
     after: Option<String>,
     count: Option<u8>,
     exclude_player: Option<String>,
+    address_format: Option<AddressFormat>,
 ) -> Result<Json<GetOpenLobbiesResponse>, Error> {
     check_is_wallet_in_sync(state).await?;
 
-    let lobbies = state
+    let after = match after {
+        Some(address) => match state
+            .db
+            .get_lobby_cursor(&parse_address(state, &address)?)
+            .await?
+        {
+            Some(cursor) => Some(cursor),
+            // there is nothing after an unknown lobby
+            None => return Ok(Json(GetOpenLobbiesResponse(vec![]))),
+        },
+        None => None,
+    };
+
+    let (lobbies, _) = state
         .db
-        .get_lobbies_waiting_for_p2(after, count, exclude_player)
-        .await;
+        .get_lobbies_waiting_for_p2(
+            LobbyFilter::default(),
+            exclude_player,
+            after,
+            count.unwrap_or(10).into(),
+        )
+        .await?;
 
-    match lobbies {
-        Ok(lobbies) => Ok(Json(GetOpenLobbiesResponse(
-            lobbies
-                .into_iter()
-                .map(|(address, block_height, p1_public_key)| OpenLobby {
-                    // for some reason the game expects the address without the network prefix
-                    address: address[2..].to_string(),
-                    block_height,
-                    p1_public_key,
+    Ok(Json(GetOpenLobbiesResponse(
+        lobbies
+            .into_iter()
+            .map(|lobby| {
+                Ok(OpenLobby {
+                    address: encode_address(state, &lobby.address, address_format)?,
+                    block_height: lobby.block_number,
+                    p1_public_key: lobby.p1_public_key,
                 })
-                .collect::<Vec<_>>(),
-        ))),
-        Err(error) => Err(Error::InternalError(error.to_string())),
-    }
+            })
+            .collect::<Result<_, Error>>()?,
+    )))
 }
 
-#[get("/lobbies/player/<player_id>?<after>&<count>")]
+#[utoipa::path(
+    get,
+    path = "/lobbies/player/{player_id}",
+    params(
+        ("player_id" = String, Path, description = "Player public key"),
+        ("after" = Option<String>, Query, description = "Address of the last lobby of the previous page"),
+        ("count" = Option<u8>, Query, description = "Page size, defaults to 10"),
+        ("address_format" = Option<AddressFormat>, Query, description = "Encoding of the lobby addresses, defaults to hex"),
+    ),
+    responses(
+        (status = 200, description = "Lobbies the player is part of, newest first", body = GetPlayerLobbiesResponse),
+        (status = 400, description = "Invalid address", body = String),
+        (status = 500, description = "Internal error", body = String),
+        (status = 503, description = "Wallet not in sync", body = String),
+    )
+)]
+#[get("/lobbies/player/<player_id>?<after>&<count>&<address_format>")]
 async fn get_player_lobbies(
     state: &State<AppState>,
     player_id: String,
     after: Option<String>,
     count: Option<u8>,
+    address_format: Option<AddressFormat>,
 ) -> Result<Json<GetPlayerLobbiesResponse>, Error> {
     check_is_wallet_in_sync(state).await?;
 
-    let lobbies = state.db.get_player_lobbies(player_id, count, after).await;
+    let after = match after {
+        Some(address) => match state
+            .db
+            .get_lobby_cursor(&parse_address(state, &address)?)
+            .await?
+        {
+            Some(cursor) => Some(cursor),
+            // there is nothing after an unknown lobby
+            None => return Ok(Json(GetPlayerLobbiesResponse(vec![]))),
+        },
+        None => None,
+    };
 
-    match lobbies {
-        Ok(lobbies) => Ok(Json(GetPlayerLobbiesResponse(
-            lobbies
-                .into_iter()
-                .map(
-                    |(address, state, block_height, p1_public_key, p2_public_key)| PlayerLobby {
-                        // for some reason the game expects the address without the network prefix
-                        address: address[2..].to_string(),
-                        state,
-                        block_height,
-                        p1_public_key,
-                        p2_public_key: p2_public_key
-                            .split(";")
-                            .nth(1)
-                            .map(|s| s.to_string())
-                            .filter(|s| !s.is_empty()),
-                    },
-                )
-                .collect::<Vec<_>>(),
-        ))),
-        Err(error) => Err(Error::InternalError(error.to_string())),
-    }
+    let (lobbies, _) = state
+        .db
+        .get_player_lobbies(
+            player_id,
+            LobbyFilter::default(),
+            after,
+            count.unwrap_or(10).into(),
+        )
+        .await?;
+
+    Ok(Json(GetPlayerLobbiesResponse(
+        lobbies
+            .into_iter()
+            .map(|lobby| {
+                Ok(PlayerLobby {
+                    address: encode_address(state, &lobby.address, address_format)?,
+                    state: lobby.game_state,
+                    block_height: lobby.block_number,
+                    p1_public_key: lobby.p1_public_key,
+                    p2_public_key: parse_p2_public_key(&lobby.p2_public_key),
+                })
+            })
+            .collect::<Result<_, Error>>()?,
+    )))
 }
 
-#[derive(Serialize)]
+#[utoipa::path(
+    get,
+    path = "/lobbies/open/page",
+    params(
+        ("cursor" = Option<String>, Query, description = "`next_cursor` of the previous page"),
+        ("count" = Option<u32>, Query, description = "Page size, defaults to 10, at most 100"),
+        ("exclude_player" = Option<String>, Query, description = "Skip lobbies created by this public key"),
+        ("address_format" = Option<AddressFormat>, Query, description = "Encoding of the lobby addresses, defaults to hex"),
+        LobbyFilterQuery,
+    ),
+    responses(
+        (status = 200, description = "Lobbies waiting for a second player, newest first", body = GetOpenLobbiesPageResponse),
+        (status = 400, description = "Invalid cursor", body = String),
+        (status = 500, description = "Internal error", body = String),
+        (status = 503, description = "Wallet not in sync", body = String),
+    )
+)]
+#[get("/lobbies/open/page?<cursor>&<count>&<exclude_player>&<address_format>&<filter..>")]
+async fn get_open_lobbies_page(
+    state: &State<AppState>,
+    cursor: Option<String>,
+    count: Option<u32>,
+    exclude_player: Option<String>,
+    address_format: Option<AddressFormat>,
+    filter: LobbyFilterQuery,
+) -> Result<Json<GetOpenLobbiesPageResponse>, Error> {
+    check_is_wallet_in_sync(state).await?;
+
+    let after = cursor.as_deref().map(decode_lobby_cursor).transpose()?;
+    let count = count.unwrap_or(10).min(MAX_LOBBIES_PAGE_SIZE);
+
+    let (lobbies, total) = state
+        .db
+        .get_lobbies_waiting_for_p2(filter.into(), exclude_player, after, count + 1)
+        .await?;
+
+    let (lobbies, next_cursor) = paginate(lobbies, count);
+
+    Ok(Json(GetOpenLobbiesPageResponse {
+        lobbies: lobbies
+            .into_iter()
+            .map(|lobby| {
+                Ok(PagedOpenLobby {
+                    address: encode_address(state, &lobby.address, address_format)?,
+                    block_height: lobby.block_number,
+                    created_block_height: lobby.created_block,
+                    p1_public_key: lobby.p1_public_key,
+                })
+            })
+            .collect::<Result<_, Error>>()?,
+        total,
+        next_cursor,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/lobbies/player/{player_id}/page",
+    params(
+        ("player_id" = String, Path, description = "Player public key"),
+        ("cursor" = Option<String>, Query, description = "`next_cursor` of the previous page"),
+        ("count" = Option<u32>, Query, description = "Page size, defaults to 10, at most 100"),
+        ("address_format" = Option<AddressFormat>, Query, description = "Encoding of the lobby addresses, defaults to hex"),
+        LobbyFilterQuery,
+    ),
+    responses(
+        (status = 200, description = "Lobbies the player is part of, newest first", body = GetPlayerLobbiesPageResponse),
+        (status = 400, description = "Invalid cursor", body = String),
+        (status = 500, description = "Internal error", body = String),
+        (status = 503, description = "Wallet not in sync", body = String),
+    )
+)]
+#[get("/lobbies/player/<player_id>/page?<cursor>&<count>&<address_format>&<filter..>")]
+async fn get_player_lobbies_page(
+    state: &State<AppState>,
+    player_id: String,
+    cursor: Option<String>,
+    count: Option<u32>,
+    address_format: Option<AddressFormat>,
+    filter: LobbyFilterQuery,
+) -> Result<Json<GetPlayerLobbiesPageResponse>, Error> {
+    check_is_wallet_in_sync(state).await?;
+
+    let after = cursor.as_deref().map(decode_lobby_cursor).transpose()?;
+    let count = count.unwrap_or(10).min(MAX_LOBBIES_PAGE_SIZE);
+
+    let (lobbies, total) = state
+        .db
+        .get_player_lobbies(player_id, filter.into(), after, count + 1)
+        .await?;
+
+    let (lobbies, next_cursor) = paginate(lobbies, count);
+
+    Ok(Json(GetPlayerLobbiesPageResponse {
+        lobbies: lobbies
+            .into_iter()
+            .map(|lobby| {
+                Ok(PagedPlayerLobby {
+                    address: encode_address(state, &lobby.address, address_format)?,
+                    state: lobby.game_state,
+                    block_height: lobby.block_number,
+                    created_block_height: lobby.created_block,
+                    p1_public_key: lobby.p1_public_key,
+                    p2_public_key: parse_p2_public_key(&lobby.p2_public_key),
+                })
+            })
+            .collect::<Result<_, Error>>()?,
+        total,
+        next_cursor,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/contracts/{address}/state",
+    params(("address" = String, Path, description = "Contract address, in any of the address formats")),
+    responses(
+        (status = 200, description = "Last indexed state of the contract", body = ContractStateResponse),
+        (status = 400, description = "Invalid address", body = String),
+        (status = 404, description = "Unknown contract", body = String),
+        (status = 500, description = "Internal error", body = String),
+    )
+)]
+#[get("/contracts/<address>/state")]
+async fn get_contract_state(
+    state: &State<AppState>,
+    address: String,
+) -> Result<Json<ContractStateResponse>, Error> {
+    let Some((contract_state, block_height)) = state
+        .db
+        .get_contract_state_json(&parse_address(state, &address)?)
+        .await?
+    else {
+        return Err(Error::NotFound(format!("Unknown contract {}", address)));
+    };
+
+    Ok(Json(ContractStateResponse {
+        address,
+        block_height,
+        state: contract_state,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/contracts/{address}/history",
+    params(
+        ("address" = String, Path, description = "Contract address, in any of the address formats"),
+        ("after" = Option<i64>, Query, description = "Id of the last change of the previous page"),
+        ("count" = Option<u8>, Query, description = "Page size, defaults to 10"),
+    ),
+    responses(
+        (status = 200, description = "State changes of the contract, oldest first", body = GetContractHistoryResponse),
+        (status = 400, description = "Invalid address", body = String),
+        (status = 500, description = "Internal error", body = String),
+    )
+)]
+#[get("/contracts/<address>/history?<after>&<count>")]
+async fn get_contract_history(
+    state: &State<AppState>,
+    address: String,
+    after: Option<i64>,
+    count: Option<u8>,
+) -> Result<Json<GetContractHistoryResponse>, Error> {
+    let history = state
+        .db
+        .get_contract_state_history(&parse_address(state, &address)?, after, count)
+        .await?;
+
+    Ok(Json(GetContractHistoryResponse(
+        history
+            .into_iter()
+            .map(|change| ContractStateChange {
+                id: change.id,
+                tx_hash: change.tx_hash,
+                block_height: change.block_number,
+                fields: change.fields,
+            })
+            .collect(),
+    )))
+}
+
+/// Number of stored events read at a time when sending them to a client.
+const REPLAY_PAGE_SIZE: u32 = 500;
+
+/// Stored events from `from_block` on with an id greater than `after`, one
+/// page at a time.
+async fn stored_events(
+    db: &Db,
+    from_block: u64,
+    player: &Option<String>,
+    address: &Option<String>,
+    after: i64,
+) -> anyhow::Result<Vec<LobbyEvent>> {
+    db.get_lobby_events(
+        from_block,
+        player.clone(),
+        address.clone(),
+        Some(after),
+        REPLAY_PAGE_SIZE,
+    )
+    .await?
+    .into_iter()
+    .map(|(id, kind, contract_address, block_height, columns)| {
+        Ok(LobbyEvent::from_columns(
+            id,
+            LobbyEventKind::parse(&kind)?,
+            &contract_address,
+            block_height,
+            columns,
+        ))
+    })
+    .collect()
+}
+
+#[utoipa::path(
+    get,
+    path = "/events",
+    params(
+        ("player" = Option<String>, Query, description = "Only events of lobbies this public key is part of"),
+        ("address" = Option<String>, Query, description = "Only events of this contract, in any of the address formats"),
+        ("from_block" = Option<u64>, Query, description = "Send all the stored events from this block height before the live ones"),
+    ),
+    responses(
+        (status = 200, description = "Server-sent events stream, each event data is a LobbyEvent. A `reset` event with no data means that events were missed and the client has to reload its state", body = LobbyEvent, content_type = "text/event-stream"),
+        (status = 400, description = "Invalid address", body = String),
+        (status = 500, description = "Internal error", body = String),
+    )
+)]
+#[get("/events?<player>&<address>&<from_block>")]
+async fn events(
+    state: &State<AppState>,
+    player: Option<String>,
+    address: Option<String>,
+    from_block: Option<u64>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], Error> {
+    let address = address
+        .map(|address| parse_address(state, &address))
+        .transpose()?;
+
+    // subscribe before reading the stored events, so that nothing is missed in
+    // between. Duplicates are skipped by id.
+    let mut rx = state.events.subscribe();
+
+    let db = state.db.clone();
+
+    // read here so that a failing db is reported with the response status.
+    let first_page = match from_block {
+        Some(from_block) => stored_events(&db, from_block, &player, &address, 0).await?,
+        None => vec![],
+    };
+
+    Ok(EventStream! {
+        let mut last_id = 0;
+        let mut page = first_page;
+        let mut catch_up_from = from_block;
+
+        'stream: loop {
+            // the stored events are sent in pages, until there are no more.
+            // They are read again after missing live events, which are also
+            // stored by then.
+            while let Some(from_block) = catch_up_from {
+                let full = page.len() == REPLAY_PAGE_SIZE as usize;
+
+                for event in page.drain(..) {
+                    last_id = event.id;
+                    yield Event::json(&event).id(event.id.to_string());
+                }
+
+                if !full {
+                    catch_up_from = None;
+                    break;
+                }
+
+                page = match stored_events(&db, from_block, &player, &address, last_id).await {
+                    Ok(page) => page,
+                    Err(error) => {
+                        tracing::error!(?error, "failed to read the stored events");
+                        break 'stream;
+                    }
+                };
+            }
+
+            let event = rocket::tokio::select! {
+                event = rx.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "event stream lagging behind");
+
+                        if last_id == 0 && from_block.is_none() {
+                            // there is no position to catch up from.
+                            yield Event::empty().event("reset");
+                            continue;
+                        }
+
+                        let from_block = from_block.unwrap_or(0);
+
+                        page = match stored_events(&db, from_block, &player, &address, last_id).await {
+                            Ok(page) => page,
+                            Err(error) => {
+                                tracing::error!(?error, "failed to read the stored events");
+                                break;
+                            }
+                        };
+                        catch_up_from = Some(from_block);
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+
+            if event.id <= last_id || !event.matches(player.as_deref(), address.as_deref()) {
+                continue;
+            }
+
+            last_id = event.id;
+            yield Event::json(&event).id(event.id.to_string());
+        }
+    })
+}
+
+#[derive(Serialize, ToSchema)]
 struct Achievement {
     name: String,
     is_active: bool,
@@ -261,7 +1018,7 @@ struct Achievement {
     description: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 struct Achievements {
     caip2: String,
     block: u64,
@@ -270,65 +1027,227 @@ struct Achievements {
     achievements: Vec<Achievement>,
 }
 
-const CAIP2: &str = "polkadot:00000000000000000000000000000000";
-const PLAYED_FIRST_MATCH_ACHIEVEMENT_ID: &str = "played_first_match";
-
+#[utoipa::path(
+    get,
+    path = "/achievements/public/list",
+    responses(
+        (status = 200, description = "PRC-1 achievements list", body = Achievements),
+        (status = 500, description = "Internal error", body = String),
+    )
+)]
 #[get("/achievements/public/list")]
-async fn get_public_achievements() -> Json<Achievements> {
+async fn get_public_achievements(state: &State<AppState>) -> Result<Json<Achievements>, Error> {
+    let config = &state.achievements;
+
     let game = Achievements {
-        caip2: CAIP2.to_string(),
-        block: 0,
-        id: "paima-kachina-kolosseum".to_string(),
-        name: "Kachina Kolosseum".to_string(),
-        achievements: vec![Achievement {
-            name: PLAYED_FIRST_MATCH_ACHIEVEMENT_ID.to_string(),
-            is_active: true,
-            display_name: "Welcome to the Arena".to_string(),
-            description: "Play your first match (win or lose)".to_string(),
-        }],
+        caip2: config.caip2.clone(),
+        block: state.db.last_contract_block().await?.unwrap_or(0),
+        id: config.id.clone(),
+        name: config.name.clone(),
+        achievements: config
+            .achievements
+            .iter()
+            .map(|achievement| Achievement {
+                name: achievement.name.clone(),
+                is_active: achievement.is_active,
+                display_name: achievement.display_name.clone(),
+                description: achievement.description.clone(),
+            })
+            .collect(),
     };
 
-    Json(game)
+    Ok(Json(game))
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, ToSchema)]
 struct PlayerAchievements {
     caip2: String,
     block: u64,
     wallet: String,
-    completed: u8,
+    completed: u32,
     achievements: Vec<AchievementStatus>,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, ToSchema)]
 struct AchievementStatus {
     name: String,
     completed: bool,
+    /// block of the match that completed it
+    completed_block: Option<u64>,
+    progress: u64,
+    total: u64,
 }
 
+#[utoipa::path(
+    get,
+    path = "/achievements/wallet/{player_id}",
+    params(("player_id" = String, Path, description = "Player public key")),
+    responses(
+        (status = 200, description = "PRC-1 achievements progress for a wallet", body = PlayerAchievements),
+        (status = 500, description = "Internal error", body = String),
+    )
+)]
 #[get("/achievements/wallet/<player_id>")]
 async fn get_player_achievements(
     state: &State<AppState>,
     player_id: String,
 ) -> Result<Json<PlayerAchievements>, Error> {
-    let completed = state
-        .db
-        .played_first_match_achievement_completed(player_id.clone())
-        .await
-        .map_err(|e| Error::InternalError(e.to_string()))?;
+    let matches = achievements::player_matches(&state.db, &player_id).await?;
+    let completed = state.db.get_completed_achievements(&player_id).await?;
+
+    let statuses = state
+        .achievements
+        .achievements
+        .iter()
+        .filter(|achievement| achievement.is_active)
+        .map(|achievement| {
+            let completed_block = completed
+                .iter()
+                .find(|(name, _)| name == &achievement.name)
+                .map(|(_, block)| *block);
+
+            AchievementStatus {
+                name: achievement.name.clone(),
+                completed: completed_block.is_some(),
+                completed_block,
+                progress: achievement.rule.progress(&matches),
+                total: achievement.rule.total(),
+            }
+        })
+        .collect::<Vec<_>>();
 
     Ok(Json(PlayerAchievements {
-        caip2: CAIP2.to_string(),
-        block: 0,
+        caip2: state.achievements.caip2.clone(),
+        block: state.db.last_contract_block().await?.unwrap_or(0),
         wallet: player_id,
-        completed: if completed { 1 } else { 0 },
-        achievements: vec![AchievementStatus {
-            name: PLAYED_FIRST_MATCH_ACHIEVEMENT_ID.to_string(),
-            completed,
-        }],
+        completed: statuses.iter().filter(|status| status.completed).count() as u32,
+        achievements: statuses,
     }))
 }
 
+#[utoipa::path(
+    get,
+    path = "/players/{player_id}/stats",
+    params(
+        ("player_id" = String, Path, description = "Player public key"),
+        ("from_block" = Option<u64>, Query, description = "Only matches finished at or after this block height"),
+        ("to_block" = Option<u64>, Query, description = "Only matches finished at or before this block height"),
+    ),
+    responses(
+        (status = 200, description = "Results of the finished matches of the player", body = PlayerStats),
+        (status = 500, description = "Internal error", body = String),
+    )
+)]
+#[get("/players/<player_id>/stats?<from_block>&<to_block>")]
+async fn get_player_stats(
+    state: &State<AppState>,
+    player_id: String,
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+) -> Result<Json<PlayerStats>, Error> {
+    let stats = state
+        .db
+        .get_player_stats(&player_id, from_block, to_block)
+        .await?;
+
+    Ok(Json(stats.into()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/leaderboard",
+    params(
+        ("from_block" = Option<u64>, Query, description = "Only matches finished at or after this block height"),
+        ("to_block" = Option<u64>, Query, description = "Only matches finished at or before this block height"),
+        ("offset" = Option<u64>, Query, description = "Number of entries to skip"),
+        ("count" = Option<u8>, Query, description = "Page size, defaults to 10"),
+    ),
+    responses(
+        (status = 200, description = "Players ranked by wins, then draws, then fewest losses", body = GetLeaderboardResponse),
+        (status = 500, description = "Internal error", body = String),
+    )
+)]
+#[get("/leaderboard?<from_block>&<to_block>&<offset>&<count>")]
+async fn get_leaderboard(
+    state: &State<AppState>,
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+    offset: Option<u64>,
+    count: Option<u8>,
+) -> Result<Json<GetLeaderboardResponse>, Error> {
+    let offset = offset.unwrap_or(0);
+
+    let leaderboard = state
+        .db
+        .get_leaderboard(from_block, to_block, offset, count.unwrap_or(10))
+        .await?;
+
+    Ok(Json(GetLeaderboardResponse(
+        leaderboard
+            .into_iter()
+            .zip(offset + 1..)
+            .map(|(stats, rank)| LeaderboardEntry {
+                rank,
+                stats: stats.into(),
+            })
+            .collect(),
+    )))
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Midnight Batcher"),
+    paths(
+        submit_tx,
+        funds,
+        address,
+        get_open_lobbies,
+        get_player_lobbies,
+        get_open_lobbies_page,
+        get_player_lobbies_page,
+        get_contract_state,
+        get_contract_history,
+        events,
+        get_public_achievements,
+        get_player_achievements,
+        get_player_stats,
+        get_leaderboard
+    ),
+    components(schemas(
+        Transaction,
+        SubmitTxResponse,
+        GetFundsResponse,
+        BatcherAddress,
+        OpenLobby,
+        PagedOpenLobby,
+        GetOpenLobbiesResponse,
+        GetOpenLobbiesPageResponse,
+        PlayerLobby,
+        PagedPlayerLobby,
+        GetPlayerLobbiesResponse,
+        GetPlayerLobbiesPageResponse,
+        ContractStateResponse,
+        ContractStateChange,
+        GetContractHistoryResponse,
+        LobbyEvent,
+        LobbyEventKind,
+        AddressFormat,
+        Achievement,
+        Achievements,
+        PlayerAchievements,
+        AchievementStatus,
+        PlayerStats,
+        LeaderboardEntry,
+        GetLeaderboardResponse
+    ))
+)]
+struct ApiDoc;
+
+#[get("/openapi.json")]
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn rocket(
     prover_params: Arc<ProvingParams>,
@@ -340,7 +1259,23 @@ pub fn rocket(
     whitelisting: Option<whitelisting::Constraints>,
     db: Db,
     address: String,
+    shielded_address: String,
+    fee_policy: FeePolicy,
+    coin_selection: CoinSelectionConfig,
+    activity: Arc<Activity>,
+    server_config: ServerConfig,
+    events: EventSender,
+    achievements: Arc<AchievementsConfig>,
+    contract_schema: Arc<Schema>,
 ) -> rocket::Rocket<rocket::Build> {
+    let allowed_origins = match &server_config.allowed_origins {
+        Some(origins) => AllowedOrigins::some_exact(origins),
+        // browsers would send the api key of a partner game from any website
+        // otherwise.
+        None if fee_policy.require_api_key => AllowedOrigins::some_exact::<&str>(&[]),
+        None => AllowedOrigins::all(),
+    };
+
     let state = AppState {
         proving_params: prover_params,
         api,
@@ -351,10 +1286,17 @@ pub fn rocket(
         whitelisting: Arc::new(whitelisting),
         db,
         address,
+        shielded_address,
+        fee_policy,
+        coin_selection,
+        activity,
+        events,
+        achievements,
+        contract_schema,
     };
 
     let cors = CorsOptions::default()
-        .allowed_origins(AllowedOrigins::all())
+        .allowed_origins(allowed_origins)
         .allowed_methods(
             vec![Method::Get, Method::Post, Method::Patch]
                 .into_iter()
@@ -363,19 +1305,87 @@ pub fn rocket(
         )
         .allow_credentials(true);
 
-    rocket::build()
+    let mut figment = rocket::Config::figment();
+
+    if let Some(address) = server_config.address {
+        figment = figment.merge(("address", address));
+    }
+
+    if let Some(port) = server_config.port {
+        figment = figment.merge(("port", port));
+    }
+
+    rocket::custom(figment)
         .manage(state)
-        .mount(
-            "/",
-            routes![
-                submit_tx,
-                funds,
-                address,
-                get_open_lobbies,
-                get_player_lobbies,
-                get_public_achievements,
-                get_player_achievements
-            ],
-        )
+        .mount("/", api_routes())
         .attach(cors.to_cors().unwrap())
 }
+
+fn api_routes() -> Vec<rocket::Route> {
+    routes![
+        submit_tx,
+        funds,
+        address,
+        get_open_lobbies,
+        get_player_lobbies,
+        get_open_lobbies_page,
+        get_player_lobbies_page,
+        get_contract_state,
+        get_contract_history,
+        events,
+        get_public_achievements,
+        get_player_achievements,
+        get_player_stats,
+        get_leaderboard,
+        openapi
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// The path of the route in the OpenAPI syntax, e.g. `/contracts/{address}/state`.
+    fn openapi_path(route: &rocket::Route) -> String {
+        route
+            .uri
+            .path()
+            .to_string()
+            .split('/')
+            .map(|segment| {
+                match segment
+                    .strip_prefix('<')
+                    .and_then(|segment| segment.strip_suffix('>'))
+                {
+                    Some(param) => format!("{{{}}}", param.trim_end_matches("..")),
+                    None => segment.to_string(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[test]
+    fn openapi_documents_the_mounted_routes() {
+        let documented = ApiDoc::openapi()
+            .paths
+            .paths
+            .into_iter()
+            .flat_map(|(path, item)| {
+                item.operations.into_keys().map(move |method| {
+                    let method = serde_json::to_value(method).unwrap();
+                    (method.as_str().unwrap().to_ascii_uppercase(), path.clone())
+                })
+            })
+            .collect::<BTreeSet<_>>();
+
+        let mounted = api_routes()
+            .iter()
+            .map(|route| (route.method.as_str().to_string(), openapi_path(route)))
+            .filter(|(_, path)| path != "/openapi.json")
+            .collect::<BTreeSet<_>>();
+
+        assert_eq!(documented, mounted);
+    }
+}
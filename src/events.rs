@@ -0,0 +1,117 @@
+//! Lobby updates pushed to the game clients. Events are stored, so that
+//! clients can catch up from a block height after reconnecting, and then
+//! broadcasted to the open streams.
+
+use crate::contract_state::{parse_p2_public_key, Outcomes};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+const P2_MISSING: &str = "00;";
+
+pub type EventSender = broadcast::Sender<LobbyEvent>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LobbyEventKind {
+    NewLobby,
+    LobbyJoined,
+    StateChanged,
+    GameFinished,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LobbyEvent {
+    pub id: i64,
+    pub kind: LobbyEventKind,
+    /// hex encoded, like in the lobby endpoints by default
+    pub address: String,
+    pub block_height: u64,
+    pub game_state: String,
+    pub p1_public_key: String,
+    pub p2_public_key: Option<String>,
+}
+
+impl LobbyEventKind {
+    /// Classifies a change of the lobby columns (game state, p1 and p2) of a
+    /// contract, `None` if nothing changed.
+    pub fn classify(
+        previous: &[String; 3],
+        current: &[String; 3],
+        outcomes: &Outcomes,
+    ) -> Option<Self> {
+        if previous == current {
+            return None;
+        }
+
+        let [previous_game_state, _, previous_p2] = previous;
+        let [game_state, _, p2] = current;
+
+        if previous_p2 == P2_MISSING && p2 != P2_MISSING {
+            Some(LobbyEventKind::LobbyJoined)
+        } else if outcomes.is_finished(game_state) && !outcomes.is_finished(previous_game_state) {
+            Some(LobbyEventKind::GameFinished)
+        } else {
+            Some(LobbyEventKind::StateChanged)
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LobbyEventKind::NewLobby => "new_lobby",
+            LobbyEventKind::LobbyJoined => "lobby_joined",
+            LobbyEventKind::StateChanged => "state_changed",
+            LobbyEventKind::GameFinished => "game_finished",
+        }
+    }
+
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "new_lobby" => Ok(LobbyEventKind::NewLobby),
+            "lobby_joined" => Ok(LobbyEventKind::LobbyJoined),
+            "state_changed" => Ok(LobbyEventKind::StateChanged),
+            "game_finished" => Ok(LobbyEventKind::GameFinished),
+            _ => anyhow::bail!("unknown lobby event kind {}", s),
+        }
+    }
+}
+
+impl LobbyEvent {
+    /// Builds the event from the stored columns, which keep the option encoding
+    /// of the second player.
+    pub fn from_columns(
+        id: i64,
+        kind: LobbyEventKind,
+        contract_address: &str,
+        block_height: u64,
+        [game_state, p1_public_key, p2_public_key]: [String; 3],
+    ) -> Self {
+        Self {
+            id,
+            kind,
+            address: contract_address.to_string(),
+            block_height,
+            game_state,
+            p1_public_key,
+            p2_public_key: parse_p2_public_key(&p2_public_key),
+        }
+    }
+
+    /// Whether the event is of a lobby of `player`, and of the contract at
+    /// `address` in its stored form.
+    pub fn matches(&self, player: Option<&str>, address: Option<&str>) -> bool {
+        let player_matches = match player {
+            Some(player) => {
+                self.p1_public_key == player || self.p2_public_key.as_deref() == Some(player)
+            }
+            None => true,
+        };
+
+        let address_matches = match address {
+            Some(address) => self.address == address,
+            None => true,
+        };
+
+        player_matches && address_matches
+    }
+}
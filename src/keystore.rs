@@ -0,0 +1,383 @@
+use anyhow::Context as _;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead as _, KeyInit as _},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use clap::{arg, ArgMatches, Command};
+use midnight_zswap::serialize::NetworkId;
+use rand::{rngs::OsRng, Rng as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write as _,
+    os::unix::fs::OpenOptionsExt as _,
+    path::{Path, PathBuf},
+};
+
+const PASSPHRASE_ENV: &str = "BATCHER_KEYSTORE_PASSPHRASE";
+const NEW_PASSPHRASE_ENV: &str = "BATCHER_KEYSTORE_NEW_PASSPHRASE";
+
+/// Wallet seed encrypted with ChaCha20-Poly1305, using a key derived from a
+/// passphrase with Argon2id.
+#[derive(Serialize, Deserialize)]
+pub struct Keystore {
+    version: u32,
+    salt: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Where to read the passphrase from, in order: the environment, a file
+/// descriptor, or an interactive prompt.
+#[derive(Clone, Copy)]
+pub struct PassphraseSource {
+    pub fd: Option<i32>,
+}
+
+impl PassphraseSource {
+    fn read(&self, env: &str, prompt: &str, confirm: bool) -> anyhow::Result<String> {
+        if let Ok(passphrase) = std::env::var(env) {
+            return Ok(passphrase);
+        }
+
+        if let Some(fd) = self.fd {
+            if fd < 0 {
+                anyhow::bail!("invalid passphrase file descriptor {}", fd);
+            }
+
+            // opening the descriptor through /dev/fd instead of taking
+            // ownership of it, so that a descriptor that isn't open, or that
+            // is used by something else in the process, is an error instead of
+            // undefined behaviour.
+            let passphrase = std::fs::read_to_string(format!("/dev/fd/{}", fd))
+                .context("Failed to read passphrase from file descriptor")?;
+
+            return Ok(passphrase.trim_end_matches(['\n', '\r']).to_string());
+        }
+
+        let passphrase = rpassword::prompt_password(prompt).context("Failed to read passphrase")?;
+
+        if confirm && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+            anyhow::bail!("passphrases don't match");
+        }
+
+        Ok(passphrase)
+    }
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+) -> anyhow::Result<[u8; 32]> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32))
+        .map_err(|e| anyhow::anyhow!("Invalid kdf parameters: {}", e))?;
+
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Failed to derive key: {}", e))?;
+
+    Ok(key)
+}
+
+impl Keystore {
+    pub fn encrypt(seed: &[u8; 32], passphrase: &str) -> anyhow::Result<Self> {
+        // 64 MiB, stronger than the argon2 crate defaults since this is only
+        // done at startup.
+        Self::encrypt_with_costs(seed, passphrase, (64 * 1024, 3, 1))
+    }
+
+    fn encrypt_with_costs(
+        seed: &[u8; 32],
+        passphrase: &str,
+        (m_cost, t_cost, p_cost): (u32, u32, u32),
+    ) -> anyhow::Result<Self> {
+        let salt: [u8; 16] = OsRng.gen();
+        let nonce: [u8; 12] = OsRng.gen();
+
+        let key = derive_key(passphrase, &salt, m_cost, t_cost, p_cost)?;
+
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(Nonce::from_slice(&nonce), seed.as_slice())
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt seed"))?;
+
+        Ok(Self {
+            version: 1,
+            salt: hex::encode(salt),
+            m_cost,
+            t_cost,
+            p_cost,
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    pub fn decrypt(&self, passphrase: &str) -> anyhow::Result<[u8; 32]> {
+        if self.version != 1 {
+            anyhow::bail!("unsupported keystore version {}", self.version);
+        }
+
+        let salt = hex::decode(&self.salt).context("Invalid keystore salt")?;
+        let nonce = hex::decode(&self.nonce).context("Invalid keystore nonce")?;
+        let ciphertext = hex::decode(&self.ciphertext).context("Invalid keystore ciphertext")?;
+
+        if nonce.len() != 12 {
+            anyhow::bail!("Invalid keystore nonce length");
+        }
+
+        let key = derive_key(passphrase, &salt, self.m_cost, self.t_cost, self.p_cost)?;
+
+        let seed = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| anyhow::anyhow!("Wrong passphrase or corrupted keystore"))?;
+
+        <[u8; 32]>::try_from(seed).map_err(|_| anyhow::anyhow!("expected seed to contain 32 bytes"))
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path).context("Failed to read keystore")?;
+
+        serde_json::from_str(&raw).context("Invalid keystore file")
+    }
+
+    fn write(&self, path: &Path) -> anyhow::Result<()> {
+        write_private(path, serde_json::to_string_pretty(self)?.as_bytes())
+            .context("Failed to write keystore")
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path`, only readable by the
+/// owner, and renames it over `path` once synced, so that the previous file is
+/// still there if writing fails halfway.
+fn write_private(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    // left over by an interrupted write, and maybe with other permissions.
+    let _ = std::fs::remove_file(&tmp_path);
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .context("Failed to create temporary file")?;
+
+    file.write_all(contents).context("Failed to write file")?;
+    file.sync_all().context("Failed to write file")?;
+
+    std::fs::rename(&tmp_path, path).context("Failed to replace file")?;
+
+    // persists the rename itself.
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .context("Failed to sync directory")?;
+
+    Ok(())
+}
+
+fn parse_plaintext_seed(raw: &str) -> anyhow::Result<[u8; 32]> {
+    <[u8; 32]>::try_from(hex::decode(raw.trim()).context("seed should be a valid hex")?)
+        .map_err(|_| anyhow::anyhow!("expected seed to contain 32 bytes"))
+}
+
+/// Reads the wallet seed from either a keystore or a plaintext hex file. The
+/// latter is only allowed for local networks.
+pub fn load_seed(
+    path: &Path,
+    network_id: NetworkId,
+    passphrase: PassphraseSource,
+) -> anyhow::Result<[u8; 32]> {
+    let raw = std::fs::read_to_string(path).context("Failed to read credentials")?;
+
+    if let Ok(keystore) = serde_json::from_str::<Keystore>(&raw) {
+        let passphrase = passphrase.read(PASSPHRASE_ENV, "Keystore passphrase: ", false)?;
+
+        return keystore.decrypt(&passphrase);
+    }
+
+    if !matches!(network_id, NetworkId::Undeployed) {
+        anyhow::bail!(
+            "plaintext seeds are only allowed on the undeployed network, use `keystore import` to encrypt it"
+        );
+    }
+
+    parse_plaintext_seed(&raw)
+}
+
+/// Generates a new seed and writes it to `path`, in plaintext for the
+/// undeployed network and as a keystore otherwise.
+pub fn create_seed_file(
+    path: &Path,
+    network_id: NetworkId,
+    passphrase: PassphraseSource,
+) -> anyhow::Result<[u8; 32]> {
+    if path.exists() {
+        anyhow::bail!("{} already exists", path.display());
+    }
+
+    let seed: [u8; 32] = OsRng.gen();
+
+    if matches!(network_id, NetworkId::Undeployed) {
+        write_private(path, hex::encode(seed).as_bytes()).context("Failed to write seed")?;
+    } else {
+        let passphrase = passphrase.read(PASSPHRASE_ENV, "New passphrase: ", true)?;
+
+        Keystore::encrypt(&seed, &passphrase)?.write(path)?;
+    }
+
+    Ok(seed)
+}
+
+pub fn command() -> Command {
+    Command::new("keystore")
+        .about("Manage the encrypted wallet seed at the --secret path")
+        .subcommand_required(true)
+        .subcommand(Command::new("create").about("Generate a new seed and encrypt it"))
+        .subcommand(
+            Command::new("import")
+                .about("Encrypt an existing plaintext hex seed")
+                .arg(arg!(<SEED_FILE>).value_parser(clap::value_parser!(std::path::PathBuf))),
+        )
+        .subcommand(Command::new("change-passphrase").about("Re-encrypt with a new passphrase"))
+}
+
+pub fn run(path: &Path, passphrase: PassphraseSource, matches: &ArgMatches) -> anyhow::Result<()> {
+    match matches.subcommand() {
+        Some(("create", _)) => {
+            if path.exists() {
+                anyhow::bail!("{} already exists", path.display());
+            }
+
+            let seed: [u8; 32] = OsRng.gen();
+            let passphrase = passphrase.read(PASSPHRASE_ENV, "New passphrase: ", true)?;
+
+            Keystore::encrypt(&seed, &passphrase)?.write(path)?;
+        }
+        Some(("import", matches)) => {
+            if path.exists() {
+                anyhow::bail!("{} already exists", path.display());
+            }
+
+            let seed_file = matches
+                .get_one::<std::path::PathBuf>("SEED_FILE")
+                .expect("required");
+            let seed = parse_plaintext_seed(
+                &std::fs::read_to_string(seed_file).context("Failed to read seed file")?,
+            )?;
+            let passphrase = passphrase.read(PASSPHRASE_ENV, "New passphrase: ", true)?;
+
+            Keystore::encrypt(&seed, &passphrase)?.write(path)?;
+        }
+        Some(("change-passphrase", _)) => {
+            let keystore = Keystore::read(path)?;
+
+            let seed = keystore.decrypt(&passphrase.read(
+                PASSPHRASE_ENV,
+                "Current passphrase: ",
+                false,
+            )?)?;
+
+            // the file descriptor can only be read once, so the new passphrase
+            // has to come from the environment or the prompt.
+            let new_passphrase =
+                PassphraseSource { fd: None }.read(NEW_PASSPHRASE_ENV, "New passphrase: ", true)?;
+
+            Keystore::encrypt(&seed, &new_passphrase)?.write(path)?;
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+
+    tracing::info!("keystore written to {}", path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt as _;
+
+    fn keystore(ciphertext: &str) -> Keystore {
+        Keystore {
+            version: 1,
+            salt: "00".repeat(16),
+            m_cost: 8,
+            t_cost: 1,
+            p_cost: 1,
+            nonce: "00".repeat(12),
+            ciphertext: ciphertext.to_string(),
+        }
+    }
+
+    #[test]
+    fn write_replaces_the_keystore() {
+        let dir = std::env::temp_dir().join(format!("batcher-keystore-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keystore.json");
+
+        keystore("aa").write(&path).unwrap();
+        keystore("bb").write(&path).unwrap();
+
+        assert_eq!(Keystore::read(&path).unwrap().ciphertext, "bb");
+        assert!(!dir.join("keystore.json.tmp").exists());
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn seed_is_decrypted_with_its_passphrase() {
+        let seed = [7; 32];
+        let keystore = Keystore::encrypt_with_costs(&seed, "correct horse", (8, 1, 1)).unwrap();
+
+        assert_eq!(keystore.decrypt("correct horse").unwrap(), seed);
+        assert!(keystore.decrypt("wrong horse").is_err());
+    }
+
+    #[test]
+    fn plaintext_seeds_are_only_readable_by_the_owner() {
+        let dir = std::env::temp_dir().join(format!("batcher-seed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seed");
+
+        let seed =
+            create_seed_file(&path, NetworkId::Undeployed, PassphraseSource { fd: None }).unwrap();
+
+        assert_eq!(
+            load_seed(&path, NetworkId::Undeployed, PassphraseSource { fd: None }).unwrap(),
+            seed
+        );
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        // an existing seed is never replaced
+        assert!(
+            create_seed_file(&path, NetworkId::Undeployed, PassphraseSource { fd: None }).is_err()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn passphrase_fd_is_checked() {
+        assert!(PassphraseSource { fd: Some(-1) }
+            .read("BATCHER_TEST_UNSET_PASSPHRASE", "", false)
+            .is_err());
+    }
+}
@@ -1,25 +1,40 @@
 #[macro_use]
 extern crate rocket;
 
+mod achievements;
+mod addresses;
+mod api_keys;
 mod balancing;
+mod coin_selection;
+mod commands;
+mod config;
+mod consolidation;
+mod contract_state;
+mod contract_tracking;
 mod db;
 mod endpoints;
+mod events;
+mod keystore;
+mod player_limits;
 mod preproofing;
 mod utils;
 mod whitelisting;
 
+use achievements::AchievementsConfig;
 use anyhow::Context as _;
 use balancing::ProvingParams;
-use clap::{arg, Command};
+use clap::{arg, ArgAction, Command};
+use config::Config;
+use consolidation::{consolidation_service, Activity};
 use db::Db;
+use events::{EventSender, LobbyEvent, LobbyEventKind};
 use futures::{SinkExt, StreamExt};
-use midnight_ledger::onchain_runtime::state::{ContractState, StateValue};
-use midnight_ledger::onchain_runtime::state_value_ext::StateValueExt;
+use midnight_ledger::onchain_runtime::state::ContractState;
 use midnight_ledger::structure::Transaction;
 use midnight_transient_crypto::proofs::Proof;
-use midnight_zswap::base_crypto::fab::{AlignmentAtom, AlignmentSegment};
 use midnight_zswap::local::State;
 use midnight_zswap::serialize::{deserialize, NetworkId, Serializable};
+use midnight_zswap::Offer;
 use preproofing::pre_proving_service;
 use rand::SeedableRng as _;
 use rand_chacha::ChaCha20Rng;
@@ -35,9 +50,6 @@ use tokio_tungstenite::{
 use url::Url;
 
 const STABLE_STATE_ID: &str = "committed";
-const WS_INDEXER_LOCALHOST: &str = "ws://127.0.0.1:8088/api/v1/graphql/ws";
-const HTTP_INDEXER_LOCALHOST: &str = "http://127.0.0.1:8088/api/v1/graphql";
-const NODE_LOCALHOST: &str = "ws://127.0.0.1:9944";
 
 #[subxt::subxt(runtime_metadata_path = "metadata.scale")]
 pub mod midnight {}
@@ -50,6 +62,10 @@ pub enum SyncStatus {
     UpToDate,
 }
 
+fn wallet_from_seed(seed: [u8; 32]) -> State {
+    State::new(&mut ChaCha20Rng::from_seed(seed))
+}
+
 fn address(zswap_state: &State) -> String {
     let pk = zswap_state.coin_public_key();
     let epk = zswap_state.enc_public_key();
@@ -64,87 +80,192 @@ fn address(zswap_state: &State) -> String {
     format!("{}|{}", pk_hex, ec_hex)
 }
 
-#[rocket::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-
-    let matches = Command::new("Midnight Batcher")
+fn cli() -> Command {
+    Command::new("Midnight Batcher")
         .version("1.0")
         .author("Enzo Cioppettini <enzo@dcspark.com>")
         .about("Midnight paymaster for Paima")
-        .arg(arg!(--"indexer-ws" <WEBSOCKET_URL>).default_value(WS_INDEXER_LOCALHOST))
-        .arg(arg!(--"indexer-http" <HTTP_URL>).default_value(HTTP_INDEXER_LOCALHOST))
-        .arg(arg!(--node <URL>).default_value(NODE_LOCALHOST))
+        .arg(
+            arg!(--config <FILEPATH> "a toml file with the batcher settings")
+                .env("BATCHER_CONFIG")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
+        )
+        .arg(arg!(--"indexer-ws" <WEBSOCKET_URL>).env("BATCHER_INDEXER_WS").global(true))
+        .arg(arg!(--"indexer-http" <HTTP_URL>).env("BATCHER_INDEXER_HTTP").global(true))
+        .arg(arg!(--node <URL>).env("BATCHER_NODE").global(true))
         .arg(
             arg!(--secret <FILEPATH>)
-                .default_value("./seed")
-                .value_parser(clap::value_parser!(PathBuf)),
+                .env("BATCHER_SECRET")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
         )
         .arg(
             arg!(--network <NETWORK>)
+                .env("BATCHER_NETWORK")
                 .value_parser(["testnet", "undeployed"])
-                .default_value("undeployed"),
+                .global(true),
         )
         .arg(
             arg!(--db <PATH>)
+                .env("BATCHER_DB")
                 .value_parser(clap::value_parser!(PathBuf))
-                .default_value("./db.sqlite"),
+                .global(true),
         )
-        .arg(arg!(--"allowed-contract" <PATH> "a path to the 'keys' directory as generated by compact").value_parser(clap::value_parser!(PathBuf)))
-        .get_matches();
-
-    let ws_indexer = matches.get_one::<String>("indexer-ws").expect("default");
-    let http_indexer = matches.get_one::<String>("indexer-http").expect("default");
-    let node = matches.get_one::<String>("node").expect("default");
-    let credentials = matches.get_one::<PathBuf>("secret").expect("default");
-    let network = matches.get_one::<String>("network").expect("default");
-    let db = matches.get_one::<PathBuf>("db").expect("default");
-    let whitelisting = matches.get_one::<PathBuf>("allowed-contract");
-
-    info!("Indexer WS: {:?}", ws_indexer);
-    info!("Indexer HTTP: {:?}", http_indexer);
-    info!("File path: {:?}", node);
-    info!("Wallet: {:?}", credentials);
-    info!("Network: {}", network);
-
-    let api = OnlineClient::<SubstrateConfig>::from_url(node)
-        .await
-        .context("Couldn't establish connection with the node")?;
+        .arg(arg!(--"allowed-contract" <PATH> "a path to the 'keys' directory as generated by compact").env("BATCHER_ALLOWED_CONTRACT").value_parser(clap::value_parser!(PathBuf)).global(true))
+        .arg(arg!(--"require-api-key" "reject submitTx requests without a valid api key").env("BATCHER_REQUIRE_API_KEY").action(ArgAction::SetTrue).global(true))
+        .arg(arg!(--"player-requests-per-minute" <COUNT> "max submitTx requests per player in a rolling minute").env("BATCHER_PLAYER_REQUESTS_PER_MINUTE").value_parser(clap::value_parser!(u32)).global(true))
+        .arg(arg!(--"player-daily-fee-budget" <DUST> "max fees sponsored per player in a rolling day").env("BATCHER_PLAYER_DAILY_FEE_BUDGET").value_parser(clap::value_parser!(u64)).global(true))
+        .arg(arg!(--"proving-threads" <COUNT> "size of the proving thread pool").env("BATCHER_PROVING_THREADS").value_parser(clap::value_parser!(usize)).global(true))
+        .arg(arg!(--"passphrase-fd" <FD> "read the keystore passphrase from this file descriptor").value_parser(clap::value_parser!(i32).range(0..)).global(true))
+        .arg(arg!(--"reset-state" "discard the stored wallet state and sync again from the seed").action(ArgAction::SetTrue).global(true))
+        .arg(arg!(--port <PORT> "http server port, overrides the Rocket config").env("BATCHER_PORT").value_parser(clap::value_parser!(u16)).global(true))
+        .subcommand(Command::new("serve").about("Run the batcher (the default when no subcommand is given)"))
+        .subcommand(commands::init_command())
+        .subcommand(commands::address_command())
+        .subcommand(commands::status_command())
+        .subcommand(commands::resync_command())
+        .subcommand(commands::verify_contract_command())
+        .subcommand(api_keys::command())
+        .subcommand(keystore::command())
+}
+
+/// The stored wallet state, or the one of the seed if there is none. Fails
+/// when the stored state, or the keys recorded with it, belong to another seed,
+/// and records the keys of the seed otherwise.
+async fn load_wallet_state(db: &Db, seed_state: State, config: &Config) -> anyhow::Result<State> {
+    let seed_address = address(&seed_state);
+
+    let state = match db.get_state(STABLE_STATE_ID).await? {
+        Some((_, state)) => {
+            let state_address = address(&state);
+            let recorded_address = db.get_state_public_keys(STABLE_STATE_ID).await?;
+
+            if state_address != seed_address
+                || recorded_address.is_some_and(|recorded| recorded != seed_address)
+            {
+                anyhow::bail!(
+                    "the wallet state stored in {} belongs to {}, but the seed at {} is for {}. Use the right --db and --secret, or --reset-state to rebuild the state from the seed",
+                    config.db.display(),
+                    state_address,
+                    config.secret.display(),
+                    seed_address
+                );
+            }
 
-    let network_id = match network.as_ref() {
-        "testnet" => NetworkId::TestNet,
-        "undeployed" => NetworkId::Undeployed,
-        _ => anyhow::bail!("invalid network"),
+            state
+        }
+        None => seed_state,
     };
 
-    let whitelisting = whitelisting
-        .map(|path| whitelisting::read_constraints(path, network_id))
-        .transpose()?;
+    db.set_state_public_keys(STABLE_STATE_ID, &seed_address)
+        .await?;
 
-    let indexer_ws_url = Url::parse(ws_indexer).context("Invalid indexer ws URL")?;
-    let indexer_http_url = Url::parse(http_indexer).context("Invalid indexer http URL")?;
+    Ok(state)
+}
 
-    let proving_params = Arc::new(ProvingParams::new()?);
+#[rocket::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let matches = cli().get_matches();
+
+    let config = Config::load(&matches)?;
+
+    let passphrase = keystore::PassphraseSource {
+        fd: matches.get_one::<i32>("passphrase-fd").copied(),
+    };
+
+    match matches.subcommand() {
+        Some(("serve", _)) | None => serve(config, passphrase).await,
+        Some(("init", _)) => commands::init(&config, passphrase),
+        Some(("address", matches)) => commands::address(&config, passphrase, matches),
+        Some(("status", _)) => commands::status(&config, passphrase).await,
+        Some(("resync", matches)) => commands::resync(&config, passphrase, matches).await,
+        Some(("verify-contract", matches)) => commands::verify_contract(&config, matches),
+        Some(("api-key", matches)) => {
+            let db = Db::open_db(&config.db, config.network.network_id()).await?;
+
+            api_keys::run(&db, matches).await
+        }
+        Some(("keystore", matches)) => keystore::run(&config.secret, passphrase, matches),
+        Some((subcommand, _)) => unreachable!("unknown subcommand {}", subcommand),
+    }
+}
+
+async fn serve(config: Config, passphrase: keystore::PassphraseSource) -> anyhow::Result<()> {
+    let network_id = config.network.network_id();
+
+    info!(
+        "Effective config:\n{}",
+        toml::to_string(&config.redacted()).context("Failed to serialize config")?
+    );
 
-    let db = Db::open_db(db, network_id).await?;
+    if let Some(threads) = config.proving.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .context("Failed to initialize proving thread pool")?;
+    }
 
-    let seed = std::fs::read_to_string(credentials).context("Failed to read credentials")?;
+    let api = OnlineClient::<SubstrateConfig>::from_url(&config.node)
+        .await
+        .context("Couldn't establish connection with the node")?;
 
-    let mut rng = ChaCha20Rng::from_seed(
-        <[u8; 32]>::try_from(hex::decode(seed.trim()).context("seed should be a valid hex")?)
-            .map_err(|_| anyhow::anyhow!("expected seed to contain 32 bytes"))?,
+    let whitelisting = config
+        .contract
+        .as_ref()
+        .map(|contract| whitelisting::read_constraints(&contract.keys, network_id))
+        .transpose()?;
+
+    let contract_schema = Arc::new(
+        match config
+            .contract
+            .as_ref()
+            .and_then(|contract| contract.schema.as_ref())
+        {
+            Some(path) => contract_state::Schema::load(path)?,
+            None => contract_state::Schema::default(),
+        },
     );
 
-    let maybe_latest_state = db.get_state(STABLE_STATE_ID).await?;
+    let achievements = Arc::new(match &config.achievements {
+        Some(path) => AchievementsConfig::load(path)?,
+        None => AchievementsConfig::default(),
+    });
 
-    let initial_state = maybe_latest_state
+    let verify_contract_state = config
+        .contract
         .as_ref()
-        .map(|(_, state)| state.clone())
-        .unwrap_or_else(|| State::new(&mut rng));
+        .is_some_and(|contract| contract.verify_state);
 
-    let address = address(&initial_state);
+    let indexer_ws_url = Url::parse(&config.indexer_ws).context("Invalid indexer ws URL")?;
+    let indexer_http_url = Url::parse(&config.indexer_http).context("Invalid indexer http URL")?;
 
-    info!("Batcher address {}", address);
+    let proving_params = Arc::new(ProvingParams::new()?);
+
+    let seed = keystore::load_seed(&config.secret, network_id, passphrase)?;
+
+    let db = Db::open_db(&config.db, network_id)
+        .await?
+        .with_state_encryption(&seed);
+
+    let seed_state = wallet_from_seed(seed);
+    let address = address(&seed_state);
+    let shielded_address = addresses::shielded_address(&seed_state, network_id)?;
+
+    if config.reset_state {
+        tracing::warn!("--reset-state given, rebuilding the wallet state from the seed");
+        db.delete_state(STABLE_STATE_ID).await?;
+    }
+
+    let initial_state = load_wallet_state(&db, seed_state, &config).await?;
+
+    // only after checking that the state belongs to the seed, otherwise it
+    // would be encrypted with the wrong key.
+    db.encrypt_plaintext_states().await?;
+
+    info!(legacy = %address, "Batcher address {}", shielded_address);
 
     let sync_status = Arc::new(RwLock::new(SyncStatus::Syncing {
         progress: 0.0,
@@ -155,12 +276,17 @@ async fn main() -> anyhow::Result<()> {
 
     let notify_tx = Arc::new(tokio::sync::Notify::new());
 
+    let (events, _) = tokio::sync::broadcast::channel(1000);
+
     let indexer_task_handle = {
         let initial_state = Arc::clone(&initial_state);
         let sync_status = Arc::clone(&sync_status);
         let notify_tx = Arc::clone(&notify_tx);
         let whitelisting = whitelisting.clone();
         let db = db.clone();
+        let events = events.clone();
+        let achievements = Arc::clone(&achievements);
+        let contract_schema = Arc::clone(&contract_schema);
 
         tokio::task::spawn(async move {
             let sleep_time = std::time::Duration::from_secs(60);
@@ -176,6 +302,10 @@ async fn main() -> anyhow::Result<()> {
                     Arc::clone(&notify_tx),
                     // TODO: maybe this is too big? but shouldn't be
                     whitelisting.clone(),
+                    Arc::clone(&contract_schema),
+                    verify_contract_state,
+                    events.clone(),
+                    Arc::clone(&achievements),
                 )
                 .await;
 
@@ -220,8 +350,34 @@ async fn main() -> anyhow::Result<()> {
         notify_tx,
         pre_proving_comm_rx,
         Arc::clone(&sync_status),
+        config.coin_selection.clone(),
     ));
 
+    let activity = Arc::new(Activity::default());
+
+    if config.consolidation.enabled {
+        tokio::task::spawn(consolidation_service(
+            Arc::clone(&initial_state),
+            Arc::clone(&proving_params),
+            api.clone(),
+            network_id,
+            pre_proving_comm_tx.clone(),
+            Arc::clone(&sync_status),
+            Arc::clone(&activity),
+            config.fees.clone(),
+            config.coin_selection.dust_threshold,
+            config.consolidation.clone(),
+        ));
+    }
+
+    if config.fees.player_limits().is_enabled() {
+        tokio::task::spawn(player_limits::pruning_service(db.clone()));
+    }
+
+    let fee_policy = config.fees.clone();
+    let coin_selection = config.coin_selection.clone();
+    let server_config = config.server.clone();
+
     let rocket_task_handle = tokio::task::spawn(async move {
         endpoints::rocket(
             proving_params,
@@ -233,6 +389,14 @@ async fn main() -> anyhow::Result<()> {
             whitelisting,
             db,
             address,
+            shielded_address,
+            fee_policy,
+            coin_selection,
+            activity,
+            server_config,
+            events,
+            achievements,
+            contract_schema,
         )
         .launch()
         .await
@@ -266,6 +430,10 @@ async fn wallet_indexer(
     sync_status: Arc<RwLock<SyncStatus>>,
     signal: Arc<tokio::sync::Notify>,
     constraints: Option<whitelisting::Constraints>,
+    contract_schema: Arc<contract_state::Schema>,
+    verify_contract_state: bool,
+    events: EventSender,
+    achievements: Arc<AchievementsConfig>,
 ) -> anyhow::Result<()> {
     let maybe_latest_state = db.get_state(STABLE_STATE_ID).await?;
 
@@ -481,138 +649,200 @@ async fn wallet_indexer(
                     let deploy_address = whitelisting::check_deploy(constraints, &tx, network_id)?;
 
                     if let Some(deploy_address) = &deploy_address {
-                        db.insert_contract_address(deploy_address).await?;
+                        db.insert_contract_address(deploy_address, block_number)
+                            .await?;
 
                         tracing::info!("detected new contract address: {}", deploy_address);
                     }
 
-                    let contract_call_address =
-                        whitelisting::check_call(&db, &tx, network_id).await?;
-
-                    if let Some(contract_address) = deploy_address.or(contract_call_address) {
-                        let tx_hash = tx.transaction_hash();
-
-                        let res: serde_json::Value = reqwest::Client::new()
-                            .post(indexer_http_url.to_string())
-                            .json(&json!({
-                                "query": format!(r#"{{
-                            contract(address: "{}", transactionOffset: {{ hash: "{}" }} ) {{
-                                state
-                            }}
-                        }}"#, contract_address, hex::encode(tx_hash.0.0)),
-                            }))
-                            .send()
+                    let contract_call_addresses =
+                        whitelisting::called_contracts(&db, &tx, network_id).await?;
+
+                    let is_deploy = deploy_address.is_some();
+
+                    let tx_hash_hex = hex::encode(tx.transaction_hash().0 .0);
+
+                    for contract_address in
+                        deploy_address.into_iter().chain(contract_call_addresses)
+                    {
+                        // the wallet state is only saved when its coins change,
+                        // so the transactions after it are replayed on restart.
+                        if db
+                            .is_contract_transaction_applied(&contract_address, &tx_hash_hex)
                             .await?
-                            .json()
+                        {
+                            tracing::debug!(
+                                %contract_address,
+                                tx_hash = %tx_hash_hex,
+                                "skipping contract transaction already applied"
+                            );
+
+                            continue;
+                        }
+
+                        let previous = db
+                            .get_contract_ledger_state(&contract_address)
+                            .await?
+                            .map(|raw| contract_tracking::decode(raw, network_id))
+                            .transpose()?;
+
+                        let local_state = contract_tracking::apply_transaction(
+                            previous.as_ref(),
+                            &tx,
+                            &contract_address,
+                            network_id,
+                            apply_stage == "SucceedEntirely",
+                        )
+                        .inspect_err(|error| {
+                            tracing::warn!(
+                                ?error,
+                                %contract_address,
+                                "failed to compute the contract state locally, querying the indexer"
+                            )
+                        })
+                        .ok();
+
+                        let state: ContractState = match local_state {
+                            Some(local_state) if !verify_contract_state => local_state,
+                            local_state => {
+                                let remote_state = contract_tracking::fetch_from_indexer(
+                                    &indexer_http_url,
+                                    &contract_address,
+                                    &tx_hash_hex,
+                                    network_id,
+                                )
+                                .await?;
+
+                                if let Some(local_state) = local_state {
+                                    if contract_tracking::encode(&local_state, network_id)?
+                                        != contract_tracking::encode(&remote_state, network_id)?
+                                    {
+                                        tracing::warn!(
+                                            %contract_address,
+                                            tx_hash = %tx_hash_hex,
+                                            "local contract state doesn't match the indexer, using the indexer's"
+                                        );
+                                    }
+                                }
+
+                                remote_state
+                            }
+                        };
+
+                        let values = contract_schema.decode(&state.data);
+
+                        if let Some(columns) = contract_schema.lobby_columns(&values) {
+                            db.update_contract_state(
+                                &contract_address,
+                                &columns[0],
+                                &columns[1],
+                                &columns[2],
+                                block_number,
+                            )
                             .await?;
 
-                        let state_raw = res
-                            .get("data")
-                            .and_then(|data| data.get("contract"))
-                            .and_then(|contract| contract.get("state"))
-                            .and_then(|state| state.as_str())
-                            .ok_or(anyhow::anyhow!(
-                                "Unexpected format for contract state query {}",
-                                res.to_string()
-                            ))?;
-
-                        let state_raw = hex::decode(state_raw).context(anyhow::anyhow!(
-                            "Expected hex string for the contract statestate"
-                        ))?;
-
-                        let state: ContractState =
-                            deserialize(std::io::Cursor::new(state_raw), network_id)?;
-
-                        let mut flattened_entries = vec![];
-                        match &state.data {
-                            StateValue::Array(arr) => {
-                                for entry in arr.iter() {
-                                    match &*entry {
-                                        StateValue::Array(arr) => {
-                                            for entry in arr.iter() {
-                                                flattened_entries.push(entry.as_cell().unwrap())
-                                            }
-                                        }
-                                        StateValue::Cell(cell) => {
-                                            flattened_entries.push(Arc::clone(cell));
-                                        }
-                                        _ => todo!(),
+                            let previous_columns = previous.as_ref().and_then(|previous| {
+                                contract_schema
+                                    .lobby_columns(&contract_schema.decode(&previous.data))
+                            });
+
+                            let kind = match (is_deploy, &previous_columns) {
+                                (true, _) => Some(LobbyEventKind::NewLobby),
+                                (false, Some(previous_columns)) => LobbyEventKind::classify(
+                                    previous_columns,
+                                    &columns,
+                                    &contract_schema.outcomes,
+                                ),
+                                // contracts indexed before the local state was kept
+                                (false, None) => Some(LobbyEventKind::StateChanged),
+                            };
+
+                            if let (Some(result), Some(p2_public_key)) = (
+                                contract_schema.outcomes.p1_result(&columns[0]),
+                                contract_state::parse_p2_public_key(&columns[2]),
+                            ) {
+                                let newly_finished = db
+                                    .record_match(
+                                        &contract_address,
+                                        &columns[1],
+                                        &p2_public_key,
+                                        result.as_str(),
+                                        block_number,
+                                    )
+                                    .await?;
+
+                                if newly_finished {
+                                    for player in [&columns[1], &p2_public_key] {
+                                        achievements
+                                            .update_player(&db, player, block_number)
+                                            .await?;
                                     }
                                 }
                             }
-                            _ => todo!(),
+
+                            if let Some(kind) = kind {
+                                let id = db
+                                    .insert_lobby_event(
+                                        &contract_address,
+                                        &tx_hash_hex,
+                                        kind.as_str(),
+                                        block_number,
+                                        columns.clone(),
+                                    )
+                                    .await?;
+
+                                // already sent if the transaction is replayed,
+                                // and there may be no clients listening.
+                                if let Some(id) = id {
+                                    let _ = events.send(LobbyEvent::from_columns(
+                                        id,
+                                        kind,
+                                        &contract_address,
+                                        block_number,
+                                        columns,
+                                    ));
+                                }
+                            }
                         }
 
-                        let mapped_entries = flattened_entries
-                            .clone()
-                            .into_iter()
-                            .rev()
-                            .take(3)
-                            .rev()
-                            .map(|state_var| {
-                                let mut joined = state_var
-                                    .value
-                                    .0
-                                    .iter()
-                                    .zip(state_var.alignment.0.iter())
-                                    .map(|(value, alignment)| match &alignment {
-                                        AlignmentSegment::Atom(atom) => match &atom {
-                                            AlignmentAtom::Compress => "".to_string(),
-                                            AlignmentAtom::Bytes { length } => {
-                                                let mut s = hex::encode(&value.0);
-
-                                                if let Some(missing_zeroes) =
-                                                    (*length as usize).checked_sub(value.0.len())
-                                                {
-                                                    s.extend(
-                                                        std::iter::repeat('0')
-                                                            .take(missing_zeroes * 2),
-                                                    );
-                                                }
-
-                                                s
-                                            }
-                                            AlignmentAtom::Field => hex::encode(&value.0),
-                                        },
-                                        _ => todo!(),
-                                    })
-                                    .fold(String::new(), |mut s, v| {
-                                        s.push_str(&v);
-                                        s.push(';');
-                                        s
-                                    });
-
-                                joined.pop();
-
-                                joined
-                            })
-                            .collect::<Vec<_>>();
-
-                        db.update_contract_state(
+                        db.insert_contract_state_change(
+                            &contract_address,
+                            &tx_hash_hex,
+                            block_number,
+                            serde_json::Value::Object(values.iter().cloned().collect()),
+                        )
+                        .await?;
+
+                        db.put_contract_state_values(&contract_address, values, block_number)
+                            .await?;
+
+                        db.set_contract_state_json(
                             &contract_address,
-                            &mapped_entries[0],
-                            &mapped_entries[1],
-                            &mapped_entries[2],
+                            contract_state::to_json(&state.data),
                             block_number,
                         )
                         .await?;
+
+                        // last, so that a replay after a failure in between
+                        // runs the transaction again from the previous state.
+                        // The writes above are idempotent.
+                        db.set_contract_ledger_state(
+                            &contract_address,
+                            &tx_hash_hex,
+                            contract_tracking::encode(&state, network_id)?,
+                        )
+                        .await?;
                     }
                 }
 
-                match tx {
-                    Transaction::Standard(stx) => {
-                        confirmed_state = confirmed_state.apply(&stx.guaranteed_coins);
-                        unconfirmed_state = unconfirmed_state.apply(&stx.guaranteed_coins);
+                for offer in applied_offers(&tx, &apply_stage) {
+                    confirmed_state = confirmed_state.apply(offer);
+                    unconfirmed_state = unconfirmed_state.apply(offer);
+                }
 
-                        if let Some(fallible_coins) = &stx.fallible_coins {
-                            confirmed_state = confirmed_state.apply(fallible_coins);
-                            unconfirmed_state = unconfirmed_state.apply(fallible_coins);
-                        }
-                    }
-                    Transaction::ClaimMint(cmtx) => {
-                        confirmed_state = confirmed_state.apply_mint(&cmtx.mint);
-                        unconfirmed_state = unconfirmed_state.apply_mint(&cmtx.mint);
-                    }
+                if let Transaction::ClaimMint(cmtx) = &tx {
+                    confirmed_state = confirmed_state.apply_mint(&cmtx.mint);
+                    unconfirmed_state = unconfirmed_state.apply_mint(&cmtx.mint);
                 }
 
                 *unconfirmed_state_guard = unconfirmed_state;
@@ -639,3 +869,115 @@ async fn wallet_indexer(
 
     Ok(())
 }
+
+/// The coin offers of `tx` that changed the ledger, given the stage it was
+/// applied at. The fallible coins only count when the whole transaction
+/// succeeded.
+fn applied_offers<'a>(tx: &'a Transaction<Proof>, apply_stage: &str) -> Vec<&'a Offer<Proof>> {
+    match tx {
+        Transaction::Standard(stx) => std::iter::once(&stx.guaranteed_coins)
+            .chain(
+                stx.fallible_coins
+                    .as_ref()
+                    .filter(|_| apply_stage == "SucceedEntirely"),
+            )
+            .collect(),
+        Transaction::ClaimMint(_) => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_offer() -> Offer<Proof> {
+        Offer {
+            inputs: vec![],
+            outputs: vec![],
+            transient: vec![],
+            deltas: vec![],
+        }
+    }
+
+    async fn db(name: &str) -> Db {
+        Db::open_db(
+            format!("file:batcher-main-{}?mode=memory&cache=shared", name),
+            NetworkId::Undeployed,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn seed_state_is_used_without_a_stored_one() {
+        let db = db("empty").await;
+        let seed_address = address(&wallet_from_seed([1; 32]));
+
+        let state = load_wallet_state(&db, wallet_from_seed([1; 32]), &Config::default())
+            .await
+            .unwrap();
+
+        assert_eq!(address(&state), seed_address);
+        assert_eq!(
+            db.get_state_public_keys(STABLE_STATE_ID).await.unwrap(),
+            Some(seed_address)
+        );
+    }
+
+    #[tokio::test]
+    async fn stored_state_of_another_seed_is_rejected() {
+        let db = db("other-seed").await;
+
+        db.persist_state(STABLE_STATE_ID, "hash", &wallet_from_seed([1; 32]))
+            .await
+            .unwrap();
+
+        assert!(
+            load_wallet_state(&db, wallet_from_seed([1; 32]), &Config::default())
+                .await
+                .is_ok()
+        );
+        assert!(
+            load_wallet_state(&db, wallet_from_seed([2; 32]), &Config::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn recorded_keys_of_another_seed_are_rejected() {
+        let db = db("other-keys").await;
+
+        db.persist_state(STABLE_STATE_ID, "hash", &wallet_from_seed([2; 32]))
+            .await
+            .unwrap();
+        db.set_state_public_keys(STABLE_STATE_ID, &address(&wallet_from_seed([1; 32])))
+            .await
+            .unwrap();
+
+        assert!(
+            load_wallet_state(&db, wallet_from_seed([2; 32]), &Config::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn fallible_coins_are_only_applied_on_full_success() {
+        let tx = Transaction::new(empty_offer(), Some(empty_offer()), None);
+        let Transaction::Standard(stx) = &tx else {
+            unreachable!()
+        };
+
+        let applied = applied_offers(&tx, "SucceedEntirely");
+        assert_eq!(applied.len(), 2);
+        assert!(std::ptr::eq(
+            applied[1],
+            stx.fallible_coins.as_ref().unwrap()
+        ));
+
+        let applied = applied_offers(&tx, "SucceedPartially");
+        assert_eq!(applied.len(), 1);
+        assert!(std::ptr::eq(applied[0], &stx.guaranteed_coins));
+    }
+}
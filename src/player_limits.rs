@@ -0,0 +1,115 @@
+use crate::{
+    addresses::ContractAddress, contract_state::Schema, contract_tracking, db::Db, utils::unix_now,
+};
+use midnight_ledger::structure::{ContractAction, Transaction};
+use midnight_transient_crypto::proofs::Proof;
+use midnight_zswap::serialize::{serialize, NetworkId};
+use std::time::Duration;
+
+/// How often the requests and fees out of the limit windows are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Limits applied to each player identity. A player is identified by the
+/// public key that the contract checks the caller against, as mapped by the
+/// `[players]` section of the contract schema, so that a client can't pick an
+/// identity other than the one its transaction proves.
+#[derive(Clone, Copy, Default)]
+pub struct PlayerLimits {
+    pub requests_per_minute: Option<u32>,
+    pub daily_fee_budget: Option<u64>,
+}
+
+impl PlayerLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.requests_per_minute.is_some() || self.daily_fee_budget.is_some()
+    }
+}
+
+pub struct PlayerBudget {
+    pub player: String,
+    pub daily_fee_budget: u64,
+}
+
+/// The public key of the player sending `tx`, read from the field of the
+/// contract state that the deploy or the called circuit binds to the caller.
+/// Returns `None` if the schema doesn't map the transaction to a player.
+pub async fn transaction_player(
+    db: &Db,
+    schema: &Schema,
+    tx: &Transaction<Proof>,
+    network_id: NetworkId,
+) -> anyhow::Result<Option<String>> {
+    let Some(players) = &schema.players else {
+        return Ok(None);
+    };
+
+    let Transaction::Standard(stx) = tx else {
+        return Ok(None);
+    };
+
+    let Some(contract_calls) = &stx.contract_calls else {
+        return Ok(None);
+    };
+
+    let [action] = &contract_calls.calls[..] else {
+        return Ok(None);
+    };
+
+    let (field, state) = match action {
+        ContractAction::Deploy(deploy) => {
+            let Some(field) = &players.deploy else {
+                return Ok(None);
+            };
+
+            (field, deploy.initial_state.clone())
+        }
+        ContractAction::Call(call) => {
+            let entry_point = String::from_utf8_lossy(&call.entry_point.0);
+
+            let Some(field) = players.calls.get(entry_point.as_ref()) else {
+                return Ok(None);
+            };
+
+            let mut buf = vec![];
+            serialize(&call.address, &mut buf, network_id)?;
+            let address = ContractAddress::from_serialized(&buf, network_id)?.to_stored();
+
+            let Some(raw) = db.get_contract_ledger_state(&address).await? else {
+                return Ok(None);
+            };
+
+            let previous = contract_tracking::decode(raw, network_id)?;
+
+            // fields written by the call, like the key of a player joining a
+            // lobby, are only there after running it. The call may also run
+            // against a newer state than the indexed one, in which case the
+            // fields it only reads are still the same.
+            let state = contract_tracking::apply_transaction(
+                Some(&previous),
+                tx,
+                &address,
+                network_id,
+                true,
+            )
+            .unwrap_or(previous);
+
+            (field, state)
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(schema.player_key(field, &state.data))
+}
+
+/// Deletes the usage records of the players that are out of the limit
+/// windows, since they are otherwise only deleted on the next request of the
+/// same player.
+pub async fn pruning_service(db: Db) {
+    loop {
+        if let Err(error) = db.prune_player_usage(unix_now()).await {
+            tracing::error!(?error, "failed to prune the player usage");
+        }
+
+        tokio::time::sleep(PRUNE_INTERVAL).await;
+    }
+}
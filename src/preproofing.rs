@@ -1,4 +1,4 @@
-use crate::{balancing::ProvingParams, SyncStatus};
+use crate::{balancing::ProvingParams, coin_selection::CoinSelectionConfig, SyncStatus};
 use futures::pin_mut;
 use midnight_ledger::structure::Transaction;
 use midnight_transient_crypto::proofs::Proof;
@@ -9,7 +9,6 @@ use midnight_zswap::{
 };
 use rand::rngs::OsRng;
 use std::{
-    cmp::Reverse,
     collections::HashMap,
     future::Future as _,
     sync::Arc,
@@ -41,6 +40,7 @@ pub async fn pre_proving_service(
     signal: Arc<tokio::sync::Notify>,
     mut comm: PreProvingServiceChannelRx,
     sync_status: Arc<RwLock<SyncStatus>>,
+    coin_selection: CoinSelectionConfig,
 ) {
     let proven: Arc<Mutex<HashMap<Nullifier, ProofOrNotifier>>> =
         Arc::new(Mutex::new(HashMap::new()));
@@ -115,13 +115,14 @@ pub async fn pre_proving_service(
             .filter(|coin| {
                 !state.pending_spends.contains_key(&coin.0) && !proven_guard.contains_key(&coin.0)
             })
+            .map(|coin| (coin, coin.1.value))
             .collect::<Vec<_>>();
 
         std::mem::drop(proven_guard);
 
-        unspent_coins.sort_by_key(|(_, coin)| Reverse(coin.value));
+        coin_selection.proving_order(&mut unspent_coins, &mut OsRng);
 
-        for coin in unspent_coins {
+        for (coin, _) in unspent_coins {
             let (new_state, input) = state.spend(&mut OsRng, &coin.1).unwrap();
 
             state = new_state;
@@ -1,4 +1,4 @@
-use crate::db::Db;
+use crate::{addresses::ContractAddress, db::Db};
 use anyhow::Context as _;
 use midnight_ledger::{
     onchain_runtime::state::EntryPointBuf,
@@ -71,7 +71,7 @@ pub async fn check_call(
 
     serialize(&call.address, &mut buf, network_id)?;
 
-    let hex_address = hex::encode(buf);
+    let hex_address = ContractAddress::from_serialized(&buf, network_id)?.to_stored();
 
     if db.check_address(&hex_address).await? {
         Ok(Some(hex_address))
@@ -80,6 +80,43 @@ pub async fn check_call(
     }
 }
 
+/// The whitelisted contracts called by `tx`, in the order of their first
+/// call. Unlike [`check_call`], transactions with several calls are included,
+/// since they can be submitted without the batcher.
+pub async fn called_contracts(
+    db: &Db,
+    tx: &Transaction<Proof>,
+    network_id: NetworkId,
+) -> anyhow::Result<Vec<String>> {
+    let Transaction::Standard(tx) = tx else {
+        return Ok(vec![]);
+    };
+
+    let Some(contract_calls) = &tx.contract_calls else {
+        return Ok(vec![]);
+    };
+
+    let mut res: Vec<String> = vec![];
+
+    for action in contract_calls.calls.iter() {
+        let ContractAction::Call(call) = action else {
+            continue;
+        };
+
+        let mut buf = vec![];
+
+        serialize(&call.address, &mut buf, network_id)?;
+
+        let hex_address = ContractAddress::from_serialized(&buf, network_id)?.to_stored();
+
+        if !res.contains(&hex_address) && db.check_address(&hex_address).await? {
+            res.push(hex_address);
+        }
+    }
+
+    Ok(res)
+}
+
 pub fn check_deploy(
     constraints: &Constraints,
     tx: &Transaction<Proof>,
@@ -139,7 +176,7 @@ pub fn check_deploy(
 
     let mut buf = vec![];
     serialize(&deploy.address(), &mut buf, network_id)?;
-    let hex_address = hex::encode(buf);
+    let hex_address = ContractAddress::from_serialized(&buf, network_id)?.to_stored();
 
     Ok(Some(hex_address))
 }